@@ -26,7 +26,7 @@ impl DitherChatAppSettings {
 }
 
 pub enum DitherChatApp {
-	Loading(DitherChatAppSettings), // Loading screen, can't interact with anything
+	Loading(DitherChatAppSettings, Option<String>), // Loading screen, can't interact with anything (shows last error if any)
 	Loaded(State), // Loaded, (not necessarily connected to the network)
 }
 
@@ -37,6 +37,8 @@ pub struct State {
 	chat_join: JoinHandle<()>,
 	
 	chat_channel: chat::channel::ChatChannel,
+	/// Peers the node is connected to
+	online: usize,
 }
 
 #[derive(Debug)]
@@ -55,19 +57,22 @@ impl Application for DitherChatApp {
 
 	fn new(flags: DitherChatAppSettings) -> (Self, Command<Event>) {
 		(
-			DitherChatApp::Loading(flags),
+			DitherChatApp::Loading(flags, None),
 			Command::none(),
 		)
 	}
 
 	fn title(&self) -> String {
-		String::from("Global Chat - Dither")
+		match self {
+			Self::Loaded(state) => format!("Global Chat - Dither ({} peers online)", state.online),
+			Self::Loading(..) => String::from("Global Chat - Dither"),
+		}
 	}
 
 	fn update(&mut self, app_event: Event) -> Command<Event> {
 		//let mut sender = self.ditherchat_sender.clone();
 		match self {
-			Self::Loading(settings) => {
+			Self::Loading(settings, error) => {
 				match app_event {
 					Event::DitherChatEvent(dither_event) => {
 						log::info!("Received dither_event: {:?}", dither_event);
@@ -82,9 +87,13 @@ impl Application for DitherChatApp {
 									chat_sender: sender.clone(),
 									chat_join: join,
 									chat_channel: chat::channel::ChatChannel::new(sender.clone(), channel),
+									online: 0,
 								});
 							}
-							DitherChatEvent::Error(err) => log::error!("Dither Chat Error Received: {:?}", err),
+							DitherChatEvent::Error(err) => {
+								log::error!("Dither Chat Error Received: {:?}", err);
+								*error = Some(err.to_string());
+							},
 							_ => log::error!("DitherChat Event received that shouldn't have been while in the Loading State: {:?}", dither_event),
 						}
 					}
@@ -100,6 +109,7 @@ impl Application for DitherChatApp {
 								log::info!("Received DitherChat Message: {:?}", message);
 								state.chat_channel.update(chat::channel::Event::ReceivedMessage(message));
 							},
+							DitherChatEvent::Online(count) => state.online = count,
 							_ => {}
 						}
 						//return Command::perform(self.settings.ditherchat_handle.receiver.recv(), Message::ReceivedDitherChatEvent)
@@ -119,11 +129,14 @@ impl Application for DitherChatApp {
 
 	fn view(&mut self) -> Element<Event> {
 		match self {
-			Self::Loading(_settings) => {
+			Self::Loading(_settings, error) => {
 				Row::new()
 					.align_items(Align::Center)
 					.push(
-						Text::new("Loading...")
+						Text::new(match error {
+							Some(err) => format!("Failed to connect: {}", err),
+							None => String::from("Loading..."),
+						})
 						.horizontal_alignment(HorizontalAlignment::Center)
 						.vertical_alignment(VerticalAlignment::Center)
 						.size(40)
@@ -1,7 +1,7 @@
 use iced_futures::futures;
 use tokio::sync::mpsc::Receiver;
 
-use dither_chat::{Config, Client, DitherChatAction, DitherChatEvent, DitherChatConfig};
+use dither_chat::{Dither, DitherConfig, DitherError, DitherChatAction, DitherChatEvent, DitherChatConfig};
 
 // Just a little utility function
 pub fn connect() -> iced::Subscription<DitherChatEvent> {
@@ -41,32 +41,35 @@ where
 					State::Connecting => {
 						log::info!("Connecting...");
 						// Setup
-						match Client::new(Config::development()) {
+						let config = DitherConfig::development();
+						let event_policy = config.event_policy;
+						match Dither::new(config) {
 							Ok(mut client) => {
 								// Run swarm and get join handle + thread channels
 								if let Err(err) = client.connect() {
-									return Some(( DitherChatEvent::Error(format!("Failed to connect to network: {:?}", err)), State::Connecting ))
+									log::error!("Failed to connect to network: {:?}", err);
+									return Some(( DitherChatEvent::Error(err), State::Connecting ))
 								}
 								let swarm_handle = client.start();
 
 								// Run chat middleware using swarm
-								let chat_handle = dither_chat::DitherChat::start(swarm_handle);
+								let chat_handle = dither_chat::DitherChat::start(swarm_handle, event_policy);
 								
-								let dither_chat::ThreadHandle { join, sender, receiver } = chat_handle;
+								let dither_chat::ThreadHandle { join, sender, receiver, .. } = chat_handle;
 								
 								log::info!("Connection Established");
 								Some(( DitherChatEvent::Connection(join, sender), State::Connected(receiver) ))
 							},
 							Err(err) => {
 								log::error!("Failed to connect to network: {:?}", err);
-								Some(( DitherChatEvent::Error(format!("Failed to connect to network: {:?}", err)), State::Connecting ))
+								Some(( DitherChatEvent::Error(err), State::Connecting ))
 							}
 						}
 					},
 					State::Connected(mut receiver) => {
 						match receiver.recv().await {
 							Some(event) => Some((event, State::Connected(receiver))),
-							None => Some((DitherChatEvent::Error("Connection Closed".to_owned()), State::Disconnected)),
+							None => Some((DitherChatEvent::Error(DitherError::ChannelClosed), State::Disconnected)),
 						}
 					}
 					State::Disconnected => { None },
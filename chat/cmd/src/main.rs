@@ -16,18 +16,20 @@ use cursive::{
 		TextContent,
 	},
 };
-use dither_chat::{Client, Config, DitherChatAction, DitherChatEvent};
+use dither_chat::{Dither, DitherConfig, DitherChatAction, DitherChatEvent};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
 	env_logger::Builder::new().filter_level(log::LevelFilter::Info).init(); // Init Logger
-	let client = Client::new(Config::development())?;
+	let config = DitherConfig::development();
+	let event_policy = config.event_policy;
+	let client = Dither::new(config)?;
 	//let (tx, rx) = client.connect()?;
 	// Run swarm and get join handle + thread channels
 	let swarm_handle = client.start();
 	
 	// Run chat middleware using swarm
-	let chat_handle = dither_chat::DitherChat::start(swarm_handle);
+	let mut chat_handle = dither_chat::DitherChat::start(swarm_handle, event_policy);
 	
 	/*let yaml = load_yaml!("app.yml");
 	let app = App::from_yaml(yaml);
@@ -77,6 +79,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 			tx.send(DitherAction::FloodSub("chat".to_owned(), line)).await?;
 		}
 	}*/
+	
+	// Stop the node once the UI is closed
+	chat_handle.sender.send(DitherChatAction::Shutdown).await?;
+	chat_handle.join.await?;
 	Ok(())
 }
 
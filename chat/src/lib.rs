@@ -1,27 +1,36 @@
 //use libp2p::core::PeerId;
-use std::error::Error;
+use std::collections::HashSet;
 use tokio::{
 	sync::mpsc::{self, Sender},
 	task::JoinHandle,
 };
 
-use dither::{DitherAction, DitherEvent};
+use dither::{DitherAction, DitherEvent, DitherRequest, DitherReply, Application};
 pub use dither::{
 	ThreadHandle,
 	PeerId,
-	Client,
-	Config,
+	Dither,
+	DitherConfig,
+	DitherError,
 	Multiaddr,
+	StopReason,
+	EventPolicy,
+	DroppedEvents,
 };
 
 mod types;
 pub use types::*;
 
+/// Application tag dither-chat connections are made on
+const CHAT_APPLICATION: &str = "chat";
+
 #[derive(Debug, Clone)]
 pub enum DitherChatAction {
 	SendMessage(Message, Channel),
 	Configure(DitherChatConfig),
 	Connect(PeerId),
+	/// Stop the chat and the node it runs on
+	Shutdown,
 	//SendMessage(Message, PeerId),
 	//UpdateMessage(Message),
 	//DeleteMessage(Message),
@@ -30,7 +39,9 @@ pub enum DitherChatAction {
 pub enum DitherChatEvent {
 	Connection(JoinHandle<()>, mpsc::Sender<DitherChatAction>),
 	ReceivedMessage(Message),
-	Error(String),
+	/// Number of peers the node is connected to changed
+	Online(usize),
+	Error(DitherError),
 }
 
 pub struct DitherChat {
@@ -39,16 +50,16 @@ pub struct DitherChat {
 }
 
 impl DitherChat {
-	async fn handle_chat_action(chat_action: DitherChatAction, network_sender: &mut Sender<DitherAction>, event_sender: &mut Sender<DitherChatEvent>, self_sender: &mut Sender<DitherChatAction>) -> Result<(), Box<dyn Error>> {
+	async fn handle_chat_action(chat_action: DitherChatAction, user_id: &PeerId, network_sender: &mut Sender<DitherRequest>, event_sender: &mut Sender<DitherChatEvent>, self_sender: &mut Sender<DitherChatAction>) -> Result<(), DitherError> {
 		match chat_action {
-			DitherChatAction::SendMessage(message, channel) => {
+			DitherChatAction::SendMessage(mut message, channel) => {
 				log::info!("Sending Message: {:?} on channel: {:?}", message, channel);
-				message.sender = Some()
+				message.sender = Some(user_id.to_string());
 				event_sender.send(DitherChatEvent::ReceivedMessage(message.clone())).await?;
 				match channel {
 					Channel::FloodSub(topic) => {
-						let data = serde_json::to_vec(&message)?;
-						network_sender.send(DitherAction::PubSubBroadcast(topic, data)).await?;
+						let data = serde_json::to_vec(&message).map_err(|err| DitherError::Encoding(err.to_string()))?;
+						DitherRequest::call(network_sender, DitherAction::PubSubBroadcast(topic, data)).await?;
 					}
 					Channel::Peer(_peer) => {
 						log::warn!("Unimplemented sending directly to peers");
@@ -59,59 +70,118 @@ impl DitherChat {
 			DitherChatAction::Configure(config) => {
 				log::info!("Configuring DitherChat: {:?}", config);
 				for addr in config.bootstraps {
-					network_sender.send(DitherAction::Dial(addr)).await?;
+					// A bad bootstrap should not stop the rest of the configuration
+					if let Err(err) = DitherRequest::call(network_sender, DitherAction::Bootstrap(addr)).await {
+						log::error!("Failed to bootstrap: {:?}", err);
+						event_sender.send(DitherChatEvent::Error(err)).await?;
+					}
 				}
 				if let Some(peer_str) = config.init_peer {
 					if let Ok(peer) = peer_str.parse::<PeerId>() {
 						self_sender.send(DitherChatAction::Connect(peer)).await?;
 					}
 				}
-				network_sender.send(DitherAction::PubSubSubscribe(config.pubsub_topic)).await?;
+				DitherRequest::call(network_sender, DitherAction::PubSubSubscribe(config.pubsub_topic)).await?;
 			},
 			DitherChatAction::Connect(peer) => {
-				network_sender.send(DitherAction::Connect(peer)).await?;
+				if let DitherReply::Connected(connection) = DitherRequest::call(network_sender, DitherAction::Connect(peer, Application::new(CHAT_APPLICATION))).await? {
+					log::info!("Connected to: {:?}", connection);
+				}
+			}
+			DitherChatAction::Shutdown => {
+				DitherRequest::call(network_sender, DitherAction::Shutdown).await?;
 			}
 			//_ => {},
 		}
 		Ok(())
 	}
-	async fn handle_dither_event(dither_event: DitherEvent, _network_sender: &mut Sender<DitherAction>, event_sender: &mut Sender<DitherChatEvent>, _self_sender: &mut Sender<DitherChatAction>) -> Result<(), Box<dyn Error>> {
+	async fn handle_dither_event(dither_event: DitherEvent, online: &mut HashSet<PeerId>, _network_sender: &mut Sender<DitherRequest>, event_sender: &mut Sender<DitherChatEvent>, _self_sender: &mut Sender<DitherChatAction>) -> Result<(), DitherError> {
 		match dither_event {
-			DitherEvent::ReceivedData(data) => {
+			DitherEvent::ReceivedData(_application, data) => {
 				log::info!("Recieved data from network: {:?}", data);
-				let msg = serde_json::from_slice(&data)?;
-				event_sender.send(DitherChatEvent::ReceivedMessage(msg)).await.expect("App side closed");
+				let msg = serde_json::from_slice(&data).map_err(|err| DitherError::Encoding(err.to_string()))?;
+				event_sender.send(DitherChatEvent::ReceivedMessage(msg)).await?;
+			},
+			DitherEvent::PeerConnected(peer, _) => {
+				online.insert(peer);
+				event_sender.send(DitherChatEvent::Online(online.len())).await?;
+			},
+			DitherEvent::PeerDisconnected(peer, _) => {
+				online.remove(&peer);
+				event_sender.send(DitherChatEvent::Online(online.len())).await?;
+			},
+			DitherEvent::Error(err) => {
+				event_sender.send(DitherChatEvent::Error(err)).await?;
 			},
 			_ => {},
 		}
 		Ok(())
 	}
-	pub fn start(swarm_handle: ThreadHandle<(), DitherAction, DitherEvent>) -> ThreadHandle<(), DitherChatAction, DitherChatEvent> {
+	/// Run chat on the node behind `swarm_handle`, `event_policy` applies when the UI does not keep up with chat events
+	pub fn start(swarm_handle: ThreadHandle<Result<StopReason, DitherError>, DitherRequest, DitherEvent>, event_policy: EventPolicy) -> ThreadHandle<(), DitherChatAction, DitherChatEvent> {
 		let (outer_action_sender, mut action_receiver) = mpsc::channel(64);
-		let (mut event_sender, outer_event_receiver) = mpsc::channel(64);
+		// Chat events carry no state that a newer event could replace
+		let (mut event_sender, outer_event_receiver, dropped) = dither::queue::channel(event_policy, 64, |_, _| false);
 		
 		let mut error_event_sender = event_sender.clone();
 		
 		let mut self_sender = outer_action_sender.clone();
 		let join = tokio::spawn( async move {
 			
-			let ThreadHandle { join: network_join, sender: mut network_sender, receiver: mut network_receiver } = swarm_handle;
+			let ThreadHandle { join: network_join, sender: mut node_sender, receiver: mut node_receiver, .. } = swarm_handle;
+			
+			// Register with the node so only chat traffic arrives on our channels
+			let (mut network_sender, mut network_receiver, network_dropped) = match DitherRequest::call(&mut node_sender, DitherAction::Register(Application::new(CHAT_APPLICATION))).await {
+				Ok(DitherReply::Registered(sender, receiver, dropped)) => (sender, receiver, dropped),
+				Ok(reply) => {
+					log::error!("Unexpected reply to Register: {:?}", reply);
+					return;
+				},
+				Err(err) => {
+					log::error!("Failed to register chat application: {:?}", err);
+					let _ = error_event_sender.send(DitherChatEvent::Error(err)).await;
+					return;
+				},
+			};
 			let mut n_network_sender = network_sender.clone();
 			let mut n_event_sender = event_sender.clone();
 			let mut n_self_sender = self_sender.clone();
 			
 			// Setup Network Layer
-			network_sender.send(Create)
-			
+			let user_id = match DitherRequest::call(&mut network_sender, DitherAction::CreateUser()).await {
+				Ok(DitherReply::UserCreated(user_id, _key)) => user_id,
+				Ok(reply) => {
+					log::error!("Unexpected reply to CreateUser: {:?}", reply);
+					return;
+				},
+				Err(err) => {
+					log::error!("Failed to create user: {:?}", err);
+					let _ = error_event_sender.send(DitherChatEvent::Error(err)).await;
+					return;
+				},
+			};
+			log::info!("Chatting as: {:?}", user_id);
+			if let Err(err) = DitherRequest::call(&mut network_sender, DitherAction::Accept(Application::new(CHAT_APPLICATION))).await {
+				log::error!("Failed to accept chat connections: {:?}", err);
+			}
 			
 			// App Layer -> Chat Layer -> Network Layer
 			let chat_action_join = tokio::spawn(async move {
-				network_sender.send(DitherAction::PrintListening).await.expect("Failed to print listening");
+				// The node stops with `StopReason::SendersClosed` once its own sender is dropped, keep it until chat stops
+				let _node_sender = node_sender;
+				match DitherRequest::call(&mut network_sender, DitherAction::GetListenAddresses).await {
+					Ok(DitherReply::ListenAddresses(addresses)) => log::info!("Listening on: {:?}", addresses),
+					Ok(reply) => log::error!("Unexpected reply to GetListenAddresses: {:?}", reply),
+					Err(err) => log::error!("Failed to get listen addresses: {:?}", err),
+				}
 				loop {
 					if let Some(chat_action) = action_receiver.recv().await {
-						if let Err(err) = DitherChat::handle_chat_action(chat_action, &mut network_sender, &mut event_sender, &mut self_sender).await {
+						let shutdown = matches!(chat_action, DitherChatAction::Shutdown);
+						if let Err(err) = DitherChat::handle_chat_action(chat_action, &user_id, &mut network_sender, &mut event_sender, &mut self_sender).await {
 							log::error!("Failed to handle DitherChatAction: {:?}", err);
+							let _ = event_sender.send(DitherChatEvent::Error(err)).await;
 						}
+						if shutdown { break }
 					} else {
 						log::info!("All DitherChatAction Senders Closed, Stoping...");
 						break;
@@ -121,10 +191,22 @@ impl DitherChat {
 			
 			// Network Layer -> UI Layer -> App Layer
 			let chat_event_join = tokio::spawn(async move {
+				let mut reported_dropped = 0;
+				let mut online = HashSet::new();
 				loop {
-					if let Some(dither_event) = network_receiver.recv().await {
-						if let Err(err) = DitherChat::handle_dither_event(dither_event, &mut n_network_sender, &mut n_event_sender, &mut n_self_sender).await {
+					// Node level events (e.g. errors) still arrive on the node's own receiver
+					let dither_event = tokio::select! {
+						event = network_receiver.recv() => event,
+						event = node_receiver.recv() => event,
+					};
+					if network_dropped.count() > reported_dropped {
+						log::warn!("Node dropped {} chat events, chat is not keeping up", network_dropped.count() - reported_dropped);
+						reported_dropped = network_dropped.count();
+					}
+					if let Some(dither_event) = dither_event {
+						if let Err(err) = DitherChat::handle_dither_event(dither_event, &mut online, &mut n_network_sender, &mut n_event_sender, &mut n_self_sender).await {
 							log::error!("Failed to handle DitherEvent: {:?}", err);
+							let _ = n_event_sender.send(DitherChatEvent::Error(err)).await;
 						}
 					} else {
 						log::info!("Network Layer Stopped...");
@@ -133,20 +215,27 @@ impl DitherChat {
 				}
 			});
 			
-			// Propagate Panic when network thread panics
-			if let Err(err) = network_join.await {
-				log::error!("Dither Network Panic: {:?}", err);
-				error_event_sender.send(DitherChatEvent::Error(err.to_string())).await.expect("Failed To Send Error");
+			// Propagate Panic or failed shutdown of the network thread
+			match network_join.await {
+				Ok(Ok(reason)) => log::info!("Dither Network Stopped: {:?}", reason),
+				Ok(Err(err)) => {
+					log::error!("Dither Network failed to stop cleanly: {:?}", err);
+					let _ = error_event_sender.send(DitherChatEvent::Error(err)).await;
+				},
+				Err(err) => {
+					log::error!("Dither Network Panic: {:?}", err);
+					let _ = error_event_sender.send(DitherChatEvent::Error(DitherError::TaskFailed(err.to_string()))).await;
+				},
 			}
 			
 			if let Err(err) = chat_action_join.await {
 				log::error!("Dither Chat Panic: {:?}", err);
-				error_event_sender.send(DitherChatEvent::Error(err.to_string())).await.expect("Failed To Send Error");
+				error_event_sender.send(DitherChatEvent::Error(DitherError::TaskFailed(err.to_string()))).await.expect("Failed To Send Error");
 			}
 		
 			chat_event_join.await.expect("Chat Event Channel Closed");
 		});
 		
-		ThreadHandle { join, sender: outer_action_sender, receiver: outer_event_receiver }
+		ThreadHandle { join, sender: outer_action_sender, receiver: outer_event_receiver, dropped }
 	}
 }
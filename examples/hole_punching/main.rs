@@ -0,0 +1,100 @@
+// Node for trying out hole punching between two nodes behind NAT, see `netns.sh` for a setup on one machine
+//
+// relay <port>                      Listen on <port> and give slots to nodes behind NAT
+// host <relay address>              Reserve a slot on the relay, create a user and echo frames sent to it
+// connect <relay address> <user>    Connect to <user> through the relay and report whether the connection was upgraded
+
+use std::{env, error::Error, time::Duration};
+
+use dither::{
+	Dither, DitherConfig, DitherAction, DitherEvent, DitherReply, DitherRequest,
+	Application, Multiaddr, PeerId,
+};
+
+const APPLICATION: &str = "hole-punching";
+
+fn usage() -> ! {
+	eprintln!("Usage: hole_punching relay <port> | host <relay address> | connect <relay address> <user>");
+	std::process::exit(2);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+	let args: Vec<String> = env::args().skip(1).collect();
+	let mut config = DitherConfig::development();
+	// Hole punches between two NATs need dials from the listen port, PORT_REUSE=0 shows them failing
+	config.transport.port_reuse = env::var("PORT_REUSE").map_or(true, |value| value != "0");
+	match args.first().map(String::as_str) {
+		Some("relay") => {
+			let port: u16 = args.get(1).unwrap_or_else(|| usage()).parse()?;
+			config.listen_addresses = vec![format!("/ip4/0.0.0.0/tcp/{}", port).parse()?];
+			config.nat_relay.serve = true;
+		},
+		Some("host") => config.nat_relay.reserve = true,
+		Some("connect") => {},
+		_ => usage(),
+	}
+	let mut dither = Dither::new(config)?;
+	let peer_id = dither.peer_id().clone();
+	dither.connect()?;
+	let mut handle = dither.start();
+	let application = Application::new(APPLICATION);
+
+	if let Some(relay) = args.get(1).filter(|_| args[0] != "relay") {
+		let relay: Multiaddr = relay.parse()?;
+		DitherRequest::call(&mut handle.sender, DitherAction::Bootstrap(relay)).await?;
+	}
+	match args[0].as_str() {
+		"host" => {
+			DitherRequest::call(&mut handle.sender, DitherAction::Accept(application.clone())).await?;
+			// Users created before the relay slot publish no address through it
+			while let Some(event) = handle.receiver.recv().await {
+				if let DitherEvent::RelayReserved(relay) = event {
+					println!("Reserved a slot on {}", relay);
+					break;
+				}
+			}
+			if let DitherReply::UserCreated(user, _) = DitherRequest::call(&mut handle.sender, DitherAction::CreateUser()).await? {
+				println!("User: {}", user);
+			}
+		},
+		"connect" => {
+			let user: PeerId = args.get(2).unwrap_or_else(|| usage()).parse().map_err(|err| format!("Invalid user: {:?}", err))?;
+			// Give the DHT time to learn about the relay
+			tokio::time::delay_for(Duration::from_secs(2)).await;
+			let mut connection = match DitherRequest::call(&mut handle.sender, DitherAction::Connect(user, application)).await? {
+				DitherReply::Connected(connection) => connection,
+				reply => return Err(format!("Unexpected reply: {:?}", reply).into()),
+			};
+			println!("Connected over circuit {:?}", connection.circuit());
+			tokio::spawn(async move {
+				for i in 0u32.. {
+					if connection.send(format!("ping {}", i).into_bytes()).await.is_err() { break }
+					match connection.recv().await {
+						Some(reply) => println!("Received: {}", String::from_utf8_lossy(&reply)),
+						None => break,
+					}
+					tokio::time::delay_for(Duration::from_secs(1)).await;
+				}
+			});
+		},
+		_ => {},
+	}
+	while let Some(event) = handle.receiver.recv().await {
+		match event {
+			DitherEvent::IncomingConnection(mut connection) => {
+				println!("Incoming connection {:?}", connection.id());
+				tokio::spawn(async move {
+					while let Some(data) = connection.recv().await {
+						if connection.send(data).await.is_err() { break }
+					}
+				});
+			},
+			DitherEvent::ConnectionUpgraded(id, node) => println!("Connection {:?} now goes directly to {}", id, node),
+			DitherEvent::UpgradeFailed(id, reason) => println!("Connection {:?} stays on the relay: {}", id, reason),
+			DitherEvent::NewListenAddr(addr) => println!("Listening on {}/p2p/{}", addr, peer_id),
+			_ => {},
+		}
+	}
+	Ok(())
+}
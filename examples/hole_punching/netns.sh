@@ -0,0 +1,83 @@
+#!/usr/bin/env bash
+# Runs the hole_punching example in network namespaces on one machine (needs root, iproute2 and iptables)
+#
+#   relay (10.0.0.1) ---+--- wan bridge ---+--- nat-a (10.0.0.2, MASQUERADE) --- host (192.168.1.2)
+#                       |
+#                       +--- nat-b (10.0.0.3, MASQUERADE) --- connect (192.168.2.2)     two-nat
+#                       +--- connect (10.0.0.4)                                          one-nat
+#
+# one-nat: the connecting node is reachable, the host's dial to it gets through its NAT -> ConnectionUpgraded
+# two-nat: both nodes are behind NAT. The example turns on `TransportConfig::port_reuse`, so they dial from their
+#          listen port and MASQUERADE keeps source ports that are free, so each dial leaves from the address
+#          identify observed on the relayed connection and the dials meet -> ConnectionUpgraded
+#          With PORT_REUSE=0 every dial gets another port -> UpgradeFailed, frames keep going over the relay
+#
+# Usage: sudo [PORT_REUSE=0] ./netns.sh [one-nat|two-nat]
+set -euo pipefail
+
+SCENARIO=${1:-two-nat}
+NODE=${NODE:-target/debug/examples/hole_punching}
+LOGS=$(mktemp -d)
+NAMESPACES="wan relay nat-a host nat-b connect"
+
+cleanup() {
+	kill $(jobs -p) 2>/dev/null || true
+	for ns in $NAMESPACES; do ip netns del "$ns" 2>/dev/null || true; done
+	echo "Logs are in $LOGS"
+}
+trap cleanup EXIT
+
+# Connect interface $2 in namespace $1 to interface $4 in namespace $3
+link() {
+	ip link add "$2" netns "$1" type veth peer name "$4" netns "$3"
+	ip -n "$1" link set "$2" up
+	ip -n "$3" link set "$4" up
+	if [ "$3" = wan ]; then ip -n wan link set "$4" master br0; fi
+}
+# Router $1 with wan address $2 for the private network $3.0/24 behind it, with $4 at $3.2
+nat() {
+	link "$1" wan0 wan "$1"
+	link "$1" lan0 "$4" eth0
+	ip -n "$1" addr add "$2/24" dev wan0
+	ip -n "$1" addr add "$3.1/24" dev lan0
+	ip netns exec "$1" sysctl -qw net.ipv4.ip_forward=1
+	ip netns exec "$1" iptables -t nat -A POSTROUTING -o wan0 -j MASQUERADE
+	ip -n "$4" addr add "$3.2/24" dev eth0
+	ip -n "$4" route add default via "$3.1"
+}
+
+for ns in $NAMESPACES; do
+	ip netns add "$ns"
+	ip -n "$ns" link set lo up
+done
+ip -n wan link add br0 type bridge
+ip -n wan link set br0 up
+
+link relay eth0 wan relay
+ip -n relay addr add 10.0.0.1/24 dev eth0
+nat nat-a 10.0.0.2 192.168.1 host
+if [ "$SCENARIO" = two-nat ]; then
+	nat nat-b 10.0.0.3 192.168.2 connect
+else
+	link connect eth0 wan connect
+	ip -n connect addr add 10.0.0.4/24 dev eth0
+fi
+
+# Prints the first line of log $1 matching $2 once it shows up
+wait_for() {
+	until grep -m1 "$2" "$LOGS/$1.log"; do sleep 0.5; done
+}
+
+ip netns exec relay "$NODE" relay 4001 > "$LOGS/relay.log" 2>&1 &
+RELAY=$(wait_for relay "Listening on /ip4/10.0.0.1/" | sed 's/Listening on //')
+echo "Relay: $RELAY"
+
+ip netns exec host "$NODE" host "$RELAY" > "$LOGS/host.log" 2>&1 &
+USER_ID=$(wait_for host "^User: " | sed 's/User: //')
+echo "Host user: $USER_ID"
+
+ip netns exec connect "$NODE" connect "$RELAY" "$USER_ID" > "$LOGS/connect.log" 2>&1 &
+wait_for connect "now goes directly\|stays on the relay"
+sleep 3
+echo "Frames received by the connecting node:"
+grep "^Received" "$LOGS/connect.log" | tail -n 3
@@ -1,8 +1,36 @@
+// Pick relays for onion circuits from a table of measured links between peers
 
-use dither::routing::Router;
-fn main () {
-    let routers = Vec::new();
-    routers.push(Router::new())
+use dither::{
+	PeerId,
+	routing::{Router, RoutingTable, RouteMeasurement},
+};
 
-
-}
\ No newline at end of file
+fn main() {
+	let peers: Vec<PeerId> = (0..6).map(|_| PeerId::random()).collect();
+	let (me, destination) = (peers[0].clone(), peers[5].clone());
+	
+	// (from, to, latency ms, bandwidth kb/s)
+	let links = [
+		(0, 1, 20.0, 8000), (1, 5, 25.0, 8000),
+		(0, 2, 10.0, 500), (2, 5, 10.0, 500),
+		(0, 3, 60.0, 20000), (3, 4, 15.0, 20000), (4, 5, 30.0, 20000),
+		(1, 3, 5.0, 10000),
+	];
+	let mut table = RoutingTable::new(me.clone());
+	for (from, to, latency, bandwidth) in links.iter() {
+		table.add_route(peers[*from].clone(), peers[*to].clone(), RouteMeasurement::new(*latency, *bandwidth, None));
+	}
+	let router = Router::new(table);
+	
+	let name = |peer: &PeerId| peers.iter().position(|p| p == peer).expect("Known peer");
+	match router.best_path(&me, &destination) {
+		Some(path) => println!("Best path: {:?} (cost {:.1})", path.hops.iter().map(name).collect::<Vec<_>>(), path.cost),
+		None => println!("No path to destination"),
+	}
+	for (i, path) in router.disjoint_paths(&me, &destination, 3).iter().enumerate() {
+		println!("Circuit {}: {:?} (cost {:.1})", i, path.hops.iter().map(name).collect::<Vec<_>>(), path.cost);
+	}
+	// Relays `DitherAction::ConnectAnonymously` would build its circuit over, with every peer connected
+	let relays = router.circuit_relays(&destination, &peers, 2);
+	println!("Anonymous circuit relays: {:?}", relays.iter().map(name).collect::<Vec<_>>());
+}
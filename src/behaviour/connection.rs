@@ -0,0 +1,623 @@
+// Direct connections between an application and a user hosted on another node
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet, VecDeque},
+	fmt,
+	io,
+	iter,
+	pin::Pin,
+	sync::{Arc, RwLock},
+	task::{Context, Poll},
+};
+use futures::{Future, io::{AsyncRead, AsyncWrite}};
+use serde_derive::{Serialize, Deserialize};
+use tokio::sync::{mpsc, Mutex};
+use libp2p::{
+	PeerId,
+	Multiaddr,
+	core::{
+		connection::ConnectionId,
+		upgrade::{self, InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+	},
+	swarm::{
+		NetworkBehaviour,
+		NetworkBehaviourAction,
+		NotifyHandler,
+		OneShotHandler,
+		OneShotHandlerConfig,
+		PollParameters,
+		SubstreamProtocol,
+		DialPeerCondition,
+	},
+};
+
+use crate::{Application, DitherError, UserId};
+use super::{onion::CircuitId, SendFailure};
+
+/// Largest frame accepted on a `UserConnection`
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Largest encoded frame read from a substream, frame data is sent as base64
+const MAX_ENCODED_FRAME: usize = MAX_FRAME_SIZE / 3 * 4 + 1024;
+/// Frames that can wait in a `UserConnection` before the application reads them, or wait for frames sent before them
+const CONNECTION_BUFFER: usize = 64;
+/// Connections from other nodes whose frames arrived before their `Open`
+const MAX_UNOPENED: usize = 256;
+
+/// Protocol id frames of `application` are sent on
+fn protocol_name(application: &Application) -> Vec<u8> {
+	format!("/dither/app/{}/1.0.0", application.tag()).into_bytes()
+}
+/// Inverse of `protocol_name`
+fn application_of(protocol: &[u8]) -> Option<Application> {
+	let protocol = std::str::from_utf8(protocol).ok()?;
+	let tag = protocol.strip_prefix("/dither/app/")?.strip_suffix("/1.0.0")?;
+	Some(Application::new(tag))
+}
+
+/// Applications accepted by the node, shared with the handlers of every connection
+type Accepted = Arc<RwLock<HashSet<Application>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UserConnectionId(u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FrameKind {
+	/// First frame of a connection, contains the user being connected to
+	Open(Vec<u8>),
+	Data(#[serde(with = "super::encoding")] Vec<u8>),
+	Close,
+}
+/// Single message on a `UserConnection`, `connection` is the id chosen by the side that opened it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame {
+	connection: u64,
+	/// Set if the sender of the frame opened the connection
+	initiator: bool,
+	/// Position among the frames its sender sent on the connection, starting at 0 with `Open`
+	/// Every frame takes its own substream (or a different route once upgraded), so they are put back in order on arrival
+	seq: u64,
+	kind: FrameKind,
+}
+
+/// How frames of a connection reach the other side
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Route {
+	Direct(PeerId),
+	/// Over an onion circuit, the node on the other side only knows the circuit
+	Circuit(CircuitId),
+}
+
+/// Sent from `UserConnection` handles to the behaviour
+#[derive(Debug)]
+enum ConnectionCommand {
+	Send(UserConnectionId, Vec<u8>),
+	Close(UserConnectionId),
+}
+
+/// Handle to a connection between an application and a user on another node
+/// Each frame sent is delivered to the other side as one message
+#[derive(Clone)]
+pub struct UserConnection {
+	id: UserConnectionId,
+	user: UserId,
+	node: PeerId,
+	application: Application,
+	/// Set if this side opened the connection
+	initiator: bool,
+	circuit: Option<CircuitId>,
+	commands: mpsc::Sender<ConnectionCommand>,
+	incoming: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+}
+impl fmt::Debug for UserConnection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("UserConnection")
+		.field("id", &self.id)
+		.field("user", &self.user)
+		.field("node", &self.node)
+		.field("application", &self.application)
+		.finish()
+	}
+}
+impl UserConnection {
+	pub fn id(&self) -> UserConnectionId { self.id }
+	/// User this connection was made to, (the local user for incoming connections)
+	pub fn user(&self) -> &UserId { &self.user }
+	/// Node on the other side of the connection, the local node for connections received over a circuit
+	pub fn node(&self) -> &PeerId { &self.node }
+	pub fn application(&self) -> &Application { &self.application }
+	/// True if this connection was opened by `DitherAction::Connect` on this node
+	pub fn is_initiator(&self) -> bool { self.initiator }
+	/// Onion circuit this connection was opened on, see `DitherAction::ConnectAnonymously`
+	/// Relayed connections keep reporting it after `DitherEvent::ConnectionUpgraded` moved them to a direct connection
+	pub fn circuit(&self) -> Option<CircuitId> { self.circuit }
+	/// Send a frame, fails if it is larger than `MAX_FRAME_SIZE` or the node has stopped
+	pub async fn send(&mut self, data: Vec<u8>) -> Result<(), DitherError> {
+		if data.len() > MAX_FRAME_SIZE {
+			return Err(DitherError::SendFailed(SendFailure::TooLarge));
+		}
+		self.commands.send(ConnectionCommand::Send(self.id, data)).await.map_err(|_| DitherError::ChannelClosed)
+	}
+	/// Receive next frame, `None` once the connection is closed
+	pub async fn recv(&self) -> Option<Vec<u8>> {
+		self.incoming.lock().await.recv().await
+	}
+	/// Close the connection for both sides
+	pub async fn close(mut self) {
+		let _ = self.commands.send(ConnectionCommand::Close(self.id)).await;
+	}
+}
+
+/// Receives frames for every application accepted by the node
+/// The protocols are read for every substream, so applications accepted after a peer connected are reachable on the existing connection
+#[derive(Debug, Clone, Default)]
+pub struct FrameProtocol {
+	applications: Accepted,
+}
+impl UpgradeInfo for FrameProtocol {
+	type Info = Vec<u8>;
+	type InfoIter = std::vec::IntoIter<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		let applications = self.applications.read().expect("Application lock is never poisoned");
+		applications.iter().map(protocol_name).collect::<Vec<_>>().into_iter()
+	}
+}
+impl<TSocket> InboundUpgrade<TSocket> for FrameProtocol
+where
+	TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Output = (Application, Frame);
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+	fn upgrade_inbound(self, mut socket: TSocket, info: Self::Info) -> Self::Future {
+		Box::pin(async move {
+			let application = application_of(&info)
+				.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid application protocol"))?;
+			let data = upgrade::read_one(&mut socket, MAX_ENCODED_FRAME).await
+				.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+			Ok((application, serde_json::from_slice(&data)?))
+		})
+	}
+}
+
+/// Sends a single encoded frame on the protocol of its application
+#[derive(Debug, Clone)]
+pub struct FrameUpgrade {
+	protocol: Vec<u8>,
+	data: Vec<u8>,
+}
+impl UpgradeInfo for FrameUpgrade {
+	type Info = Vec<u8>;
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(self.protocol.clone())
+	}
+}
+impl<TSocket> OutboundUpgrade<TSocket> for FrameUpgrade
+where
+	TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Output = ();
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+	fn upgrade_outbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
+		Box::pin(async move {
+			upgrade::write_one(&mut socket, self.data).await
+		})
+	}
+}
+
+/// Event produced by the `OneShotHandler`
+#[derive(Debug)]
+pub enum InnerMessage {
+	Received(Application, Frame),
+	Sent,
+}
+impl From<(Application, Frame)> for InnerMessage {
+	fn from((application, frame): (Application, Frame)) -> InnerMessage {
+		InnerMessage::Received(application, frame)
+	}
+}
+impl From<()> for InnerMessage {
+	fn from(_: ()) -> InnerMessage {
+		InnerMessage::Sent
+	}
+}
+
+#[derive(Debug)]
+pub enum ConnectionEvent {
+	/// Another node opened a connection to one of our users
+	Incoming(UserConnection),
+	/// Connection could not be made or was lost, with the circuit it was carried on and why if it failed
+	Closed(UserConnectionId, Option<CircuitId>, Option<String>),
+	/// Frame to be sent over an onion circuit
+	CircuitFrame(CircuitId, Application, Frame),
+}
+
+/// State kept for every open `UserConnection`
+struct ConnectionState {
+	route: Route,
+	application: Application,
+	/// Id chosen by the initiator, frames on this connection are addressed by it
+	remote_id: u64,
+	initiator: bool,
+	incoming: mpsc::Sender<Vec<u8>>,
+	/// Sequence number of the next frame we send
+	next_send: u64,
+	/// Sequence number of the next frame handed to the application
+	next_receive: u64,
+	/// Frames that arrived ahead of `next_receive`
+	early: BTreeMap<u64, FrameKind>,
+}
+
+/// Manages `UserConnection`s, each frame is sent on its own substream negotiated on the application's protocol
+pub struct Connections {
+	local: PeerId,
+	next_id: u64,
+	/// Applications other nodes may open connections to
+	applications: Accepted,
+	/// Users hosted on this node
+	local_users: HashSet<UserId>,
+	connections: HashMap<UserConnectionId, ConnectionState>,
+	/// Maps (route, id chosen by the other side) to local id for connections opened by other nodes
+	remote_ids: HashMap<(Route, u64), UserConnectionId>,
+	/// Frames that overtook the `Open` of their connection, by (route, id chosen by the other side)
+	unopened: HashMap<(Route, u64), BTreeMap<u64, FrameKind>>,
+	connected: HashSet<PeerId>,
+	/// Circuits whose connections moved to a direct connection, frames still arriving on them are taken as sent on it
+	moved: HashMap<Route, Route>,
+	/// Addresses to dial for upgrading circuits to a direct connection, see `Connections::dial_direct`
+	direct_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+	/// Frames waiting for a connection to their node
+	pending: HashMap<PeerId, Vec<FrameUpgrade>>,
+	commands_sender: mpsc::Sender<ConnectionCommand>,
+	commands: mpsc::Receiver<ConnectionCommand>,
+	events: VecDeque<NetworkBehaviourAction<FrameUpgrade, ConnectionEvent>>,
+}
+
+impl Connections {
+	pub fn new(local: PeerId) -> Connections {
+		let (commands_sender, commands) = mpsc::channel(CONNECTION_BUFFER);
+		Connections {
+			local,
+			next_id: 0,
+			applications: Accepted::default(),
+			local_users: HashSet::new(),
+			connections: HashMap::new(),
+			remote_ids: HashMap::new(),
+			unopened: HashMap::new(),
+			connected: HashSet::new(),
+			moved: HashMap::new(),
+			direct_addresses: HashMap::new(),
+			pending: HashMap::new(),
+			commands_sender,
+			commands,
+			events: VecDeque::new(),
+		}
+	}
+	/// Accept incoming connections for `application`
+	pub fn accept(&mut self, application: Application) {
+		self.applications.write().expect("Application lock is never poisoned").insert(application);
+	}
+	fn is_accepted(&self, application: &Application) -> bool {
+		self.applications.read().expect("Application lock is never poisoned").contains(application)
+	}
+	pub fn add_local_user(&mut self, user: UserId) {
+		self.local_users.insert(user);
+	}
+	/// Check whether data for `user` on `application` may be delivered to this node
+	pub fn accepts(&self, user: Option<&UserId>, application: &Application) -> Result<(), String> {
+		if let Some(user) = user {
+			if !self.local_users.contains(user) {
+				return Err("User is not hosted on this node".to_owned());
+			}
+		}
+		if !self.is_accepted(application) {
+			return Err(format!("Application {} is not accepted", application.tag()));
+		}
+		Ok(())
+	}
+	/// Open connection to `user` hosted on `node`, frames sent before the node is reached are queued
+	pub fn connect(&mut self, user: UserId, node: PeerId, application: Application) -> UserConnection {
+		self.open(user, node.clone(), application, Route::Direct(node))
+	}
+	/// Open connection to `user` through a circuit ending at `node`, frames are queued by the circuit until it is built
+	pub fn connect_circuit(&mut self, user: UserId, node: PeerId, application: Application, circuit: CircuitId) -> UserConnection {
+		self.open(user, node, application, Route::Circuit(circuit))
+	}
+	fn open(&mut self, user: UserId, node: PeerId, application: Application, route: Route) -> UserConnection {
+		self.accept(application.clone());
+		let id = self.new_id();
+		let circuit = match route { Route::Circuit(circuit) => Some(circuit), Route::Direct(_) => None };
+		let (connection, incoming) = self.new_connection(id, user.clone(), node, application.clone(), true, circuit);
+		self.connections.insert(id, ConnectionState { route, application, remote_id: id.0, initiator: true, incoming, next_send: 0, next_receive: 0, early: BTreeMap::new() });
+		self.send_frame(id, FrameKind::Open(user.into_bytes()));
+		connection
+	}
+	/// Frame received over an onion circuit
+	pub fn receive_circuit_frame(&mut self, circuit: CircuitId, application: Application, frame: Frame) {
+		let route = Route::Circuit(circuit);
+		let route = self.moved.get(&route).cloned().unwrap_or(route);
+		self.receive_frame(route, application, frame);
+	}
+	/// Circuit was torn down, connections on it are closed
+	pub fn circuit_closed(&mut self, circuit: CircuitId) {
+		let route = Route::Circuit(circuit);
+		if self.moved.remove(&route).is_none() {
+			self.route_lost(&route);
+		}
+	}
+	/// Where frames of `id` are sent, `None` once the connection is closed
+	pub fn route_of(&self, id: UserConnectionId) -> Option<Route> {
+		self.connections.get(&id).map(|state| state.route.clone())
+	}
+	/// Connections whose frames go over `circuit`
+	pub fn on_circuit(&self, circuit: CircuitId) -> Vec<UserConnectionId> {
+		let route = Route::Circuit(circuit);
+		self.connections.iter().filter(|(_, state)| state.route == route).map(|(id, _)| *id).collect()
+	}
+	pub fn is_connected(&self, node: &PeerId) -> bool {
+		self.connected.contains(node)
+	}
+	/// Dial `node` on `addresses` it sent over a circuit, answered by the swarm like any other dial
+	pub fn dial_direct(&mut self, node: PeerId, addresses: Vec<Multiaddr>) {
+		self.direct_addresses.insert(node.clone(), addresses);
+		self.events.push_back(NetworkBehaviourAction::DialPeer { peer_id: node, condition: DialPeerCondition::Disconnected });
+	}
+	/// Send frames of connections on `circuit` directly to `node` from now on, returns the connections that moved
+	pub fn move_to_direct(&mut self, circuit: CircuitId, node: PeerId) -> Vec<UserConnectionId> {
+		let (old, new) = (Route::Circuit(circuit), Route::Direct(node));
+		let mut moved = Vec::new();
+		for (id, state) in self.connections.iter_mut().filter(|(_, state)| state.route == old) {
+			state.route = new.clone();
+			if !state.initiator {
+				if let Some(local) = self.remote_ids.remove(&(old.clone(), state.remote_id)) {
+					self.remote_ids.insert((new.clone(), state.remote_id), local);
+				}
+			}
+			moved.push(*id);
+		}
+		self.moved.insert(old, new);
+		moved
+	}
+	/// Close every connection, nodes that are still connected are told about it
+	pub fn close_all(&mut self) {
+		self.pending.clear();
+		let ids: Vec<UserConnectionId> = self.connections.keys().cloned().collect();
+		for id in ids {
+			let reachable = self.connections.get(&id).map_or(false, |state| match &state.route {
+				Route::Direct(node) => self.connected.contains(node),
+				Route::Circuit(_) => true,
+			});
+			if reachable {
+				self.send_frame(id, FrameKind::Close);
+			}
+			self.close(id, None);
+		}
+	}
+	fn new_id(&mut self) -> UserConnectionId {
+		self.next_id += 1;
+		UserConnectionId(self.next_id)
+	}
+	fn new_connection(&self, id: UserConnectionId, user: UserId, node: PeerId, application: Application, initiator: bool, circuit: Option<CircuitId>) -> (UserConnection, mpsc::Sender<Vec<u8>>) {
+		let (incoming_sender, incoming) = mpsc::channel(CONNECTION_BUFFER);
+		(UserConnection {
+			id,
+			user,
+			node,
+			application,
+			initiator,
+			circuit,
+			commands: self.commands_sender.clone(),
+			incoming: Arc::new(Mutex::new(incoming)),
+		}, incoming_sender)
+	}
+	fn send_frame(&mut self, id: UserConnectionId, kind: FrameKind) {
+		if let FrameKind::Data(data) = &kind {
+			if data.len() > MAX_FRAME_SIZE {
+				log::error!("Dropped frame of {} bytes on {:?}, larger than {} bytes", data.len(), id, MAX_FRAME_SIZE);
+				return;
+			}
+		}
+		let state = match self.connections.get_mut(&id) {
+			Some(state) => state,
+			None => { log::warn!("Sending on closed connection {:?}", id); return },
+		};
+		let frame = Frame { connection: state.remote_id, initiator: state.initiator, seq: state.next_send, kind };
+		state.next_send += 1;
+		let node = match &state.route {
+			Route::Direct(node) => node.clone(),
+			Route::Circuit(circuit) => {
+				let event = ConnectionEvent::CircuitFrame(*circuit, state.application.clone(), frame);
+				self.events.push_back(NetworkBehaviourAction::GenerateEvent(event));
+				return;
+			},
+		};
+		let upgrade = FrameUpgrade {
+			protocol: protocol_name(&state.application),
+			data: serde_json::to_vec(&frame).expect("Frames always serialize"),
+		};
+		if self.connected.contains(&node) {
+			self.events.push_back(NetworkBehaviourAction::NotifyHandler { peer_id: node, handler: NotifyHandler::Any, event: upgrade });
+		} else {
+			// Dial once, frames are flushed in `inject_connected`
+			let pending = self.pending.entry(node.clone()).or_default();
+			if pending.is_empty() {
+				self.events.push_back(NetworkBehaviourAction::DialPeer { peer_id: node, condition: DialPeerCondition::Disconnected });
+			}
+			pending.push(upgrade);
+		}
+	}
+	fn close(&mut self, id: UserConnectionId, reason: Option<String>) {
+		if let Some(state) = self.connections.remove(&id) {
+			let circuit = match &state.route { Route::Circuit(circuit) => Some(*circuit), Route::Direct(_) => None };
+			if !state.initiator {
+				self.remote_ids.remove(&(state.route, state.remote_id));
+			}
+			self.events.push_back(NetworkBehaviourAction::GenerateEvent(ConnectionEvent::Closed(id, circuit, reason)));
+		}
+	}
+	fn receive_frame(&mut self, route: Route, application: Application, frame: Frame) {
+		let Frame { connection, initiator, seq, kind } = frame;
+		let id = if initiator {
+			self.remote_ids.get(&(route.clone(), connection)).cloned()
+		} else {
+			// Frame on a connection we opened, addressed by our own id
+			let id = UserConnectionId(connection);
+			match self.connections.get(&id) {
+				Some(state) if state.initiator && state.route == route => Some(id),
+				_ => None,
+			}
+		};
+		match (kind, id) {
+			(kind, Some(id)) => self.deliver(id, seq, kind),
+			(FrameKind::Open(user), None) if initiator && seq == 0 => {
+				let early = self.unopened.remove(&(route.clone(), connection)).unwrap_or_default();
+				let user = match PeerId::from_bytes(user) {
+					Ok(user) if self.local_users.contains(&user) => user,
+					_ => { log::warn!("{:?} tried to connect to a user not on this node", route); return },
+				};
+				if !self.is_accepted(&application) {
+					log::warn!("{:?} tried to connect to unaccepted application {:?}", route, application);
+					return;
+				}
+				let id = self.new_id();
+				let (node, circuit) = match &route {
+					Route::Direct(node) => (node.clone(), None),
+					Route::Circuit(circuit) => (self.local.clone(), Some(*circuit)),
+				};
+				let (connection_handle, incoming) = self.new_connection(id, user, node, application.clone(), false, circuit);
+				self.connections.insert(id, ConnectionState { route: route.clone(), application, remote_id: connection, initiator: false, incoming, next_send: 0, next_receive: 1, early: BTreeMap::new() });
+				self.remote_ids.insert((route, connection), id);
+				self.events.push_back(NetworkBehaviourAction::GenerateEvent(ConnectionEvent::Incoming(connection_handle)));
+				for (seq, kind) in early {
+					self.deliver(id, seq, kind);
+				}
+			},
+			(kind, None) if initiator => {
+				let key = (route, connection);
+				if !self.unopened.contains_key(&key) && self.unopened.len() >= MAX_UNOPENED {
+					log::warn!("Too many unopened connections, dropped frame from {:?}", key.0);
+					return;
+				}
+				let early = self.unopened.entry(key).or_default();
+				if early.len() < CONNECTION_BUFFER {
+					early.insert(seq, kind);
+				}
+			},
+			(kind, None) => log::warn!("{:?} sent unexpected frame: {:?}", route, kind),
+		}
+	}
+	/// Hand frames of `id` to the application in the order they were sent, frames that are early wait for the ones before them
+	/// Frames are never dropped from the middle of a connection, it fails if frames can not be buffered anymore
+	fn deliver(&mut self, id: UserConnectionId, seq: u64, kind: FrameKind) {
+		let state = match self.connections.get_mut(&id) {
+			Some(state) => state,
+			None => return,
+		};
+		if seq < state.next_receive || state.early.contains_key(&seq) {
+			log::debug!("Dropped duplicate frame {} on {:?}", seq, id);
+			return;
+		}
+		if seq > state.next_receive {
+			if state.early.len() >= CONNECTION_BUFFER {
+				self.fail(id, format!("More than {} frames arrived out of order", CONNECTION_BUFFER));
+			} else {
+				state.early.insert(seq, kind);
+			}
+			return;
+		}
+		let mut next = Some(kind);
+		while let Some(kind) = next {
+			state.next_receive += 1;
+			match kind {
+				FrameKind::Data(data) => {
+					if let Err(err) = state.incoming.try_send(data) {
+						let reason = match err {
+							mpsc::error::TrySendError::Full(_) => format!("Application did not read {} frames in time", CONNECTION_BUFFER),
+							mpsc::error::TrySendError::Closed(_) => "Application dropped the connection".to_owned(),
+						};
+						self.fail(id, reason);
+						return;
+					}
+				},
+				FrameKind::Close => {
+					self.close(id, None);
+					return;
+				},
+				FrameKind::Open(_) => log::warn!("Dropped second open frame on {:?}", id),
+			}
+			next = state.early.remove(&state.next_receive);
+		}
+	}
+	/// Close `id` on both sides because frames on it would have been lost
+	fn fail(&mut self, id: UserConnectionId, reason: String) {
+		log::error!("Closing connection {:?}: {}", id, reason);
+		self.send_frame(id, FrameKind::Close);
+		self.close(id, Some(reason));
+	}
+	fn route_lost(&mut self, route: &Route) {
+		self.unopened.retain(|(unopened, _), _| unopened != route);
+		let lost: Vec<UserConnectionId> = self.connections.iter()
+			.filter(|(_, state)| &state.route == route)
+			.map(|(id, _)| *id).collect();
+		for id in lost { self.close(id, None) }
+	}
+}
+
+impl NetworkBehaviour for Connections {
+	type ProtocolsHandler = OneShotHandler<FrameProtocol, FrameUpgrade, InnerMessage>;
+	type OutEvent = ConnectionEvent;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		OneShotHandler::new(SubstreamProtocol::new(FrameProtocol { applications: self.applications.clone() }), OneShotHandlerConfig::default())
+	}
+
+	fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+		self.direct_addresses.get(peer).cloned().unwrap_or_default()
+	}
+
+	fn inject_connected(&mut self, peer: &PeerId) {
+		self.connected.insert(peer.clone());
+		self.direct_addresses.remove(peer);
+		for upgrade in self.pending.remove(peer).unwrap_or_default() {
+			self.events.push_back(NetworkBehaviourAction::NotifyHandler { peer_id: peer.clone(), handler: NotifyHandler::Any, event: upgrade });
+		}
+	}
+
+	fn inject_disconnected(&mut self, peer: &PeerId) {
+		self.connected.remove(peer);
+		self.route_lost(&Route::Direct(peer.clone()));
+	}
+
+	fn inject_dial_failure(&mut self, peer: &PeerId) {
+		self.pending.remove(peer);
+		self.direct_addresses.remove(peer);
+		self.inject_disconnected(peer);
+	}
+
+	fn inject_event(&mut self, peer: PeerId, _: ConnectionId, event: InnerMessage) {
+		if let InnerMessage::Received(application, frame) = event {
+			self.receive_frame(Route::Direct(peer), application, frame);
+		}
+	}
+
+	fn poll(&mut self, cx: &mut Context<'_>, _: &mut impl PollParameters) -> Poll<NetworkBehaviourAction<FrameUpgrade, ConnectionEvent>> {
+		while let Poll::Ready(Some(command)) = self.commands.poll_recv(cx) {
+			match command {
+				ConnectionCommand::Send(id, data) => self.send_frame(id, FrameKind::Data(data)),
+				ConnectionCommand::Close(id) => {
+					self.send_frame(id, FrameKind::Close);
+					self.close(id, None);
+				},
+			}
+		}
+		if let Some(event) = self.events.pop_front() {
+			return Poll::Ready(event);
+		}
+		Poll::Pending
+	}
+}
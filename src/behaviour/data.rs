@@ -0,0 +1,105 @@
+// Reliable delivery of `DitherAction::SendData` payloads, every request is answered with an acknowledgement
+
+use std::io;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use serde_derive::{Serialize, Deserialize};
+use libp2p::{
+	core::upgrade,
+	request_response::{RequestResponseCodec, ProtocolName},
+};
+
+/// Largest payload accepted by `DitherAction::SendData`
+pub const MAX_DATA_SIZE: usize = 1024 * 1024;
+/// Payloads are sent as base64, the rest of the request (user, application tag) has to fit in what is left
+const MAX_REQUEST_SIZE: usize = super::encoding::encoded_len(MAX_DATA_SIZE) + 4096;
+
+#[derive(Debug, Clone)]
+pub struct DataProtocol();
+impl ProtocolName for DataProtocol {
+	fn protocol_name(&self) -> &[u8] {
+		b"/dither/data/1.0.0"
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataRequest {
+	/// User the data is for, `None` if sent back on a connection the other side opened
+	pub user: Option<Vec<u8>>,
+	pub application: String,
+	#[serde(with = "super::encoding")]
+	pub data: Vec<u8>,
+}
+impl DataRequest {
+	/// Whether the encoded request is small enough for the receiving node to read it
+	pub fn fits(&self) -> bool {
+		serde_json::to_vec(self).map_or(false, |encoded| encoded.len() <= MAX_REQUEST_SIZE)
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DataResponse {
+	Delivered,
+	Rejected(String),
+}
+
+/// Why a `DitherAction::SendData` did not arrive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendFailure {
+	/// Node hosting the user could not be reached or dropped the connection
+	Unreachable,
+	/// Node does not host the user or does not accept the application
+	Rejected(String),
+	/// Payload is larger than `MAX_DATA_SIZE`, or the encoded request is larger than a node reads (e.g. because of a very long application tag)
+	TooLarge,
+	/// Node did not acknowledge in time
+	Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct DataCodec();
+
+#[async_trait]
+impl RequestResponseCodec for DataCodec {
+	type Protocol = DataProtocol;
+	type Request = DataRequest;
+	type Response = DataResponse;
+
+	async fn read_request<T>(&mut self, _: &DataProtocol, io: &mut T) -> io::Result<Self::Request>
+	where T: AsyncRead + Unpin + Send
+	{
+		let data = upgrade::read_one(io, MAX_REQUEST_SIZE).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+	async fn read_response<T>(&mut self, _: &DataProtocol, io: &mut T) -> io::Result<Self::Response>
+	where T: AsyncRead + Unpin + Send
+	{
+		let data = upgrade::read_one(io, 1024).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+	async fn write_request<T>(&mut self, _: &DataProtocol, io: &mut T, request: Self::Request) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, serde_json::to_vec(&request)?).await
+	}
+	async fn write_response<T>(&mut self, _: &DataProtocol, io: &mut T, response: Self::Response) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, serde_json::to_vec(&response)?).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn largest_payload_fits() {
+		let request = DataRequest { user: Some(vec![0xff; 38]), application: "chat".to_owned(), data: vec![0xff; MAX_DATA_SIZE] };
+		assert!(request.fits());
+		let request = DataRequest { application: "a".repeat(8192), ..request };
+		assert!(!request.fits());
+	}
+}
@@ -0,0 +1,39 @@
+// Binary data in JSON messages as base64 strings, `Vec<u8>` would otherwise be written as an array of numbers
+// Use with `#[serde(with = "super::encoding")]`
+
+use serde::{Serializer, Deserializer, Deserialize, de::Error};
+
+pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_str(&base64::encode(data))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+	let encoded = String::deserialize(deserializer)?;
+	base64::decode(&encoded).map_err(D::Error::custom)
+}
+
+/// Length of `len` bytes once encoded
+pub const fn encoded_len(len: usize) -> usize {
+	(len + 2) / 3 * 4
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_derive::{Serialize, Deserialize};
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct Message(#[serde(with = "super")] Vec<u8>);
+
+	#[test]
+	fn bytes_round_trip_as_base64() {
+		let message = Message((0..=255).collect());
+		let json = serde_json::to_vec(&message).unwrap();
+		assert_eq!(json.len(), super::encoded_len(256) + 2);
+		assert_eq!(serde_json::from_slice::<Message>(&json).unwrap(), message);
+	}
+
+	#[test]
+	fn invalid_base64_is_rejected() {
+		assert!(serde_json::from_slice::<Message>(b"\"not base64!\"").is_err());
+	}
+}
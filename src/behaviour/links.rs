@@ -0,0 +1,152 @@
+// Sharing measured links between nodes, so paths can be found through peers this node is not connected to
+//
+// Relaying nodes publish the links they measured themselves on a floodsub topic, signed with their node key
+// Other nodes add them to their `RoutingTable` as links between the advertising node and its peers
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_derive::{Serialize, Deserialize};
+use libp2p::{
+	PeerId,
+	identity::{Keypair, PublicKey},
+};
+
+use crate::{DitherError, routing::MEASUREMENT_TTL};
+
+/// Floodsub topic advertisements are published on, it is never handed to applications
+pub const LINKS_TOPIC: &str = "/dither/links/1.0.0";
+/// How often a relaying node advertises its links, well within `MEASUREMENT_TTL` so they do not expire on other nodes
+pub const ADVERTISE_INTERVAL: Duration = Duration::from_secs(30);
+/// Links in a single advertisement, floodsub drops messages over 2 KiB
+pub const MAX_LINKS: usize = 10;
+/// Encoded advertisements stay below this, leaving room for the rest of the floodsub message
+const MAX_ADVERTISEMENT_SIZE: usize = 1536;
+
+/// Link measured by the advertising node: peer, latency in milliseconds and bandwidth in kilobits / second
+pub type Link = (PeerId, u32, u32);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkRecord {
+	/// Seconds since the unix epoch, so old advertisements can not be replayed as fresh ones
+	sent: u64,
+	/// Base58 `PeerId`, latency and bandwidth
+	links: Vec<(String, u32, u32)>,
+}
+
+/// `LinkRecord` signed by the advertising node
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedLinks {
+	/// Protobuf encoded public key of the advertising node
+	#[serde(with = "super::encoding")]
+	key: Vec<u8>,
+	#[serde(with = "super::encoding")]
+	record: Vec<u8>,
+	#[serde(with = "super::encoding")]
+	signature: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> DitherError {
+	DitherError::Encoding(msg.to_owned())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Sign the links measured by the node owning `key`, the cheapest `MAX_LINKS` if there are more
+pub fn advertise(key: &Keypair, links: &[Link], now: SystemTime) -> Result<Vec<u8>, DitherError> {
+	let mut links = links.to_vec();
+	links.sort_by_key(|(_, latency, _)| *latency);
+	let record = serde_json::to_vec(&LinkRecord {
+		sent: unix_secs(now),
+		links: links.into_iter().take(MAX_LINKS).map(|(peer, latency, bandwidth)| (peer.to_base58(), latency, bandwidth)).collect(),
+	}).map_err(|err| DitherError::Encoding(err.to_string()))?;
+	let signature = key.sign(&record).map_err(|err| DitherError::Encoding(err.to_string()))?;
+	let data = serde_json::to_vec(&SignedLinks { key: key.public().into_protobuf_encoding(), record, signature })
+		.map_err(|err| DitherError::Encoding(err.to_string()))?;
+	if data.len() > MAX_ADVERTISEMENT_SIZE {
+		return Err(invalid("Link advertisement too large"));
+	}
+	Ok(data)
+}
+
+/// Links advertised by `source`, checking they were signed by it and are no older than `MEASUREMENT_TTL`
+pub fn verify(source: &PeerId, data: &[u8], now: SystemTime) -> Result<Vec<Link>, DitherError> {
+	let SignedLinks { key, record, signature } = serde_json::from_slice(data).map_err(|err| DitherError::Encoding(err.to_string()))?;
+	let key = PublicKey::from_protobuf_encoding(&key).map_err(|_| invalid("Invalid public key in link advertisement"))?;
+	if &PeerId::from(key.clone()) != source {
+		return Err(invalid("Link advertisement was not signed by its source"));
+	}
+	if !key.verify(&record, &signature) {
+		return Err(invalid("Link advertisement signature is invalid"));
+	}
+	let LinkRecord { sent, links } = serde_json::from_slice(&record).map_err(|err| DitherError::Encoding(err.to_string()))?;
+	// Allow for clocks that are a little off in either direction
+	let now = unix_secs(now);
+	if sent + MEASUREMENT_TTL.as_secs() < now || sent > now + MEASUREMENT_TTL.as_secs() {
+		return Err(invalid("Link advertisement is out of date"));
+	}
+	links.into_iter().take(MAX_LINKS)
+		.map(|(peer, latency, bandwidth)| {
+			let peer = peer.parse().map_err(|_| invalid("Invalid node id in link advertisement"))?;
+			Ok((peer, latency, bandwidth))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn links(count: usize) -> Vec<Link> {
+		(0..count).map(|i| (PeerId::random(), u32::MAX - i as u32, u32::MAX)).collect()
+	}
+
+	#[test]
+	fn advertisement_is_verified() {
+		let key = Keypair::generate_ed25519();
+		let source = PeerId::from(key.public());
+		let now = SystemTime::now();
+		let sent = links(3);
+		let data = advertise(&key, &sent, now).expect("Advertisement fits");
+		let mut received = verify(&source, &data, now).expect("Advertisement is valid");
+		received.reverse();
+		assert_eq!(received, sent);
+	}
+
+	#[test]
+	fn largest_advertisement_fits() {
+		let key = Keypair::generate_ed25519();
+		let data = advertise(&key, &links(MAX_LINKS * 2), SystemTime::now()).expect("Advertisement fits");
+		assert_eq!(verify(&PeerId::from(key.public()), &data, SystemTime::now()).map(|links| links.len()).ok(), Some(MAX_LINKS));
+	}
+
+	#[test]
+	fn other_source_is_rejected() {
+		let key = Keypair::generate_ed25519();
+		let data = advertise(&key, &links(1), SystemTime::now()).expect("Advertisement fits");
+		assert!(verify(&PeerId::random(), &data, SystemTime::now()).is_err());
+	}
+
+	#[test]
+	fn tampered_advertisement_is_rejected() {
+		let key = Keypair::generate_ed25519();
+		let source = PeerId::from(key.public());
+		let data = advertise(&key, &links(1), SystemTime::now()).expect("Advertisement fits");
+		let mut signed: SignedLinks = serde_json::from_slice(&data).expect("Valid advertisement");
+		let mut record: LinkRecord = serde_json::from_slice(&signed.record).expect("Valid record");
+		record.links[0].1 = 0;
+		signed.record = serde_json::to_vec(&record).expect("Record encodes");
+		let data = serde_json::to_vec(&signed).expect("Advertisement encodes");
+		assert!(verify(&source, &data, SystemTime::now()).is_err());
+	}
+
+	#[test]
+	fn old_advertisement_is_rejected() {
+		let key = Keypair::generate_ed25519();
+		let source = PeerId::from(key.public());
+		let sent = SystemTime::now();
+		let data = advertise(&key, &links(1), sent).expect("Advertisement fits");
+		assert!(verify(&source, &data, sent + MEASUREMENT_TTL / 2).is_ok());
+		assert!(verify(&source, &data, sent + MEASUREMENT_TTL * 2).is_err());
+	}
+}
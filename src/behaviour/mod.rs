@@ -0,0 +1,947 @@
+// Define the behaviour of any connection in Dither
+
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	num::NonZeroUsize,
+	task::{Context, Poll},
+	time::{Duration, Instant, SystemTime},
+};
+use futures::FutureExt;
+use serde_derive::{Serialize, Deserialize};
+use tokio::time::{self, Interval};
+use libp2p::{
+	NetworkBehaviour,
+	floodsub::{Floodsub, FloodsubEvent, Topic},
+	mdns::{TokioMdns, MdnsEvent},
+	identify::{Identify, IdentifyEvent, IdentifyInfo},
+	ping::{Ping, PingConfig, PingEvent, PingSuccess},
+	request_response::{RequestResponse, RequestResponseConfig, RequestResponseEvent, RequestResponseMessage, ProtocolSupport, OutboundFailure, RequestId},
+	kad::{Kademlia, KademliaConfig, KademliaEvent, QueryId, QueryResult, GetRecordOk, GetRecordError, Quorum, Record, record::{Key, store::MemoryStore}},
+	swarm::{NetworkBehaviourEventProcess, NetworkBehaviourAction, PollParameters},
+	identity::Keypair,
+	core::ConnectedPoint,
+	PeerId,
+	Multiaddr,
+};
+
+use crate::{Application, User, UserId, NetworkKey, DitherError, DitherConfig, config::{BandwidthProbeConfig, NatRelayConfig, HolePunchConfig}, routing::BandwidthSample};
+
+mod encoding;
+mod connection;
+pub use connection::{UserConnection, UserConnectionId};
+use connection::{Connections, ConnectionEvent, Frame, Route};
+mod data;
+pub use data::{SendFailure, MAX_DATA_SIZE};
+use data::{DataCodec, DataProtocol, DataRequest, DataResponse};
+mod probe;
+use probe::{ProbeCodec, ProbeProtocol, ProbeResponse, MAX_PROBE_SIZE};
+mod reservation;
+use reservation::{ReservationCodec, ReservationProtocol, ReservationRequest, ReservationResponse};
+pub use reservation::RESERVATION_PROTOCOL;
+mod punch;
+use punch::{HolePunch, Draining, MAX_ATTEMPTS};
+mod links;
+pub use links::ADVERTISE_INTERVAL;
+use links::LINKS_TOPIC;
+mod onion;
+pub use onion::CircuitId;
+pub use onion::{IntroductionId, RELAY_PROTOCOL};
+use onion::{Onion, OnionEvent};
+
+/// Id of a `DitherAction::SendData`, returned with `DitherEvent::SendQueued` and `DitherEvent::SendResult`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SendId(u64);
+
+/// Protocol version advertised through identify
+const PROTOCOL_VERSION: &str = "/dither/1.0.0";
+/// Kademlia protocol name, keeps our DHT separate from other libp2p networks
+const KAD_PROTOCOL: &[u8] = b"/dither/kad/1.0.0";
+/// Records of a user compared by `discover_user`, the one with the highest `User::seq` is used
+const DISCOVERY_RECORDS: usize = 3;
+/// How often relay slots are checked for expiry and renewal
+const RESERVATION_CHECK: Duration = Duration::from_secs(10);
+/// How often hole punches are checked for their deadline and our own addresses are refreshed
+const PUNCH_CHECK: Duration = Duration::from_secs(1);
+/// Delivered data smaller than this says more about latency than about bandwidth
+const TRAFFIC_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// End-to-end message between the creator of an onion circuit and its last hop
+#[derive(Debug, Serialize, Deserialize)]
+enum CircuitMessage {
+	/// Frame of a `UserConnection` on the application with this tag
+	Frame(String, Frame),
+	/// `DitherAction::SendData` over the circuit, acknowledged with a `DataResponse` with the same id
+	Data(u64, DataRequest),
+	DataResponse(u64, DataResponse),
+	/// Ask the last hop to move to a direct connection, with the creator's `PeerId` and addresses, see `HolePunchConfig`
+	Upgrade(Vec<u8>, Vec<Vec<u8>>),
+	/// Addresses of the last hop, answering `Upgrade`
+	UpgradeReply(Vec<Vec<u8>>),
+	/// Dial now, the creator dials half a round trip later
+	UpgradeSync,
+	UpgradeRefused(String),
+	/// Signed definition of a hidden user with its `UserId`, put on the DHT by the introduction point at the end of the circuit
+	/// so the record names the introduction point as its publisher instead of the user's node
+	Publish(Vec<u8>, Vec<u8>),
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "DitherEvent", poll_method = "poll")]
+pub struct DitherBehaviour {
+	pub floodsub: Floodsub,
+	pub mdns: TokioMdns,
+	pub identify: Identify,
+	pub ping: Ping,
+	pub kademlia: Kademlia<MemoryStore>,
+	pub connections: Connections,
+	pub data: RequestResponse<DataCodec>,
+	pub probe: RequestResponse<ProbeCodec>,
+	pub onion: Onion,
+	pub reservation: RequestResponse<ReservationCodec>,
+
+	/// `DitherAction::Discover` lookups in progress
+	#[behaviour(ignore)]
+	discoveries: HashMap<QueryId, UserId>,
+	/// `DitherAction::SendData` requests waiting for acknowledgement, with their node, size and when they were sent
+	#[behaviour(ignore)]
+	sends: HashMap<RequestId, (SendId, PeerId, usize, Instant)>,
+	/// `DitherAction::SendData` requests sent over a circuit waiting for acknowledgement
+	#[behaviour(ignore)]
+	circuit_sends: HashMap<SendId, CircuitId>,
+	#[behaviour(ignore)]
+	probe_config: BandwidthProbeConfig,
+	/// Probes waiting for their answer, with their size and when they were sent
+	#[behaviour(ignore)]
+	probes: HashMap<RequestId, (usize, Instant)>,
+	/// Last probe sent to or answered for each peer
+	#[behaviour(ignore)]
+	last_probed: HashMap<PeerId, Instant>,
+	/// Latest ping round trip time of each peer, subtracted from transfer times
+	#[behaviour(ignore)]
+	rtts: HashMap<PeerId, Duration>,
+	#[behaviour(ignore)]
+	nat_relay: NatRelayConfig,
+	/// Nodes with a slot on this node and when it runs out
+	#[behaviour(ignore)]
+	reserved: HashMap<PeerId, Instant>,
+	/// Relays this node has a slot on and when to renew it
+	#[behaviour(ignore)]
+	relays: HashMap<PeerId, Instant>,
+	/// Relays asked for a slot that did not answer yet
+	#[behaviour(ignore)]
+	reserving: HashSet<PeerId>,
+	/// Started on the first poll, timers need the runtime
+	#[behaviour(ignore)]
+	reservation_timer: Option<Interval>,
+	#[behaviour(ignore)]
+	local: PeerId,
+	/// Signs link advertisements
+	#[behaviour(ignore)]
+	key: Keypair,
+	#[behaviour(ignore)]
+	hole_punching: HolePunchConfig,
+	/// Upgrades of relayed circuits to direct connections in progress
+	#[behaviour(ignore)]
+	punches: HashMap<CircuitId, HolePunch>,
+	/// Upgraded circuits and when to close them once no sends are waiting on them
+	#[behaviour(ignore)]
+	draining: Draining<CircuitId>,
+	/// Addresses sent to nodes we punch a hole to, refreshed by `punch_timer`
+	#[behaviour(ignore)]
+	own_addresses: Vec<Vec<u8>>,
+	#[behaviour(ignore)]
+	punch_timer: Option<Interval>,
+	#[behaviour(ignore)]
+	next_send_id: u64,
+	// Events waiting to be returned by the swarm
+	#[behaviour(ignore)]
+	events: VecDeque<DitherEvent>,
+}
+
+#[derive(Debug)]
+pub enum DitherEvent {
+	/// Data received on an application (floodsub topics are named after their application)
+	ReceivedData(Application, Vec<u8>),
+	FloodsubEvent(FloodsubEvent),
+	/// Peers found on the local network
+	Discovered(Vec<(PeerId, Multiaddr)>),
+	/// Peers on the local network that have not been seen for a while
+	Expired(Vec<(PeerId, Multiaddr)>),
+	/// Peer told us about itself
+	Identified(PeerId, IdentifyInfo),
+	PingEvent(PingEvent),
+	/// Reply to `DitherAction::Discover`, `User::user_nodes` are the nodes hosting the user, hidden users only publish `User::introduction_points`
+	UserDiscovered(User),
+	/// No valid definition of this user could be found on the network
+	UserNotFound(UserId),
+	/// Bootstrap node was reached and its `PeerId` verified, address is remembered for the next start
+	Bootstrapped(PeerId, Multiaddr),
+	/// Bootstrap node could not be reached or did not have the expected `PeerId`
+	BootstrapFailed(Multiaddr, DitherError),
+	/// Reply to `DitherAction::Connect` or `DitherAction::ConnectAnonymously`, frames can be sent once the connection is received
+	Connected(UserConnection),
+	/// Another node connected to a user on this node
+	IncomingConnection(UserConnection),
+	/// Connection was closed by either side or the node hosting it disconnected, with the reason if frames on it could not be delivered
+	ConnectionClosed(UserConnectionId, Option<String>),
+	/// `DitherAction::SendData` on this connection was assigned this id, sent in the same order as the actions
+	SendQueued(UserConnectionId, SendId),
+	/// Data was acknowledged by the receiving node or failed to be delivered
+	SendResult(SendId, Result<(), SendFailure>),
+	/// First connection to a peer was established, either dialed by us or accepted by a listener
+	PeerConnected(PeerId, ConnectedPoint),
+	/// Last connection to a peer was closed
+	PeerDisconnected(PeerId, ConnectedPoint),
+	/// Address could not be dialed, `PeerId` is known if the dial was made to a specific peer
+	DialFailed(Option<PeerId>, Multiaddr, DitherError),
+	/// Node is listening on this address
+	NewListenAddr(Multiaddr),
+	/// Node stopped listening on this address (e.g. the interface went down)
+	ExpiredListenAddr(Multiaddr),
+	/// Throughput to a peer was measured, see `DitherConfig::bandwidth_probe`
+	BandwidthMeasured(PeerId, BandwidthSample),
+	/// This node got a slot on a relay, local users publish an address through it, see `NatRelayConfig`
+	RelayReserved(PeerId),
+	/// Relay refused a slot or it could not be renewed, the address through it is no longer published
+	RelayLost(PeerId, String),
+	/// Connection through a relay now goes directly to this node, see `HolePunchConfig`
+	ConnectionUpgraded(UserConnectionId, PeerId),
+	/// No direct connection could be made, the connection stays on its relay
+	UpgradeFailed(UserConnectionId, String),
+	/// Relaying node advertised the links it measured, with latency in milliseconds and bandwidth in kilobits / second
+	/// Answered by the node itself, which adds them to its routing table
+	LinksAdvertised(PeerId, Vec<(PeerId, u32, u32)>),
+	/// Action or network failure that could not be returned any other way
+	Error(DitherError),
+	/// Reply to `DitherAction::CreateUser` and `DitherAction::CreateHiddenUser`, the `NetworkKey` is the only copy of the user's private key outside the node
+	UserCreated(UserId, NetworkKey),
+	/// Someone waits at a rendezvous point to connect to a hidden user on this node, answered by the node itself
+	Introduced(IntroductionId, UserId, PeerId),
+	/// Circuit to this introduction point of a hidden user on this node was lost, answered by the node itself
+	IntroductionLost(UserId, PeerId),
+}
+
+impl DitherEvent {
+	/// Whether `newer` makes the queued `self` outdated, used by `EventPolicy::Coalesce`
+	pub fn coalesces(&self, newer: &DitherEvent) -> bool {
+		match (self, newer) {
+			(DitherEvent::PingEvent(queued), DitherEvent::PingEvent(newer)) => queued.peer == newer.peer,
+			(DitherEvent::Identified(queued, _), DitherEvent::Identified(newer, _)) => queued == newer,
+			_ => false,
+		}
+	}
+}
+
+impl DitherBehaviour {
+	/// Every part of the behaviour reads its own section of `config`
+	/// Connections are closed after `TransportConfig::idle_timeout_secs` without activity, or kept alive by ping if `None`
+	pub fn new(peer: PeerId, key: &Keypair, mdns: TokioMdns, config: &DitherConfig) -> DitherBehaviour {
+		let idle_timeout = config.transport.idle_timeout();
+		Self {
+			floodsub: {
+				let mut floodsub = Floodsub::new(peer.clone());
+				floodsub.subscribe(Topic::new(LINKS_TOPIC));
+				floodsub
+			},
+			mdns,
+			identify: Identify::new(PROTOCOL_VERSION.to_owned(), format!("dither/{}", env!("CARGO_PKG_VERSION")), key.public()),
+			ping: Ping::new(PingConfig::new().with_keep_alive(idle_timeout.is_none())),
+			kademlia: {
+				let mut config = KademliaConfig::default();
+				config.set_protocol_name(KAD_PROTOCOL);
+				if let Some(timeout) = idle_timeout {
+					config.set_connection_idle_timeout(timeout);
+				}
+				Kademlia::with_config(peer.clone(), MemoryStore::new(peer.clone()), config)
+			},
+			connections: Connections::new(peer.clone()),
+			data: {
+				let mut config = RequestResponseConfig::default();
+				if let Some(timeout) = idle_timeout {
+					config.set_connection_keep_alive(timeout);
+				}
+				RequestResponse::new(DataCodec(), vec![(DataProtocol(), ProtocolSupport::Full)], config)
+			},
+			// Nodes that did not opt in do not answer probes
+			probe: RequestResponse::new(ProbeCodec(), vec![(ProbeProtocol(), if config.bandwidth_probe.enabled { ProtocolSupport::Full } else { ProtocolSupport::Outbound })], RequestResponseConfig::default()),
+			onion: Onion::new(key.clone(), &config.cover_traffic, &config.relay),
+			// Only relays answer reservations
+			reservation: RequestResponse::new(ReservationCodec(), vec![(ReservationProtocol(), if config.nat_relay.serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound })], RequestResponseConfig::default()),
+			discoveries: HashMap::new(),
+			sends: HashMap::new(),
+			circuit_sends: HashMap::new(),
+			probe_config: config.bandwidth_probe.clone(),
+			probes: HashMap::new(),
+			last_probed: HashMap::new(),
+			rtts: HashMap::new(),
+			nat_relay: config.nat_relay.clone(),
+			reserved: HashMap::new(),
+			relays: HashMap::new(),
+			reserving: HashSet::new(),
+			reservation_timer: None,
+			local: peer,
+			key: key.clone(),
+			hole_punching: config.hole_punching.clone(),
+			punches: HashMap::new(),
+			draining: Draining::default(),
+			own_addresses: Vec::new(),
+			punch_timer: None,
+			next_send_id: 0,
+			events: VecDeque::new(),
+		}
+	}
+	pub fn subscribe(&mut self, topic: Topic) {
+		self.floodsub.subscribe(topic);
+	}
+	pub fn unsubscribe(&mut self, topic: Topic) {
+		// Paths through nodes we are not connected to are learned on this topic
+		if topic.id() == LINKS_TOPIC { return }
+		self.floodsub.unsubscribe(topic);
+	}
+	/// Publish links this node measured to other nodes, nodes that do not relay for anyone keep theirs to themselves
+	pub fn advertise_links(&mut self, links: &[(PeerId, u32, u32)]) {
+		if !self.onion.is_public() || links.is_empty() { return }
+		match links::advertise(&self.key, links, SystemTime::now()) {
+			Ok(data) => self.floodsub.publish(Topic::new(LINKS_TOPIC), data),
+			Err(err) => log::warn!("Failed to advertise links: {:?}", err),
+		}
+	}
+	pub fn broadcast(&mut self, topic: Topic, data: Vec<u8>) {
+		self.floodsub.publish(topic, data);
+	}
+	/// Store signed definition of a local user on the DHT under its `UserId`
+	pub fn publish_user(&mut self, user: &User, key: &NetworkKey) -> Result<(), DitherError> {
+		let record = Record::new(Key::new(&user.id().clone().into_bytes()), user.to_signed_record(key)?);
+		self.kademlia.put_record(record, Quorum::One)?;
+		Ok(())
+	}
+	/// Have the introduction points at the end of `circuits` put the definition of hidden `user` on the DHT
+	pub fn publish_hidden_user(&mut self, user: &User, key: &NetworkKey, circuits: &[CircuitId]) -> Result<(), DitherError> {
+		let record = user.to_signed_record(key)?;
+		for circuit in circuits {
+			self.send_circuit(*circuit, CircuitMessage::Publish(user.id().clone().into_bytes(), record.clone()));
+		}
+		Ok(())
+	}
+	/// Put the definition of a hidden user that arrived on `circuit` on the DHT, if it names this node as an introduction point
+	/// The signature is all that proves where it came from, circuit cells may overtake each other so its introduction circuit might not be known yet
+	fn publish_introduced(&mut self, circuit: CircuitId, user: Vec<u8>, record: Vec<u8>) {
+		let user_id = match PeerId::from_bytes(user) {
+			Ok(user_id) => user_id,
+			Err(_) => { log::warn!("Invalid user id in definition on circuit {:?}", circuit); return },
+		};
+		match User::from_signed_record(&user_id, &record) {
+			Ok(user) if user.is_hidden() && user.introduction_points().contains(&self.local) => {
+				log::info!("Publishing hidden user {:?} as its introduction point", user_id);
+				if let Err(err) = self.kademlia.put_record(Record::new(Key::new(&user_id.into_bytes()), record), Quorum::One) {
+					log::warn!("Failed to publish hidden user: {:?}", err);
+				}
+			},
+			Ok(_) => log::warn!("Refused to publish {:?}, this node is not one of its introduction points", user_id),
+			Err(err) => log::warn!("Invalid definition of {:?} on circuit {:?}: {:?}", user_id, circuit, err),
+		}
+	}
+	/// Lookup definition of a user on the DHT, answered with `DitherEvent::UserDiscovered` or `DitherEvent::UserNotFound`
+	pub fn discover_user(&mut self, user_id: UserId) {
+		let quorum = Quorum::N(NonZeroUsize::new(DISCOVERY_RECORDS).expect("Not zero"));
+		let query = self.kademlia.get_record(&Key::new(&user_id.clone().into_bytes()), quorum);
+		self.discoveries.insert(query, user_id);
+	}
+	/// Send data to the node on the other side of `connection`, answered with `DitherEvent::SendQueued` and `DitherEvent::SendResult`
+	pub fn send_data(&mut self, connection: &UserConnection, data: Vec<u8>) -> SendId {
+		self.next_send_id += 1;
+		let send_id = SendId(self.next_send_id);
+		self.push_event(DitherEvent::SendQueued(connection.id(), send_id));
+		if data.len() > MAX_DATA_SIZE {
+			self.push_event(DitherEvent::SendResult(send_id, Err(SendFailure::TooLarge)));
+			return send_id;
+		}
+		let request = DataRequest {
+			user: if connection.is_initiator() { Some(connection.user().clone().into_bytes()) } else { None },
+			application: connection.application().tag().to_owned(),
+			data,
+		};
+		if !request.fits() {
+			self.push_event(DitherEvent::SendResult(send_id, Err(SendFailure::TooLarge)));
+			return send_id;
+		}
+		// Upgraded connections moved off the circuit they were opened on
+		let route = self.connections.route_of(connection.id()).unwrap_or_else(|| match connection.circuit() {
+			Some(circuit) => Route::Circuit(circuit),
+			None => Route::Direct(connection.node().clone()),
+		});
+		let node = match route {
+			Route::Circuit(circuit) => {
+				self.circuit_sends.insert(send_id, circuit);
+				self.send_circuit(circuit, CircuitMessage::Data(send_id.0, request));
+				return send_id;
+			},
+			Route::Direct(node) => node,
+		};
+		let size = request.data.len();
+		let request_id = self.data.send_request(&node, request);
+		self.sends.insert(request_id, (send_id, node, size, Instant::now()));
+		send_id
+	}
+	/// Open a `UserConnection` to `user` on `node` through a new onion circuit over `relays`
+	pub fn connect_circuit(&mut self, user: UserId, node: PeerId, application: Application, relays: Vec<PeerId>) -> UserConnection {
+		let mut path = relays;
+		path.push(node.clone());
+		let circuit = self.onion.build_circuit(path);
+		self.connections.connect_circuit(user, node, application, circuit)
+	}
+	/// Open a `UserConnection` to hidden `user` through a circuit over `relays` to `rendezvous`,
+	/// introduced over `intro_path` (ending at one of its introduction points) so neither node learns where the other one is
+	pub fn connect_hidden(&mut self, user: &User, application: Application, rendezvous: PeerId, relays: Vec<PeerId>, intro_path: Vec<PeerId>) -> UserConnection {
+		let mut path = relays;
+		path.push(rendezvous.clone());
+		let circuit = self.onion.connect_hidden(user.id().clone(), user.public_key().clone(), path, intro_path);
+		self.connections.connect_circuit(user.id().clone(), rendezvous, application, circuit)
+	}
+	/// Try moving connections on `circuit` to a direct connection with `node`, its last hop, once the circuit is built
+	/// Answered with `DitherEvent::ConnectionUpgraded` or `DitherEvent::UpgradeFailed` for every connection on it
+	pub fn upgrade_circuit(&mut self, circuit: CircuitId, node: PeerId) {
+		if !self.hole_punching.enabled { return }
+		log::info!("Upgrading circuit {:?} to a direct connection with {:?}", circuit, node);
+		self.punches.insert(circuit, HolePunch::new(node, true, self.hole_punching.timeout()));
+	}
+	/// Send our addresses to the other side of `circuit`, starting an attempt of its upgrade
+	fn send_upgrade(&mut self, circuit: CircuitId) {
+		match self.punches.get_mut(&circuit) {
+			Some(punch) if punch.initiator => punch.restart(Instant::now()),
+			_ => return,
+		}
+		let message = CircuitMessage::Upgrade(self.local.clone().into_bytes(), self.own_addresses.clone());
+		self.send_circuit(circuit, message);
+	}
+	/// Node on the other side of `circuit` wants to upgrade it
+	/// Only circuits that came through one of our relays are, anyone else could learn where this node is
+	fn upgrade_requested(&mut self, circuit: CircuitId, node: Vec<u8>, addresses: Vec<Vec<u8>>) {
+		let through_relay = self.onion.predecessor(circuit).map_or(false, |prev| self.relays.contains_key(prev));
+		let node = match PeerId::from_bytes(node) {
+			Ok(node) if self.hole_punching.enabled && through_relay => node,
+			Ok(_) => {
+				self.send_circuit(circuit, CircuitMessage::UpgradeRefused("Circuit can not be upgraded".to_owned()));
+				return;
+			},
+			Err(_) => {
+				self.send_circuit(circuit, CircuitMessage::UpgradeRefused("Invalid peer id".to_owned()));
+				return;
+			},
+		};
+		log::info!("{:?} asked to upgrade circuit {:?} to a direct connection", node, circuit);
+		// Retries of the initiator start over
+		let mut punch = HolePunch::new(node, false, self.hole_punching.timeout());
+		punch.addresses = punch::parse_addresses(addresses);
+		self.punches.insert(circuit, punch);
+		self.send_circuit(circuit, CircuitMessage::UpgradeReply(self.own_addresses.clone()));
+	}
+	/// Other side of `circuit` answered with its addresses, it dials once it has our sync and we half a round trip later
+	fn upgrade_answered(&mut self, circuit: CircuitId, addresses: Vec<Vec<u8>>) {
+		match self.punches.get_mut(&circuit) {
+			Some(punch) if punch.initiator => {
+				let wait = punch.answered(punch::parse_addresses(addresses), Instant::now());
+				punch.dial = Some(time::delay_for(wait));
+			},
+			_ => return,
+		}
+		self.send_circuit(circuit, CircuitMessage::UpgradeSync);
+	}
+	/// Dial the other side of `circuit` on the addresses it sent
+	fn punch_dial(&mut self, circuit: CircuitId) {
+		let (node, addresses) = match self.punches.get_mut(&circuit) {
+			Some(punch) => {
+				punch.dialed = true;
+				(punch.node.clone(), punch.addresses.clone())
+			},
+			None => return,
+		};
+		// A connection made another way does just as well
+		if self.connections.is_connected(&node) {
+			self.peer_connected(&node);
+			return;
+		}
+		log::debug!("Dialing {:?} directly on {:?}", node, addresses);
+		self.connections.dial_direct(node, addresses);
+	}
+	fn punch_failed(&mut self, circuit: CircuitId, reason: String) {
+		if self.punches.remove(&circuit).is_none() { return }
+		log::info!("Circuit {:?} stays relayed: {}", circuit, reason);
+		for id in self.connections.on_circuit(circuit) {
+			self.push_event(DitherEvent::UpgradeFailed(id, reason.clone()));
+		}
+	}
+	/// A connection to `peer` was established, which completes upgrades of circuits to it
+	pub fn peer_connected(&mut self, peer: &PeerId) {
+		let done: Vec<CircuitId> = self.punches.iter().filter(|(_, punch)| punch.node == *peer).map(|(circuit, _)| *circuit).collect();
+		for circuit in done {
+			let initiator = self.punches.remove(&circuit).map_or(false, |punch| punch.initiator);
+			log::info!("Circuit {:?} upgraded to a direct connection with {:?}", circuit, peer);
+			for id in self.connections.move_to_direct(circuit, peer.clone()) {
+				self.push_event(DitherEvent::ConnectionUpgraded(id, peer.clone()));
+			}
+			// Only the creator knows when the circuit is no longer needed
+			if initiator {
+				self.draining.insert(circuit, Instant::now());
+			}
+		}
+	}
+	/// Dialing `peer` failed on every address, upgrades to it start over until they run out of attempts
+	pub fn direct_dial_failed(&mut self, peer: &PeerId) {
+		let failed: Vec<CircuitId> = self.punches.iter()
+			.filter(|(_, punch)| punch.initiator && punch.dialed && punch.node == *peer)
+			.map(|(circuit, _)| *circuit).collect();
+		for circuit in failed {
+			if self.punches.get_mut(&circuit).map_or(false, HolePunch::dial_failed) {
+				self.send_upgrade(circuit);
+			} else {
+				self.punch_failed(circuit, format!("Direct dials failed {} times", MAX_ATTEMPTS));
+			}
+		}
+	}
+	/// Fail hole punches past their deadline, refresh our addresses and close upgraded circuits that are no longer used
+	fn check_punches(&mut self, params: &mut impl PollParameters) {
+		self.own_addresses = punch::dialable(params.listened_addresses().chain(params.external_addresses()));
+		let now = Instant::now();
+		let expired: Vec<CircuitId> = self.punches.iter().filter(|(_, punch)| punch.is_expired(now)).map(|(circuit, _)| *circuit).collect();
+		for circuit in expired {
+			self.punch_failed(circuit, "No direct connection was made in time".to_owned());
+		}
+		let circuit_sends = &self.circuit_sends;
+		for circuit in self.draining.done(now, |circuit| circuit_sends.values().any(|c| c == circuit)) {
+			self.onion.close(circuit);
+			self.connections.circuit_closed(circuit);
+		}
+	}
+	fn send_circuit(&mut self, circuit: CircuitId, message: CircuitMessage) {
+		self.onion.send(circuit, serde_json::to_vec(&message).expect("Circuit messages always serialize"));
+	}
+	/// Connections and sends on a circuit that was torn down fail
+	fn circuit_lost(&mut self, circuit: CircuitId) {
+		self.punches.remove(&circuit);
+		self.draining.remove(&circuit);
+		self.connections.circuit_closed(circuit);
+		let lost: Vec<SendId> = self.circuit_sends.iter().filter(|(_, c)| **c == circuit).map(|(id, _)| *id).collect();
+		for send_id in lost {
+			self.circuit_sends.remove(&send_id);
+			self.push_event(DitherEvent::SendResult(send_id, Err(SendFailure::Unreachable)));
+		}
+	}
+	/// Data that arrived over a circuit or a direct request, answered with whether it was delivered
+	fn receive_data(&mut self, request: DataRequest) -> DataResponse {
+		let application = Application::new(&request.application);
+		let user = match request.user.map(PeerId::from_bytes).transpose() {
+			Ok(user) => user,
+			Err(_) => return DataResponse::Rejected("Invalid user id".to_owned()),
+		};
+		match self.connections.accepts(user.as_ref(), &application) {
+			Ok(()) => {
+				log::debug!("Received {} bytes for {:?}", request.data.len(), application);
+				self.push_event(DitherEvent::ReceivedData(application, request.data));
+				DataResponse::Delivered
+			},
+			Err(reason) => DataResponse::Rejected(reason),
+		}
+	}
+	/// Whether `peer` was probed (in either direction) within the configured interval, marks it as probed now if not
+	fn probe_due(&mut self, peer: &PeerId) -> bool {
+		let now = Instant::now();
+		match self.last_probed.get(peer) {
+			Some(last) if now.duration_since(*last) < self.probe_config.interval() => false,
+			_ => { self.last_probed.insert(peer.clone(), now); true },
+		}
+	}
+	/// Send a probe to `peer` if probing is enabled and it is due
+	fn probe_peer(&mut self, peer: &PeerId) {
+		if !self.probe_config.enabled || !self.probe_due(peer) { return }
+		let size = self.probe_config.probe_bytes.min(MAX_PROBE_SIZE);
+		log::debug!("Probing bandwidth to {:?} with {} bytes", peer, size);
+		let request_id = self.probe.send_request(peer, vec![0; size]);
+		self.probes.insert(request_id, (size, Instant::now()));
+	}
+	/// Throughput of `bytes` sent to `peer` and acknowledged after `elapsed`, without the round trip
+	fn throughput(&self, peer: &PeerId, bytes: usize, elapsed: Duration) -> u32 {
+		let transfer = self.rtts.get(peer)
+			.and_then(|rtt| elapsed.checked_sub(*rtt))
+			.filter(|transfer| *transfer > Duration::from_millis(1))
+			.unwrap_or(elapsed);
+		probe::kbps(bytes, transfer)
+	}
+	/// Ask `relay` for a slot, answered with `DitherEvent::RelayReserved` or `DitherEvent::RelayLost`
+	pub fn reserve(&mut self, relay: PeerId) {
+		log::info!("Asking {:?} for a relay slot", relay);
+		self.reservation.send_request(&relay, ReservationRequest::Reserve);
+		if !self.relays.contains_key(&relay) {
+			self.reserving.insert(relay);
+		}
+	}
+	/// Whether this node has or asked for a slot on `relay`
+	pub fn reserves_on(&self, relay: &PeerId) -> bool {
+		self.relays.contains_key(relay) || self.reserving.contains(relay)
+	}
+	/// A slot on `relay` was refused or lost, with `DitherEvent::Error` once no relay is left to reach this node through
+	fn reservation_lost(&mut self, relay: PeerId, reason: String) {
+		let reserved = self.relays.remove(&relay).is_some();
+		let asked = self.reserving.remove(&relay);
+		if !reserved && !asked { return }
+		self.onion.remove_relay(&relay);
+		self.push_event(DitherEvent::RelayLost(relay, reason));
+		if self.relays.is_empty() && self.reserving.is_empty() {
+			log::warn!("No relay slot left, nodes behind NAT can not be reached");
+			self.push_event(DitherEvent::Error(DitherError::Transport("No relay gave this node a slot".to_owned())));
+		}
+	}
+	/// Give up every slot on a relay, used when the node shuts down
+	pub fn release_relays(&mut self) {
+		for (relay, _) in self.relays.drain() {
+			self.reservation.send_request(&relay, ReservationRequest::Release);
+		}
+	}
+	/// Drop slots on this node that were not renewed and renew our own slots that are due
+	fn check_reservations(&mut self) {
+		let now = Instant::now();
+		let expired: Vec<PeerId> = self.reserved.iter().filter(|(_, until)| **until <= now).map(|(peer, _)| peer.clone()).collect();
+		for peer in expired {
+			log::info!("Relay slot of {:?} expired", peer);
+			self.reserved.remove(&peer);
+			self.onion.remove_reserved(&peer);
+		}
+		let due: Vec<PeerId> = self.relays.iter().filter(|(_, renew)| **renew <= now).map(|(relay, _)| relay.clone()).collect();
+		for relay in due {
+			// Pushed back again once the relay answers
+			self.relays.insert(relay.clone(), now + self.nat_relay.reservation());
+			self.reserve(relay);
+		}
+	}
+	pub fn add_address(&mut self, peer: &PeerId, addr: Multiaddr) {
+		self.kademlia.add_address(peer, addr);
+	}
+	pub fn add_peer(&mut self, peer: PeerId) {
+		log::info!("Adding Peer: {:?}", peer);
+		self.floodsub.add_node_to_partial_view(peer);
+	}
+	pub fn remove_peer(&mut self, peer: &PeerId) {
+		log::info!("Removing Peer: {:?}", peer);
+		self.floodsub.remove_node_from_partial_view(peer);
+	}
+	/// Queue event to be returned from the swarm
+	pub fn push_event(&mut self, event: DitherEvent) {
+		self.events.push_back(event);
+	}
+	fn poll<TEv>(&mut self, cx: &mut Context, params: &mut impl PollParameters) -> Poll<NetworkBehaviourAction<TEv, DitherEvent>> {
+		if self.nat_relay.serve || self.nat_relay.reserve {
+			while self.reservation_timer.get_or_insert_with(|| tokio::time::interval(RESERVATION_CHECK)).poll_tick(cx).is_ready() {
+				self.check_reservations();
+			}
+		}
+		if self.hole_punching.enabled {
+			while self.punch_timer.get_or_insert_with(|| tokio::time::interval(PUNCH_CHECK)).poll_tick(cx).is_ready() {
+				self.check_punches(params);
+			}
+			let mut due = Vec::new();
+			for (circuit, punch) in self.punches.iter_mut() {
+				if let Some(dial) = punch.dial.as_mut() {
+					if dial.poll_unpin(cx).is_ready() {
+						punch.dial = None;
+						due.push(*circuit);
+					}
+				}
+			}
+			for circuit in due {
+				self.punch_dial(circuit);
+			}
+		}
+		if let Some(event) = self.events.pop_front() {
+			return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+		}
+		Poll::Pending
+	}
+}
+
+impl NetworkBehaviourEventProcess<FloodsubEvent> for DitherBehaviour {
+	// Called when `floodsub` produces an event.
+	fn inject_event(&mut self, event: FloodsubEvent) {
+		match event {
+			FloodsubEvent::Message(message) if message.topics.iter().any(|topic| topic.id() == LINKS_TOPIC) => {
+				match links::verify(&message.source, &message.data, SystemTime::now()) {
+					Ok(links) => self.push_event(DitherEvent::LinksAdvertised(message.source, links)),
+					Err(err) => log::debug!("Invalid link advertisement from {:?}: {:?}", message.source, err),
+				}
+			},
+			FloodsubEvent::Message(message) => {
+				log::debug!("Received: '{:?}' from {:?}", String::from_utf8_lossy(&message.data), message.source);
+				for topic in &message.topics {
+					self.push_event(DitherEvent::ReceivedData(Application::new(topic.id()), message.data.clone()));
+				}
+			},
+			_ => self.push_event(DitherEvent::FloodsubEvent(event)),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for DitherBehaviour {
+	// Called when `mdns` produces an event.
+	fn inject_event(&mut self, event: MdnsEvent) {
+		match event {
+			MdnsEvent::Discovered(list) => {
+				let list: Vec<(PeerId, Multiaddr)> = list.collect();
+				for (peer, addr) in &list {
+					self.add_peer(peer.clone());
+					self.add_address(peer, addr.clone());
+				}
+				self.push_event(DitherEvent::Discovered(list));
+			}
+			MdnsEvent::Expired(list) => {
+				let list: Vec<(PeerId, Multiaddr)> = list.collect();
+				for (peer, _) in &list {
+					if !self.mdns.has_node(peer) {
+						self.remove_peer(peer);
+					}
+				}
+				self.push_event(DitherEvent::Expired(list));
+			}
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for DitherBehaviour {
+	// Called when `identify` produces an event.
+	fn inject_event(&mut self, event: IdentifyEvent) {
+		match event {
+			IdentifyEvent::Received { peer_id, info, .. } => {
+				log::info!("Identified {:?} running {}", peer_id, info.agent_version);
+				// Only peers speaking our DHT protocol belong in the routing table
+				if info.protocols.iter().any(|protocol| protocol.as_bytes() == KAD_PROTOCOL) {
+					for addr in &info.listen_addrs {
+						self.add_address(&peer_id, addr.clone());
+					}
+				}
+				self.push_event(DitherEvent::Identified(peer_id, info));
+			},
+			IdentifyEvent::Error { peer_id, error } => log::warn!("Failed to identify {:?}: {:?}", peer_id, error),
+			IdentifyEvent::Sent { .. } => {},
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for DitherBehaviour {
+	// Called when `ping` produces an event.
+	fn inject_event(&mut self, event: PingEvent) {
+		if let Ok(PingSuccess::Ping { rtt }) = &event.result {
+			self.rtts.insert(event.peer.clone(), *rtt);
+			// Pings arrive regularly for every connected peer, probes piggyback on them
+			self.probe_peer(&event.peer);
+		}
+		self.push_event(DitherEvent::PingEvent(event));
+	}
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for DitherBehaviour {
+	// Called when `kademlia` produces an event.
+	fn inject_event(&mut self, event: KademliaEvent) {
+		match event {
+			KademliaEvent::QueryResult { id, result: QueryResult::GetRecord(result), .. } => {
+				let user_id = match self.discoveries.remove(&id) {
+					Some(user_id) => user_id,
+					None => return,
+				};
+				let records = match result {
+					Ok(GetRecordOk { records }) => records,
+					// Fewer nodes than asked had a record, the ones found are still worth comparing
+					Err(GetRecordError::QuorumFailed { records, .. }) | Err(GetRecordError::Timeout { records, .. }) => records,
+					Err(err) => { log::info!("Failed to find user {:?}: {:?}", user_id, err); Vec::new() },
+				};
+				// Stale records stay on the DHT until they expire, only the newest valid one counts
+				let user = records.iter().filter_map(|record| {
+					match User::from_signed_record(&user_id, &record.value) {
+						Ok(user) => Some(user),
+						Err(err) => { log::warn!("Invalid user record for {:?}: {:?}", user_id, err); None },
+					}
+				}).max_by_key(User::seq);
+				self.push_event(match user {
+					Some(user) => DitherEvent::UserDiscovered(user),
+					None => DitherEvent::UserNotFound(user_id),
+				});
+			},
+			KademliaEvent::QueryResult { result: QueryResult::PutRecord(result), .. } => {
+				match result {
+					Ok(ok) => log::info!("Published user record: {:?}", ok.key),
+					Err(err) => log::warn!("Failed to publish user record: {:?}", err),
+				}
+			},
+			_ => log::debug!("Kademlia Event: {:?}", event),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<ConnectionEvent> for DitherBehaviour {
+	// Called when `connections` produces an event.
+	fn inject_event(&mut self, event: ConnectionEvent) {
+		match event {
+			ConnectionEvent::Incoming(connection) => self.push_event(DitherEvent::IncomingConnection(connection)),
+			ConnectionEvent::Closed(id, circuit, reason) => {
+				// Every circuit carries a single connection
+				if let Some(circuit) = circuit {
+					self.onion.close(circuit);
+				}
+				self.push_event(DitherEvent::ConnectionClosed(id, reason));
+			},
+			ConnectionEvent::CircuitFrame(circuit, application, frame) => self.send_circuit(circuit, CircuitMessage::Frame(application.tag().to_owned(), frame)),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<DataRequest, DataResponse>> for DitherBehaviour {
+	// Called when `data` produces an event.
+	fn inject_event(&mut self, event: RequestResponseEvent<DataRequest, DataResponse>) {
+		match event {
+			RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+				log::debug!("Data request from {:?}", peer);
+				let response = self.receive_data(request);
+				self.data.send_response(channel, response);
+			},
+			RequestResponseEvent::Message { message: RequestResponseMessage::Response { request_id, response }, .. } => {
+				if let Some((send_id, node, size, sent)) = self.sends.remove(&request_id) {
+					if let (DataResponse::Delivered, true) = (&response, size >= TRAFFIC_SAMPLE_SIZE) {
+						let bandwidth = self.throughput(&node, size, sent.elapsed());
+						self.push_event(DitherEvent::BandwidthMeasured(node, BandwidthSample::Traffic(bandwidth)));
+					}
+					self.push_event(DitherEvent::SendResult(send_id, match response {
+						DataResponse::Delivered => Ok(()),
+						DataResponse::Rejected(reason) => Err(SendFailure::Rejected(reason)),
+					}));
+				}
+			},
+			RequestResponseEvent::OutboundFailure { request_id, error, .. } => {
+				if let Some((send_id, ..)) = self.sends.remove(&request_id) {
+					self.push_event(DitherEvent::SendResult(send_id, Err(match error {
+						OutboundFailure::Timeout => SendFailure::Timeout,
+						_ => SendFailure::Unreachable,
+					})));
+				}
+			},
+			RequestResponseEvent::InboundFailure { peer, error, .. } => log::warn!("Failed to receive data from {:?}: {:?}", peer, error),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Vec<u8>, ProbeResponse>> for DitherBehaviour {
+	// Called when `probe` produces an event.
+	fn inject_event(&mut self, event: RequestResponseEvent<Vec<u8>, ProbeResponse>) {
+		match event {
+			RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+				let response = if self.probe_due(&peer) { ProbeResponse::Received(request.len()) } else { ProbeResponse::Refused };
+				self.probe.send_response(channel, response);
+			},
+			RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { request_id, response } } => {
+				if let Some((size, sent)) = self.probes.remove(&request_id) {
+					match response {
+						ProbeResponse::Received(received) if received == size => {
+							let bandwidth = self.throughput(&peer, size, sent.elapsed());
+							log::debug!("Bandwidth to {:?}: {} kb/s", peer, bandwidth);
+							self.push_event(DitherEvent::BandwidthMeasured(peer, BandwidthSample::Probe(bandwidth)));
+						},
+						response => log::debug!("Probe to {:?} not measured: {:?}", peer, response),
+					}
+				}
+			},
+			RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+				self.probes.remove(&request_id);
+				log::debug!("Failed to probe {:?}: {:?}", peer, error);
+			},
+			RequestResponseEvent::InboundFailure { peer, error, .. } => log::debug!("Failed to answer probe from {:?}: {:?}", peer, error),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<ReservationRequest, ReservationResponse>> for DitherBehaviour {
+	// Called when `reservation` produces an event.
+	fn inject_event(&mut self, event: RequestResponseEvent<ReservationRequest, ReservationResponse>) {
+		match event {
+			RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+				let response = match request {
+					ReservationRequest::Reserve if !self.reserved.contains_key(&peer) && self.reserved.len() >= self.nat_relay.max_reservations => {
+						ReservationResponse::Refused("No free relay slots".to_owned())
+					},
+					ReservationRequest::Reserve => {
+						if self.reserved.insert(peer.clone(), Instant::now() + self.nat_relay.reservation()).is_none() {
+							log::info!("{:?} got a relay slot", peer);
+							self.onion.add_reserved(peer);
+						}
+						ReservationResponse::Accepted(self.nat_relay.reservation_secs)
+					},
+					ReservationRequest::Release => {
+						if self.reserved.remove(&peer).is_some() {
+							self.onion.remove_reserved(&peer);
+						}
+						ReservationResponse::Released
+					},
+				};
+				self.reservation.send_response(channel, response);
+			},
+			RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { response, .. } } => match response {
+				ReservationResponse::Accepted(secs) => {
+					// Renewed halfway, so a lost answer still leaves time for another try
+					let renew = Instant::now() + Duration::from_secs(secs / 2);
+					self.reserving.remove(&peer);
+					if self.relays.insert(peer.clone(), renew).is_none() {
+						log::info!("Got a relay slot on {:?} for {} seconds", peer, secs);
+						self.onion.add_relay(peer.clone());
+						self.push_event(DitherEvent::RelayReserved(peer));
+					}
+				},
+				ReservationResponse::Released => {},
+				ReservationResponse::Refused(reason) => self.reservation_lost(peer, reason),
+			},
+			RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+				log::warn!("Failed to reserve a relay slot on {:?}: {:?}", peer, error);
+				self.reservation_lost(peer, format!("{:?}", error));
+			},
+			RequestResponseEvent::InboundFailure { peer, error, .. } => log::debug!("Failed to answer reservation from {:?}: {:?}", peer, error),
+		}
+	}
+}
+
+impl NetworkBehaviourEventProcess<OnionEvent> for DitherBehaviour {
+	// Called when `onion` produces an event.
+	fn inject_event(&mut self, event: OnionEvent) {
+		match event {
+			OnionEvent::Received(circuit, data) => match serde_json::from_slice(&data) {
+				Ok(CircuitMessage::Frame(application, frame)) => self.connections.receive_circuit_frame(circuit, Application::new(&application), frame),
+				Ok(CircuitMessage::Data(id, request)) => {
+					let response = self.receive_data(request);
+					self.send_circuit(circuit, CircuitMessage::DataResponse(id, response));
+				},
+				Ok(CircuitMessage::DataResponse(id, response)) => {
+					if self.circuit_sends.remove(&SendId(id)).is_some() {
+						self.push_event(DitherEvent::SendResult(SendId(id), match response {
+							DataResponse::Delivered => Ok(()),
+							DataResponse::Rejected(reason) => Err(SendFailure::Rejected(reason)),
+						}));
+					}
+				},
+				Ok(CircuitMessage::Upgrade(node, addresses)) => self.upgrade_requested(circuit, node, addresses),
+				Ok(CircuitMessage::UpgradeReply(addresses)) => self.upgrade_answered(circuit, addresses),
+				Ok(CircuitMessage::UpgradeSync) => {
+					if self.punches.get(&circuit).map_or(false, |punch| !punch.initiator) {
+						self.punch_dial(circuit);
+					}
+				},
+				Ok(CircuitMessage::UpgradeRefused(reason)) => self.punch_failed(circuit, reason),
+				Ok(CircuitMessage::Publish(user, record)) => self.publish_introduced(circuit, user, record),
+				Err(err) => log::warn!("Invalid message on circuit {:?}: {:?}", circuit, err),
+			},
+			OnionEvent::Built(circuit) => {
+				log::debug!("Circuit {:?} is ready", circuit);
+				self.send_upgrade(circuit);
+			},
+			OnionEvent::Failed(circuit, reason) => {
+				log::warn!("Circuit {:?} failed: {}", circuit, reason);
+				self.circuit_lost(circuit);
+			},
+			OnionEvent::Closed(circuit) => self.circuit_lost(circuit),
+			OnionEvent::Introduction(introduction, user, rendezvous) => self.push_event(DitherEvent::Introduced(introduction, user, rendezvous)),
+			OnionEvent::IntroductionLost(user, point) => self.push_event(DitherEvent::IntroductionLost(user, point)),
+		}
+	}
+}
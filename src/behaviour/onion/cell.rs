@@ -0,0 +1,435 @@
+// Cells exchanged between neighbours on a circuit and the commands carried inside relay cells
+
+use std::{
+	convert::TryInto,
+	io,
+	iter,
+	pin::Pin,
+};
+use futures::{Future, io::{AsyncRead, AsyncWrite}};
+use libp2p::core::upgrade::{self, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+
+use super::crypto::{NONCE_SIZE, LAYER_HEADER};
+
+/// Every cell is padded to this size, so its length tells nothing about its content
+pub const CELL_SIZE: usize = 4096;
+/// Layered part of a relay cell, after its tag, circuit number, direction and nonce
+pub const RELAY_PAYLOAD_SIZE: usize = CELL_SIZE - 1 - 8 - 1 - NONCE_SIZE;
+/// Largest encoded `RelayCommand` that fits in a relay cell
+pub const MAX_COMMAND_SIZE: usize = RELAY_PAYLOAD_SIZE - LAYER_HEADER;
+/// End-to-end data carried by one `RelayCommand::Data`, after its tag and fragment header
+pub const DATA_FRAGMENT_SIZE: usize = MAX_COMMAND_SIZE - 1 - 4 - 4 - 4;
+const PROTOCOL_NAME: &[u8] = b"/dither/onion/1.0.0";
+/// Only listed so identify tells peers this node relays circuits of anyone
+pub const RELAY_PROTOCOL: &[u8] = b"/dither/onion-relay/1.0.0";
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads the fields of an encoded cell or command in order
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+		if self.0.len() < len { return Err(invalid("Truncated cell")) }
+		let (field, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Ok(field)
+	}
+	fn u8(&mut self) -> io::Result<u8> { Ok(self.take(1)?[0]) }
+	fn u32(&mut self) -> io::Result<u32> { Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap())) }
+	fn u64(&mut self) -> io::Result<u64> { Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap())) }
+	fn array<T: Default + AsMut<[u8]>>(&mut self) -> io::Result<T> {
+		let mut array = T::default();
+		let len = array.as_mut().len();
+		array.as_mut().copy_from_slice(self.take(len)?);
+		Ok(array)
+	}
+	/// Field prefixed by its length as u16
+	fn short(&mut self) -> io::Result<Vec<u8>> {
+		let len = u16::from_be_bytes(self.take(2)?.try_into().unwrap());
+		Ok(self.take(len as usize)?.to_vec())
+	}
+	fn rest(&mut self) -> Vec<u8> { std::mem::take(&mut self.0).to_vec() }
+}
+fn write_short(out: &mut Vec<u8>, field: &[u8]) {
+	out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+	out.extend_from_slice(field);
+}
+
+/// Answer of a relay to a circuit handshake, signed with the relay's node key
+#[derive(Debug, Clone)]
+pub struct HandshakeReply {
+	pub handshake: [u8; 32],
+	/// Protobuf encoded public key of the relay
+	pub key: Vec<u8>,
+	pub signature: Vec<u8>,
+}
+impl HandshakeReply {
+	fn write(&self, out: &mut Vec<u8>) {
+		out.extend_from_slice(&self.handshake);
+		write_short(out, &self.key);
+		write_short(out, &self.signature);
+	}
+	fn read(reader: &mut Reader) -> io::Result<HandshakeReply> {
+		Ok(HandshakeReply { handshake: reader.array()?, key: reader.short()?, signature: reader.short()? })
+	}
+}
+
+/// Message between two neighbours on a circuit
+/// `circuit` is the number chosen by the side that created the link, `forward` is set on cells sent by that side
+#[derive(Debug, Clone)]
+pub enum Cell {
+	/// Ask a node to become the next hop of a circuit
+	Create { circuit: u64, handshake: [u8; 32] },
+	Created { circuit: u64, reply: HandshakeReply },
+	/// Payload with one layer per remaining hop, every hop replaces the nonce so the cell looks different on each link
+	Relay { circuit: u64, forward: bool, nonce: [u8; NONCE_SIZE], payload: Vec<u8> },
+	/// Tear down the circuit from this link onwards
+	Destroy { circuit: u64, forward: bool },
+}
+
+fn pad(mut out: Vec<u8>) -> Vec<u8> {
+	debug_assert!(out.len() <= CELL_SIZE, "Cell is larger than CELL_SIZE");
+	out.resize(CELL_SIZE, 0);
+	out
+}
+impl Cell {
+	pub fn encode(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		match self {
+			Cell::Create { circuit, handshake } => {
+				out.push(0);
+				out.extend_from_slice(&circuit.to_be_bytes());
+				out.extend_from_slice(handshake);
+			},
+			Cell::Created { circuit, reply } => {
+				out.push(1);
+				out.extend_from_slice(&circuit.to_be_bytes());
+				reply.write(&mut out);
+			},
+			Cell::Relay { circuit, forward, nonce, payload } => {
+				out.push(2);
+				out.extend_from_slice(&circuit.to_be_bytes());
+				out.push(*forward as u8);
+				out.extend_from_slice(nonce);
+				out.extend_from_slice(payload);
+			},
+			Cell::Destroy { circuit, forward } => {
+				out.push(3);
+				out.extend_from_slice(&circuit.to_be_bytes());
+				out.push(*forward as u8);
+			},
+		}
+		pad(out)
+	}
+	pub fn decode(data: &[u8]) -> io::Result<Cell> {
+		if data.len() != CELL_SIZE { return Err(invalid("Cell has the wrong size")) }
+		let mut reader = Reader(data);
+		let tag = reader.u8()?;
+		let circuit = reader.u64()?;
+		Ok(match tag {
+			0 => Cell::Create { circuit, handshake: reader.array()? },
+			1 => Cell::Created { circuit, reply: HandshakeReply::read(&mut reader)? },
+			2 => Cell::Relay { circuit, forward: reader.u8()? != 0, nonce: reader.array()?, payload: reader.take(RELAY_PAYLOAD_SIZE)?.to_vec() },
+			3 => Cell::Destroy { circuit, forward: reader.u8()? != 0 },
+			_ => return Err(invalid("Unknown cell")),
+		})
+	}
+}
+
+/// Body of a relay cell once it reached the hop it is addressed to
+#[derive(Debug, Clone)]
+pub enum RelayCommand {
+	/// Extend the circuit to the node with this `PeerId`
+	Extend { next: Vec<u8>, handshake: [u8; 32] },
+	Extended(HandshakeReply),
+	ExtendFailed(String),
+	/// Fragment `index` of `count` of an end-to-end message between the circuit's creator and its last hop
+	Data { message: u32, index: u32, count: u32, data: Vec<u8> },
+	/// Dummy cell sent as cover traffic, dropped by the hop it is addressed to
+	Padding,
+	/// Make the last hop an introduction point for a hidden user, signed with the user's key over `issued` and the circuit
+	EstablishIntro { user: Vec<u8>, key: Vec<u8>, issued: u64, signature: Vec<u8> },
+	/// Ask an introduction point to pass a rendezvous request to the hidden user's node
+	Introduce { user: Vec<u8>, request: IntroduceRequest },
+	/// Rendezvous request passed from the introduction point to the hidden user's node
+	Introduced(IntroduceRequest),
+	/// Answer of the introduction point, with the reason if the request could not be passed on
+	IntroduceAck(Option<String>),
+	/// Make the last hop wait for the other side of a rendezvous with this cookie
+	EstablishRendezvous { cookie: [u8; COOKIE_SIZE] },
+	RendezvousEstablished,
+	/// Join the circuit waiting with this cookie, `reply` answers the handshake of the introduction
+	Rendezvous { cookie: [u8; COOKIE_SIZE], reply: HandshakeReply },
+	/// Rendezvous point joined both circuits, passed on from the `Rendezvous` of the hidden user's node
+	Joined(HandshakeReply),
+}
+
+/// Size of the random cookie matching both circuits of a rendezvous
+pub const COOKIE_SIZE: usize = 20;
+
+/// Where and how the node of a hidden user should meet the node that wants to connect to it
+#[derive(Debug, Clone)]
+pub struct IntroduceRequest {
+	/// `PeerId` of the rendezvous point
+	pub rendezvous: Vec<u8>,
+	pub cookie: [u8; COOKIE_SIZE],
+	/// End-to-end handshake, answered with a `HandshakeReply` signed by the hidden user
+	pub handshake: [u8; 32],
+}
+impl IntroduceRequest {
+	fn write(&self, out: &mut Vec<u8>) {
+		write_short(out, &self.rendezvous);
+		out.extend_from_slice(&self.cookie);
+		out.extend_from_slice(&self.handshake);
+	}
+	fn read(reader: &mut Reader) -> io::Result<IntroduceRequest> {
+		Ok(IntroduceRequest { rendezvous: reader.short()?, cookie: reader.array()?, handshake: reader.array()? })
+	}
+}
+impl RelayCommand {
+	pub fn encode(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		match self {
+			RelayCommand::Extend { next, handshake } => {
+				out.push(0);
+				write_short(&mut out, next);
+				out.extend_from_slice(handshake);
+			},
+			RelayCommand::Extended(reply) => {
+				out.push(1);
+				reply.write(&mut out);
+			},
+			RelayCommand::ExtendFailed(reason) => {
+				out.push(2);
+				out.extend_from_slice(reason.as_bytes());
+			},
+			RelayCommand::Data { message, index, count, data } => {
+				out.push(3);
+				out.extend_from_slice(&message.to_be_bytes());
+				out.extend_from_slice(&index.to_be_bytes());
+				out.extend_from_slice(&count.to_be_bytes());
+				out.extend_from_slice(data);
+			},
+			RelayCommand::Padding => out.push(4),
+			RelayCommand::EstablishIntro { user, key, issued, signature } => {
+				out.push(5);
+				write_short(&mut out, user);
+				write_short(&mut out, key);
+				out.extend_from_slice(&issued.to_be_bytes());
+				write_short(&mut out, signature);
+			},
+			RelayCommand::Introduce { user, request } => {
+				out.push(6);
+				write_short(&mut out, user);
+				request.write(&mut out);
+			},
+			RelayCommand::Introduced(request) => {
+				out.push(7);
+				request.write(&mut out);
+			},
+			RelayCommand::IntroduceAck(error) => {
+				out.push(8);
+				if let Some(error) = error {
+					out.extend_from_slice(error.as_bytes());
+				}
+			},
+			RelayCommand::EstablishRendezvous { cookie } => {
+				out.push(9);
+				out.extend_from_slice(cookie);
+			},
+			RelayCommand::RendezvousEstablished => out.push(10),
+			RelayCommand::Rendezvous { cookie, reply } => {
+				out.push(11);
+				out.extend_from_slice(cookie);
+				reply.write(&mut out);
+			},
+			RelayCommand::Joined(reply) => {
+				out.push(12);
+				reply.write(&mut out);
+			},
+		}
+		debug_assert!(out.len() <= MAX_COMMAND_SIZE, "Relay command is larger than MAX_COMMAND_SIZE");
+		out
+	}
+	pub fn decode(data: &[u8]) -> io::Result<RelayCommand> {
+		let mut reader = Reader(data);
+		Ok(match reader.u8()? {
+			0 => RelayCommand::Extend { next: reader.short()?, handshake: reader.array()? },
+			1 => RelayCommand::Extended(HandshakeReply::read(&mut reader)?),
+			2 => RelayCommand::ExtendFailed(String::from_utf8_lossy(&reader.rest()).into_owned()),
+			3 => RelayCommand::Data { message: reader.u32()?, index: reader.u32()?, count: reader.u32()?, data: reader.rest() },
+			4 => RelayCommand::Padding,
+			5 => RelayCommand::EstablishIntro { user: reader.short()?, key: reader.short()?, issued: reader.u64()?, signature: reader.short()? },
+			6 => RelayCommand::Introduce { user: reader.short()?, request: IntroduceRequest::read(&mut reader)? },
+			7 => RelayCommand::Introduced(IntroduceRequest::read(&mut reader)?),
+			8 => RelayCommand::IntroduceAck(match reader.rest() {
+				error if error.is_empty() => None,
+				error => Some(String::from_utf8_lossy(&error).into_owned()),
+			}),
+			9 => RelayCommand::EstablishRendezvous { cookie: reader.array()? },
+			10 => RelayCommand::RendezvousEstablished,
+			11 => RelayCommand::Rendezvous { cookie: reader.array()?, reply: HandshakeReply::read(&mut reader)? },
+			12 => RelayCommand::Joined(HandshakeReply::read(&mut reader)?),
+			_ => return Err(invalid("Unknown relay command")),
+		})
+	}
+}
+
+/// Receives a single cell from a neighbour
+#[derive(Debug, Clone, Default)]
+pub struct CellProtocol {
+	/// Also list `RELAY_PROTOCOL`, so identify tells peers this node relays circuits
+	pub relay: bool,
+}
+impl UpgradeInfo for CellProtocol {
+	type Info = &'static [u8];
+	type InfoIter = std::vec::IntoIter<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		let mut protocols = vec![PROTOCOL_NAME];
+		if self.relay { protocols.push(RELAY_PROTOCOL) }
+		protocols.into_iter()
+	}
+}
+impl<TSocket> InboundUpgrade<TSocket> for CellProtocol
+where
+	TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Output = Cell;
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+	fn upgrade_inbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
+		Box::pin(async move {
+			let data = upgrade::read_one(&mut socket, CELL_SIZE).await
+				.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+			Cell::decode(&data)
+		})
+	}
+}
+
+/// Sends a single cell to a neighbour
+#[derive(Debug, Clone)]
+pub struct CellUpgrade(pub Cell);
+impl UpgradeInfo for CellUpgrade {
+	type Info = &'static [u8];
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(PROTOCOL_NAME)
+	}
+}
+impl<TSocket> OutboundUpgrade<TSocket> for CellUpgrade
+where
+	TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Output = ();
+	type Error = io::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+	fn upgrade_outbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
+		Box::pin(async move {
+			upgrade::write_one(&mut socket, self.0.encode()).await
+		})
+	}
+}
+
+/// Event produced by the `OneShotHandler`
+#[derive(Debug)]
+pub enum InnerMessage {
+	Received(Cell),
+	Sent,
+}
+impl From<Cell> for InnerMessage {
+	fn from(cell: Cell) -> InnerMessage {
+		InnerMessage::Received(cell)
+	}
+}
+impl From<()> for InnerMessage {
+	fn from(_: ()) -> InnerMessage {
+		InnerMessage::Sent
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn reply() -> HandshakeReply {
+		HandshakeReply { handshake: [7; 32], key: vec![1; 36], signature: vec![2; 64] }
+	}
+
+	#[test]
+	fn cells_round_trip_at_cell_size() {
+		let cells = vec![
+			Cell::Create { circuit: 1, handshake: [3; 32] },
+			Cell::Created { circuit: 2, reply: reply() },
+			Cell::Relay { circuit: 3, forward: true, nonce: [4; NONCE_SIZE], payload: vec![5; RELAY_PAYLOAD_SIZE] },
+			Cell::Destroy { circuit: u64::MAX, forward: false },
+		];
+		for cell in cells {
+			let encoded = cell.encode();
+			assert_eq!(encoded.len(), CELL_SIZE);
+			let decoded = Cell::decode(&encoded).expect("Valid cell");
+			assert_eq!(format!("{:?}", decoded), format!("{:?}", cell));
+		}
+	}
+
+	#[test]
+	fn invalid_cells_are_rejected() {
+		let encoded = Cell::Destroy { circuit: 1, forward: true }.encode();
+		assert!(Cell::decode(&encoded[..CELL_SIZE - 1]).is_err());
+		assert!(Cell::decode(&[encoded.clone(), vec![0]].concat()).is_err());
+		let mut unknown = encoded;
+		unknown[0] = 0xff;
+		assert!(Cell::decode(&unknown).is_err());
+	}
+
+	#[test]
+	fn largest_fragment_fits_a_relay_cell() {
+		let command = RelayCommand::Data { message: u32::MAX, index: 1, count: 2, data: vec![9; DATA_FRAGMENT_SIZE] };
+		let encoded = command.encode();
+		assert_eq!(encoded.len(), MAX_COMMAND_SIZE);
+		match RelayCommand::decode(&encoded).expect("Valid command") {
+			RelayCommand::Data { message, index, count, data } => {
+				assert_eq!((message, index, count), (u32::MAX, 1, 2));
+				assert_eq!(data, vec![9; DATA_FRAGMENT_SIZE]);
+			},
+			command => panic!("Decoded {:?}", command),
+		}
+	}
+
+	#[test]
+	fn commands_round_trip() {
+		let request = IntroduceRequest { rendezvous: vec![6; 38], cookie: [8; COOKIE_SIZE], handshake: [9; 32] };
+		let commands = vec![
+			RelayCommand::Extend { next: vec![1; 38], handshake: [2; 32] },
+			RelayCommand::Extended(reply()),
+			RelayCommand::ExtendFailed("No route".to_owned()),
+			RelayCommand::Padding,
+			RelayCommand::EstablishIntro { user: vec![3; 38], key: vec![4; 36], issued: 1_600_000_000_000, signature: vec![5; 64] },
+			RelayCommand::Introduce { user: vec![3; 38], request: request.clone() },
+			RelayCommand::Introduced(request),
+			RelayCommand::IntroduceAck(None),
+			RelayCommand::IntroduceAck(Some("Unknown user".to_owned())),
+			RelayCommand::EstablishRendezvous { cookie: [8; COOKIE_SIZE] },
+			RelayCommand::RendezvousEstablished,
+			RelayCommand::Rendezvous { cookie: [8; COOKIE_SIZE], reply: reply() },
+			RelayCommand::Joined(reply()),
+		];
+		for command in commands {
+			let decoded = RelayCommand::decode(&command.encode()).expect("Valid command");
+			assert_eq!(format!("{:?}", decoded), format!("{:?}", command));
+		}
+	}
+
+	#[test]
+	fn truncated_commands_are_rejected() {
+		let encoded = RelayCommand::Extend { next: vec![1; 38], handshake: [2; 32] }.encode();
+		assert!(RelayCommand::decode(&encoded[..encoded.len() - 1]).is_err());
+		assert!(RelayCommand::decode(&[]).is_err());
+		assert!(RelayCommand::decode(&[0xff]).is_err());
+	}
+}
@@ -0,0 +1,390 @@
+// Per-hop keys of an onion circuit and the layers they put on relay cells
+
+use chacha20::{
+	ChaCha20, Key, Nonce,
+	stream_cipher::{NewStreamCipher, SyncStreamCipher},
+};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::cell::RELAY_PAYLOAD_SIZE;
+
+/// Bytes of a relay cell payload before the body: recognized marker, digest and body length
+pub const LAYER_HEADER: usize = 2 + 8 + 4;
+pub const NONCE_SIZE: usize = 12;
+
+/// Context signed by a relay together with both handshake keys
+pub const HANDSHAKE_CONTEXT: &[u8] = b"dither-onion-handshake-v1";
+/// Context signed by a hidden user answering a rendezvous
+pub const RENDEZVOUS_CONTEXT: &[u8] = b"dither-onion-rendezvous-v1";
+/// Context signed by a hidden user appointing an introduction point
+pub const INTRODUCTION_CONTEXT: &[u8] = b"dither-onion-introduction-v1";
+/// Bytes of an end-to-end message before its ciphertext: nonce and digest
+const MESSAGE_HEADER: usize = NONCE_SIZE + 8;
+
+/// Keys shared between the creator of a circuit and one of its hops
+/// Forward keys protect cells travelling away from the creator, backward keys cells travelling towards it
+#[derive(Clone)]
+pub struct HopKeys {
+	forward: [u8; 32],
+	backward: [u8; 32],
+	forward_digest: [u8; 32],
+	backward_digest: [u8; 32],
+	/// Replace the nonce of cells this hop passes on, so neighbours can not match a cell on both of its links
+	forward_nonce: [u8; 32],
+	backward_nonce: [u8; 32],
+	/// Known only to the creator and this hop, signed to tie a request to this circuit
+	binding: [u8; 32],
+}
+
+fn derive(label: &[u8], shared: &[u8], initiator: &[u8], relay: &[u8]) -> [u8; 32] {
+	let mut hasher = Sha256::new();
+	hasher.update(label);
+	hasher.update(shared);
+	hasher.update(initiator);
+	hasher.update(relay);
+	let mut key = [0; 32];
+	key.copy_from_slice(&hasher.finalize());
+	key
+}
+
+fn apply(key: &[u8; 32], nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+	ChaCha20::new(&Key::from(*key), &Nonce::from(*nonce)).apply_keystream(data);
+}
+
+fn digest(key: &[u8; 32], nonce: &[u8; NONCE_SIZE], body: &[u8]) -> [u8; 8] {
+	let mut hasher = Sha256::new();
+	hasher.update(key);
+	hasher.update(nonce);
+	hasher.update(&(body.len() as u32).to_be_bytes());
+	hasher.update(body);
+	let mut digest = [0; 8];
+	digest.copy_from_slice(&hasher.finalize()[..8]);
+	digest
+}
+
+/// Rounds of `permute`, four make the Feistel network a pseudorandom permutation
+const NONCE_ROUNDS: u8 = 4;
+
+fn round(key: &[u8; 32], round: u8, half: &[u8]) -> [u8; NONCE_SIZE / 2] {
+	let mut hasher = Sha256::new();
+	hasher.update(key);
+	hasher.update(&[round]);
+	hasher.update(half);
+	let mut output = [0; NONCE_SIZE / 2];
+	output.copy_from_slice(&hasher.finalize()[..NONCE_SIZE / 2]);
+	output
+}
+
+fn xor(half: &mut [u8], mask: &[u8]) {
+	half.iter_mut().zip(mask).for_each(|(byte, mask)| *byte ^= mask);
+}
+
+/// Encrypt a nonce under `key` with a Feistel network over both halves, undone by `unpermute`
+fn permute(key: &[u8; 32], nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] {
+	let mut nonce = *nonce;
+	for index in 0..NONCE_ROUNDS {
+		let (left, right) = nonce.split_at_mut(NONCE_SIZE / 2);
+		xor(left, &round(key, index, right));
+		nonce.rotate_left(NONCE_SIZE / 2);
+	}
+	nonce
+}
+
+fn unpermute(key: &[u8; 32], nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] {
+	let mut nonce = *nonce;
+	for index in (0..NONCE_ROUNDS).rev() {
+		nonce.rotate_right(NONCE_SIZE / 2);
+		let (left, right) = nonce.split_at_mut(NONCE_SIZE / 2);
+		xor(left, &round(key, index, right));
+	}
+	nonce
+}
+
+/// `body` with a header the receiving hop can recognize it by, padded with random bytes to `RELAY_PAYLOAD_SIZE`
+/// Random padding keeps hops after the receiving one from telling how much of the cell was used
+fn seal(digest_key: &[u8; 32], nonce: &[u8; NONCE_SIZE], body: &[u8]) -> Vec<u8> {
+	let mut payload = Vec::with_capacity(RELAY_PAYLOAD_SIZE);
+	payload.extend_from_slice(&[0, 0]);
+	payload.extend_from_slice(&digest(digest_key, nonce, body));
+	payload.extend_from_slice(&(body.len() as u32).to_be_bytes());
+	payload.extend_from_slice(body);
+	let used = payload.len();
+	payload.resize(RELAY_PAYLOAD_SIZE.max(used), 0);
+	OsRng.fill_bytes(&mut payload[used..]);
+	payload
+}
+
+/// Body of `payload` if it was sealed for the owner of `digest_key`
+fn open(digest_key: &[u8; 32], nonce: &[u8; NONCE_SIZE], payload: &[u8]) -> Option<Vec<u8>> {
+	if payload.len() < LAYER_HEADER || payload[..2] != [0, 0] { return None }
+	let mut len = [0; 4];
+	len.copy_from_slice(&payload[10..LAYER_HEADER]);
+	let body = payload[LAYER_HEADER..].get(..u32::from_be_bytes(len) as usize)?;
+	if payload[2..10] != digest(digest_key, nonce, body) { return None }
+	Some(body.to_vec())
+}
+
+impl HopKeys {
+	/// Keys from the Diffie-Hellman of both handshakes, `initiator` and `relay` are the public handshake keys
+	pub fn derive(shared: &[u8], initiator: &[u8], relay: &[u8]) -> HopKeys {
+		HopKeys {
+			forward: derive(b"forward", shared, initiator, relay),
+			backward: derive(b"backward", shared, initiator, relay),
+			forward_digest: derive(b"forward digest", shared, initiator, relay),
+			backward_digest: derive(b"backward digest", shared, initiator, relay),
+			forward_nonce: derive(b"forward nonce", shared, initiator, relay),
+			backward_nonce: derive(b"backward nonce", shared, initiator, relay),
+			binding: derive(b"binding", shared, initiator, relay),
+		}
+	}
+	/// Secret of the circuit up to this hop, a signature over it can not be replayed on another circuit
+	pub fn binding(&self) -> &[u8; 32] { &self.binding }
+	/// Nonce this hop puts on a cell it passes away from the creator, after using the received one for its layer
+	pub fn next_forward_nonce(&self, nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] { permute(&self.forward_nonce, nonce) }
+	/// Nonce this hop puts on a cell it passes towards the creator, its layer uses the new nonce
+	pub fn next_backward_nonce(&self, nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] { permute(&self.backward_nonce, nonce) }
+	/// Nonce the cell had before this hop passed it towards the creator, used by the creator for the next hop's layer
+	pub fn prev_backward_nonce(&self, nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] { unpermute(&self.backward_nonce, nonce) }
+	/// Add or remove this hop's layer on a cell travelling away from the creator
+	pub fn apply_forward(&self, nonce: &[u8; NONCE_SIZE], payload: &mut [u8]) { apply(&self.forward, nonce, payload) }
+	/// Add or remove this hop's layer on a cell travelling towards the creator
+	pub fn apply_backward(&self, nonce: &[u8; NONCE_SIZE], payload: &mut [u8]) { apply(&self.backward, nonce, payload) }
+	/// Payload for this hop, layers of the hops in front of it are added by the caller
+	pub fn seal_forward(&self, nonce: &[u8; NONCE_SIZE], body: &[u8]) -> Vec<u8> { seal(&self.forward_digest, nonce, body) }
+	/// Payload from this hop to the creator, with this hop's backward layer already applied
+	pub fn seal_backward(&self, nonce: &[u8; NONCE_SIZE], body: &[u8]) -> Vec<u8> {
+		let mut payload = seal(&self.backward_digest, nonce, body);
+		self.apply_backward(nonce, &mut payload);
+		payload
+	}
+	/// Body if a forward payload (with this hop's layer removed) is addressed to this hop
+	pub fn open_forward(&self, nonce: &[u8; NONCE_SIZE], payload: &[u8]) -> Option<Vec<u8>> { open(&self.forward_digest, nonce, payload) }
+	/// Body if a backward payload (with layers up to this hop removed) was sent by this hop
+	pub fn open_backward(&self, nonce: &[u8; NONCE_SIZE], payload: &[u8]) -> Option<Vec<u8>> { open(&self.backward_digest, nonce, payload) }
+	/// Encrypt a whole end-to-end message between both sides of a rendezvous
+	/// The side that sent the introduction uses the forward keys, the hidden user's node the backward keys
+	pub fn seal_message(&self, forward: bool, data: &[u8]) -> Vec<u8> {
+		let (key, digest_key) = if forward { (&self.forward, &self.forward_digest) } else { (&self.backward, &self.backward_digest) };
+		let nonce = random_nonce();
+		let mut message = Vec::with_capacity(MESSAGE_HEADER + data.len());
+		message.extend_from_slice(&nonce);
+		message.extend_from_slice(&[0; 8]);
+		message.extend_from_slice(data);
+		apply(key, &nonce, &mut message[MESSAGE_HEADER..]);
+		let digest = digest(digest_key, &nonce, &message[MESSAGE_HEADER..]);
+		message[NONCE_SIZE..MESSAGE_HEADER].copy_from_slice(&digest);
+		message
+	}
+	/// Decrypt a message sealed by the other side with `seal_message`, `None` if it was altered
+	pub fn open_message(&self, forward: bool, message: &[u8]) -> Option<Vec<u8>> {
+		let (key, digest_key) = if forward { (&self.forward, &self.forward_digest) } else { (&self.backward, &self.backward_digest) };
+		if message.len() < MESSAGE_HEADER { return None }
+		let mut nonce = [0; NONCE_SIZE];
+		nonce.copy_from_slice(&message[..NONCE_SIZE]);
+		let ciphertext = &message[MESSAGE_HEADER..];
+		if message[NONCE_SIZE..MESSAGE_HEADER] != digest(digest_key, &nonce, ciphertext) { return None }
+		let mut data = ciphertext.to_vec();
+		apply(key, &nonce, &mut data);
+		Some(data)
+	}
+}
+
+pub fn random_nonce() -> [u8; NONCE_SIZE] {
+	let mut nonce = [0; NONCE_SIZE];
+	OsRng.fill_bytes(&mut nonce);
+	nonce
+}
+
+/// Ephemeral key for one circuit handshake and the public half sent to the other side
+pub fn handshake() -> (EphemeralSecret, [u8; 32]) {
+	let secret = EphemeralSecret::new(OsRng);
+	let public = PublicKey::from(&secret);
+	(secret, *public.as_bytes())
+}
+
+/// Finish a handshake with the other side's public handshake key
+pub fn shared_secret(secret: EphemeralSecret, other: &[u8; 32]) -> [u8; 32] {
+	*secret.diffie_hellman(&PublicKey::from(*other)).as_bytes()
+}
+
+/// Bytes a relay signs to prove it answered a handshake
+pub fn handshake_transcript(initiator: &[u8; 32], relay: &[u8; 32]) -> Vec<u8> {
+	[HANDSHAKE_CONTEXT, &initiator[..], &relay[..]].concat()
+}
+
+/// Bytes a hidden user signs to prove its node answered a rendezvous
+pub fn rendezvous_transcript(cookie: &[u8], client: &[u8; 32], service: &[u8; 32]) -> Vec<u8> {
+	[RENDEZVOUS_CONTEXT, cookie, &client[..], &service[..]].concat()
+}
+
+/// Bytes a hidden user signs to appoint the last hop of the circuit with `binding` as introduction point
+/// `issued` is the time of the request in milliseconds since the Unix epoch, newer requests replace older ones
+pub fn introduction_transcript(user: &[u8], issued: u64, binding: &[u8; 32]) -> Vec<u8> {
+	[INTRODUCTION_CONTEXT, &issued.to_be_bytes(), &binding[..], user].concat()
+}
+
+pub fn random_cookie() -> [u8; 20] {
+	let mut cookie = [0; 20];
+	OsRng.fill_bytes(&mut cookie);
+	cookie
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::cell::Cell;
+
+	/// Keys of a hop as derived by the creator and by the hop itself
+	fn hop() -> (HopKeys, HopKeys) {
+		let (creator, creator_public) = handshake();
+		let (relay, relay_public) = handshake();
+		let creator_keys = HopKeys::derive(&shared_secret(creator, &relay_public), &creator_public, &relay_public);
+		let relay_keys = HopKeys::derive(&shared_secret(relay, &creator_public), &creator_public, &relay_public);
+		(creator_keys, relay_keys)
+	}
+	fn circuit(hops: usize) -> (Vec<HopKeys>, Vec<HopKeys>) {
+		(0..hops).map(|_| hop()).unzip()
+	}
+	/// Payload from the creator to hop `to`, as `Onion::send_forward` builds it
+	fn send_forward(creator: &[HopKeys], to: usize, nonce: &[u8; NONCE_SIZE], body: &[u8]) -> Vec<u8> {
+		let mut nonces = vec![*nonce];
+		for keys in &creator[..to] {
+			nonces.push(keys.next_forward_nonce(nonces.last().expect("Starts with a nonce")));
+		}
+		let mut payload = creator[to].seal_forward(&nonces[to], body);
+		for (keys, nonce) in creator[..=to].iter().zip(&nonces) {
+			keys.apply_forward(nonce, &mut payload);
+		}
+		payload
+	}
+	/// Hops remove their layer in order until one recognizes the payload, returns it and the body
+	fn receive_forward(relays: &[HopKeys], nonce: &[u8; NONCE_SIZE], mut payload: Vec<u8>) -> Option<(usize, Vec<u8>)> {
+		let mut nonce = *nonce;
+		for (index, keys) in relays.iter().enumerate() {
+			keys.apply_forward(&nonce, &mut payload);
+			if let Some(body) = keys.open_forward(&nonce, &payload) {
+				return Some((index, body));
+			}
+			nonce = keys.next_forward_nonce(&nonce);
+		}
+		None
+	}
+
+	#[test]
+	fn every_hop_gets_its_own_payload() {
+		let (creator, relays) = circuit(3);
+		for to in 0..3 {
+			let nonce = random_nonce();
+			let body = format!("for hop {}", to).into_bytes();
+			let payload = send_forward(&creator, to, &nonce, &body);
+			assert_eq!(payload.len(), RELAY_PAYLOAD_SIZE);
+			assert_eq!(receive_forward(&relays, &nonce, payload), Some((to, body)));
+		}
+	}
+
+	#[test]
+	fn creator_finds_hop_that_answered() {
+		let (creator, relays) = circuit(3);
+		let mut nonce = random_nonce();
+		let mut payload = relays[2].seal_backward(&nonce, b"from the last hop");
+		// Relays on the way back replace the nonce and add their layer
+		for keys in relays[..2].iter().rev() {
+			nonce = keys.next_backward_nonce(&nonce);
+			keys.apply_backward(&nonce, &mut payload);
+		}
+		let mut opened = None;
+		for (index, keys) in creator.iter().enumerate() {
+			keys.apply_backward(&nonce, &mut payload);
+			if let Some(body) = keys.open_backward(&nonce, &payload) {
+				opened = Some((index, body));
+				break;
+			}
+			nonce = keys.prev_backward_nonce(&nonce);
+		}
+		assert_eq!(opened, Some((2, b"from the last hop".to_vec())));
+	}
+
+	#[test]
+	fn altered_payload_is_not_recognized() {
+		let (creator, relays) = circuit(2);
+		let nonce = random_nonce();
+		let mut payload = send_forward(&creator, 1, &nonce, b"body");
+		payload[LAYER_HEADER] ^= 1;
+		assert_eq!(receive_forward(&relays, &nonce, payload), None);
+		// Same cell under another nonce
+		let payload = send_forward(&creator, 1, &nonce, b"body");
+		assert_eq!(receive_forward(&relays, &random_nonce(), payload), None);
+	}
+
+	#[test]
+	fn payload_for_other_hop_is_not_recognized() {
+		let (creator, relays) = circuit(2);
+		let nonce = random_nonce();
+		let mut payload = send_forward(&creator, 1, &nonce, b"body");
+		relays[0].apply_forward(&nonce, &mut payload);
+		assert!(relays[0].open_forward(&nonce, &payload).is_none());
+		// A hop of another circuit can not remove the layer
+		let (_, other) = hop();
+		other.apply_forward(&nonce, &mut payload);
+		assert!(other.open_forward(&nonce, &payload).is_none());
+	}
+
+	#[test]
+	fn nonces_are_permuted() {
+		let (keys, _) = hop();
+		let nonce = random_nonce();
+		let next = keys.next_backward_nonce(&nonce);
+		assert_ne!(next, nonce);
+		assert_eq!(keys.prev_backward_nonce(&next), nonce);
+		assert_ne!(keys.next_forward_nonce(&nonce), next);
+		assert_ne!(hop().0.next_backward_nonce(&nonce), next);
+	}
+
+	#[test]
+	fn cells_differ_between_adjacent_links() {
+		let (creator, relays) = circuit(3);
+		let mut nonce = random_nonce();
+		let mut payload = send_forward(&creator, 2, &nonce, b"body");
+		// Both links of the middle hop, numbered alike so only nonce and payload could tell the cells apart
+		let mut links = Vec::new();
+		for keys in &relays[..2] {
+			links.push(Cell::Relay { circuit: 1, forward: true, nonce, payload: payload.clone() }.encode());
+			keys.apply_forward(&nonce, &mut payload);
+			nonce = keys.next_forward_nonce(&nonce);
+		}
+		let (before, after) = (&links[0], &links[1]);
+		let nonces = 1 + 8 + 1..1 + 8 + 1 + NONCE_SIZE;
+		assert_ne!(before[nonces.clone()], after[nonces.clone()]);
+		assert_ne!(before[nonces.end..], after[nonces.end..]);
+		assert_eq!(receive_forward(&relays[2..], &nonce, payload), Some((0, b"body".to_vec())));
+	}
+
+	#[test]
+	fn introductions_are_bound_to_the_circuit() {
+		let ((creator, relay), (other, _)) = (hop(), hop());
+		assert_eq!(creator.binding(), relay.binding());
+		assert_ne!(creator.binding(), other.binding());
+		let transcript = introduction_transcript(b"user", 1, creator.binding());
+		assert_eq!(transcript, introduction_transcript(b"user", 1, relay.binding()));
+		assert_ne!(transcript, introduction_transcript(b"user", 1, other.binding()));
+		assert_ne!(transcript, introduction_transcript(b"user", 2, relay.binding()));
+	}
+
+	#[test]
+	fn messages_are_end_to_end() {
+		let (creator, relay) = hop();
+		let message = creator.seal_message(true, b"hello");
+		assert_eq!(relay.open_message(true, &message), Some(b"hello".to_vec()));
+		// Only the other side can open it, and only in its direction
+		assert_eq!(creator.open_message(false, &message), None);
+		assert_eq!(hop().1.open_message(true, &message), None);
+		let mut altered = message.clone();
+		*altered.last_mut().expect("Message has a body") ^= 1;
+		assert_eq!(relay.open_message(true, &altered), None);
+		assert_eq!(relay.open_message(true, &message[..MESSAGE_HEADER - 1]), None);
+		let reply = relay.seal_message(false, b"");
+		assert_eq!(creator.open_message(false, &reply), Some(Vec::new()));
+	}
+}
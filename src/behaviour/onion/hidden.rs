@@ -0,0 +1,278 @@
+// Hidden users, reachable through introduction points and a rendezvous point without publishing their nodes
+//
+// The node of a hidden user keeps circuits to a few introduction points. A node that wants to connect builds
+// a circuit to a rendezvous point of its choice, then sends the point and a random cookie to an introduction
+// point, which passes them on to the hidden user's node. That node builds its own circuit to the rendezvous
+// point, which joins both circuits. Messages across joined circuits are encrypted end-to-end with keys from a
+// handshake signed by the hidden user, so the rendezvous point only passes on ciphertext.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use libp2p::{PeerId, identity::PublicKey};
+use x25519_dalek::EphemeralSecret;
+
+use super::{
+	Onion, OnionEvent, OwnCircuit, CircuitId, CircuitLink,
+	cell::{RelayCommand, IntroduceRequest, HandshakeReply, COOKIE_SIZE, DATA_FRAGMENT_SIZE},
+	crypto::{self, HopKeys},
+};
+use crate::{NetworkKey, UserId};
+
+/// Id of an introduction received for a hidden user on this node, see `Onion::accept_introduction`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IntroductionId(u64);
+
+/// What a circuit built by this node is for
+pub(super) enum Purpose {
+	/// End-to-end data with the last hop
+	Data,
+	/// Makes the last hop an introduction point of a hidden user on this node
+	Introduction(UserId),
+	/// Rendezvous with a hidden user, data waits until its node joined
+	RendezvousClient {
+		cookie: [u8; COOKIE_SIZE],
+		user: UserId,
+		/// Key of the hidden user from its published definition, checked against the answer to `handshake`
+		key: PublicKey,
+		/// Where to send the introduction once the rendezvous point is ready
+		intro_path: Vec<PeerId>,
+		intro: Option<CircuitId>,
+		handshake: Option<([u8; 32], EphemeralSecret)>,
+	},
+	/// Sends an introduction for the rendezvous circuit `rendezvous`
+	Introduce { rendezvous: CircuitId, user: UserId, request: IntroduceRequest },
+	/// Joins a rendezvous on behalf of a hidden user on this node
+	RendezvousService { cookie: [u8; COOKIE_SIZE], reply: HandshakeReply },
+}
+
+/// Introduction received for a hidden user on this node, waiting for a circuit to its rendezvous point
+pub(super) struct Introduction {
+	user: UserId,
+	rendezvous: PeerId,
+	request: IntroduceRequest,
+}
+
+impl Onion {
+	/// Host a hidden user on this node, its key signs introduction points and rendezvous answers
+	pub fn add_hidden_user(&mut self, key: NetworkKey) {
+		self.hidden_users.insert(key.id().clone(), key);
+	}
+	/// Make the last node of `path` an introduction point of hidden `user`
+	pub fn establish_introduction(&mut self, user: UserId, path: Vec<PeerId>) -> CircuitId {
+		self.build_circuit_for(path, Purpose::Introduction(user))
+	}
+	/// Connect to hidden `user` through a rendezvous point at the end of `rendezvous_path`,
+	/// the introduction is sent over `intro_path` once the rendezvous point is ready
+	/// Payloads sent on the returned circuit are queued until the hidden user's node joined
+	pub fn connect_hidden(&mut self, user: UserId, key: PublicKey, rendezvous_path: Vec<PeerId>, intro_path: Vec<PeerId>) -> CircuitId {
+		let (secret, handshake) = crypto::handshake();
+		self.build_circuit_for(rendezvous_path, Purpose::RendezvousClient {
+			cookie: crypto::random_cookie(),
+			user,
+			key,
+			intro_path,
+			intro: None,
+			handshake: Some((handshake, secret)),
+		})
+	}
+	/// Answer an introduction with a circuit over `relays` to its rendezvous point
+	/// Data arriving on the circuit is an incoming connection to the hidden user
+	pub fn accept_introduction(&mut self, id: IntroductionId, relays: Vec<PeerId>) -> Option<CircuitId> {
+		let Introduction { user, rendezvous, request } = self.introductions.remove(&id)?;
+		let key = self.hidden_users.get(&user)?;
+		let (secret, handshake) = crypto::handshake();
+		let reply = HandshakeReply {
+			handshake,
+			key: key.public().into_protobuf_encoding(),
+			signature: key.sign(&crypto::rendezvous_transcript(&request.cookie, &request.handshake, &handshake)),
+		};
+		let keys = HopKeys::derive(&crypto::shared_secret(secret, &request.handshake), &request.handshake, &handshake);
+		let mut path = relays;
+		path.push(rendezvous);
+		let circuit = self.build_circuit_for(path, Purpose::RendezvousService { cookie: request.cookie, reply });
+		if let Some(CircuitLink::Own(link)) = self.ids.get(&circuit) {
+			if let Some(own) = self.circuits.get_mut(link) {
+				own.e2e = Some((keys, false));
+			}
+		}
+		Some(circuit)
+	}
+	/// Forget an introduction that will not be answered
+	pub fn decline_introduction(&mut self, id: IntroductionId) {
+		self.introductions.remove(&id);
+	}
+
+	/// Send the command a circuit was built for to its last hop
+	pub(super) fn purpose_built(&mut self, link: u64) {
+		let circuit = &self.circuits[&link];
+		let last = circuit.hops.len() - 1;
+		let command = match &circuit.purpose {
+			Purpose::Data => return,
+			Purpose::Introduction(user) => match self.hidden_users.get(user) {
+				Some(key) => {
+					let user = user.clone().into_bytes();
+					let issued = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+					let signature = key.sign(&crypto::introduction_transcript(&user, issued, circuit.hops[last].binding()));
+					RelayCommand::EstablishIntro { user, key: key.public().into_protobuf_encoding(), issued, signature }
+				},
+				None => return,
+			},
+			Purpose::RendezvousClient { cookie, .. } => RelayCommand::EstablishRendezvous { cookie: *cookie },
+			Purpose::Introduce { user, request, .. } => RelayCommand::Introduce { user: user.clone().into_bytes(), request: request.clone() },
+			Purpose::RendezvousService { cookie, reply } => RelayCommand::Rendezvous { cookie: *cookie, reply: reply.clone() },
+		};
+		self.send_forward(link, last, command);
+	}
+	/// Command from the last hop of a circuit built by this node
+	pub(super) fn hidden_backward(&mut self, link: u64, command: RelayCommand) {
+		let circuit = match self.circuits.get_mut(&link) {
+			Some(circuit) => circuit,
+			None => return,
+		};
+		let id = circuit.id;
+		match (command, &mut circuit.purpose) {
+			(RelayCommand::RendezvousEstablished, Purpose::RendezvousClient { cookie, user, intro_path, intro: None, handshake: Some((handshake, _)), .. }) => {
+				let request = IntroduceRequest {
+					rendezvous: circuit.path.last().expect("Circuits have hops").clone().into_bytes(),
+					cookie: *cookie,
+					handshake: *handshake,
+				};
+				let (user, intro_path) = (user.clone(), intro_path.clone());
+				let introduce = self.build_circuit_for(intro_path, Purpose::Introduce { rendezvous: id, user, request });
+				if let Some(Purpose::RendezvousClient { intro, .. }) = self.circuits.get_mut(&link).map(|circuit| &mut circuit.purpose) {
+					*intro = Some(introduce);
+				}
+			},
+			(RelayCommand::IntroduceAck(error), Purpose::Introduce { rendezvous, .. }) => {
+				let rendezvous = *rendezvous;
+				// The introduction circuit has done its job either way
+				self.close(id);
+				if let Some(error) = error {
+					self.abort(rendezvous, format!("Introduction failed: {}", error));
+				}
+			},
+			(RelayCommand::Joined(reply), Purpose::RendezvousClient { cookie, key, intro, handshake, .. }) => {
+				let (handshake, secret) = match handshake.take() {
+					Some(pending) => pending,
+					None => return,
+				};
+				if !key.verify(&crypto::rendezvous_transcript(cookie, &handshake, &reply.handshake), &reply.signature) {
+					return self.abort(id, "Rendezvous answered with an invalid signature".to_owned());
+				}
+				let keys = HopKeys::derive(&crypto::shared_secret(secret, &reply.handshake), &handshake, &reply.handshake);
+				circuit.e2e = Some((keys, true));
+				log::info!("Rendezvous on circuit {:?} joined", id);
+				if let Some(intro) = intro.take() {
+					self.close(intro);
+				}
+				self.flush(link);
+			},
+			(RelayCommand::Introduced(request), Purpose::Introduction(user)) => {
+				let rendezvous = match PeerId::from_bytes(request.rendezvous.clone()) {
+					Ok(rendezvous) => rendezvous,
+					Err(_) => { log::warn!("Introduction with an invalid rendezvous point for {:?}", user); return },
+				};
+				let user = user.clone();
+				let introduction = IntroductionId(self.new_number());
+				log::info!("Introduction for hidden user {:?} to meet at {:?}", user, rendezvous);
+				self.introductions.insert(introduction, Introduction { user: user.clone(), rendezvous: rendezvous.clone(), request });
+				self.generate(OnionEvent::Introduction(introduction, user, rendezvous));
+			},
+			(command, _) => log::warn!("Unexpected relay command on circuit {:?}: {:?}", id, command),
+		}
+	}
+	/// Command for this node as the last hop of a circuit built by another node
+	pub(super) fn hidden_forward(&mut self, link: &(PeerId, u64), command: RelayCommand) {
+		let relayed = match self.relayed.get_mut(link) {
+			Some(relayed) => relayed,
+			None => return,
+		};
+		let (id, binding) = (relayed.id, *relayed.keys.binding());
+		relayed.endpoint = true;
+		let hidden_role = matches!(command, RelayCommand::EstablishIntro { .. } | RelayCommand::EstablishRendezvous { .. });
+		if hidden_role && !self.policy.serves_hidden() {
+			log::info!("Refused to be an introduction or rendezvous point on circuit {:?}", id);
+			return self.close(id);
+		}
+		match command {
+			RelayCommand::EstablishIntro { user, key, issued, signature } => {
+				let valid = PublicKey::from_protobuf_encoding(&key).ok()
+					.filter(|key| key.clone().into_peer_id().into_bytes() == user)
+					.filter(|key| key.verify(&crypto::introduction_transcript(&user, issued, &binding), &signature));
+				match (valid, PeerId::from_bytes(user)) {
+					// A live circuit is only replaced by a request issued after its own
+					(Some(_), Ok(user)) => match self.introducing.get(&user) {
+						Some((current, since)) if *current != id && *since >= issued => {
+							log::warn!("Ignored introduction request for {:?} on circuit {:?}, older than the one on {:?}", user, id, current);
+						},
+						_ => {
+							log::info!("Introduction point for hidden user {:?}", user);
+							self.introducing.insert(user, (id, issued));
+						},
+					},
+					_ => log::warn!("Invalid introduction request on circuit {:?}", id),
+				}
+			},
+			RelayCommand::Introduce { user, request } => {
+				let intro = PeerId::from_bytes(user).ok()
+					.and_then(|user| self.introducing.get(&user))
+					.and_then(|(intro, _)| self.ids.get(intro).cloned());
+				match intro {
+					Some(CircuitLink::Relayed(prev, number)) => {
+						self.send_backward(&(prev, number), RelayCommand::Introduced(request));
+						self.send_backward(link, RelayCommand::IntroduceAck(None));
+					},
+					_ => self.send_backward(link, RelayCommand::IntroduceAck(Some("Not an introduction point for this user".to_owned()))),
+				}
+			},
+			RelayCommand::EstablishRendezvous { cookie } => {
+				self.rendezvous_points.insert(cookie, id);
+				self.send_backward(link, RelayCommand::RendezvousEstablished);
+			},
+			RelayCommand::Rendezvous { cookie, reply } => match self.rendezvous_points.remove(&cookie) {
+				Some(client) if client != id => match self.ids.get(&client).cloned() {
+					Some(CircuitLink::Relayed(prev, number)) => {
+						log::debug!("Joined circuits {:?} and {:?}", client, id);
+						self.joined.insert(client, id);
+						self.joined.insert(id, client);
+						self.send_backward(&(prev, number), RelayCommand::Joined(reply));
+					},
+					_ => self.close(id),
+				},
+				_ => self.close(id),
+			},
+			command => log::warn!("Unexpected relay command on circuit {:?}: {:?}", id, command),
+		}
+	}
+	/// Pass data on to the other circuit if `id` was joined at this rendezvous point, returns it otherwise
+	/// The budget is charged for every cell the message takes on the other circuit, not once per message
+	pub(super) fn pass_joined(&mut self, id: CircuitId, data: Vec<u8>) -> Option<Vec<u8>> {
+		let cells = ((data.len() + DATA_FRAGMENT_SIZE - 1) / DATA_FRAGMENT_SIZE).max(1);
+		match self.joined.get(&id).cloned() {
+			Some(other) if self.policy.spend_cells(cells) => { self.send(other, data); None },
+			Some(other) => { log::debug!("Dropped message for circuit {:?} over the relay bandwidth cap", other); None },
+			None => Some(data),
+		}
+	}
+	/// A relayed circuit is gone, so are the roles it had for hidden users
+	pub(super) fn hidden_relayed_removed(&mut self, id: CircuitId) {
+		self.introducing.retain(|_, (circuit, _)| *circuit != id);
+		self.rendezvous_points.retain(|_, circuit| *circuit != id);
+		if let Some(other) = self.joined.remove(&id) {
+			self.joined.remove(&other);
+			self.close(other);
+		}
+	}
+	/// A circuit built by this node is gone, `lost` if it was not closed on purpose
+	pub(super) fn hidden_own_removed(&mut self, circuit: &OwnCircuit, lost: bool) {
+		match &circuit.purpose {
+			Purpose::RendezvousClient { intro: Some(intro), .. } => self.close(*intro),
+			Purpose::Introduce { rendezvous, .. } if lost => self.abort(*rendezvous, "Lost the introduction circuit".to_owned()),
+			Purpose::Introduction(user) if lost => {
+				let point = circuit.path.last().expect("Circuits have hops").clone();
+				log::warn!("Lost introduction point {:?} of hidden user {:?}", point, user);
+				self.generate(OnionEvent::IntroductionLost(user.clone(), point));
+			},
+			_ => {},
+		}
+	}
+}
@@ -0,0 +1,999 @@
+// Onion routed circuits, each relay only learns the node before and after it
+//
+// A circuit is built hop by hop: the creator runs a handshake with the first relay, then asks the last
+// relay of the circuit so far to extend it to the next node. Every hop shares its own keys with the creator,
+// cells travelling along the circuit carry one layer per hop that is removed (or added on the way back)
+// by that hop, so only the creator and the last hop see the end-to-end payload.
+//
+// All cells have the same size, end-to-end messages are split into fragments that fit a cell. With cover
+// traffic enabled, idle circuits send dummy cells at a fixed rate, each addressed to a random hop that drops it.
+//
+// Hidden users are reached through circuits joined at a rendezvous point, see `hidden`. How much this node
+// does for circuits of other nodes is up to its `RelayPolicy`.
+
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	sync::{Arc, atomic::{AtomicUsize, Ordering}},
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
+use rand::Rng;
+use tokio::time::Interval;
+use libp2p::{
+	PeerId,
+	Multiaddr,
+	identity::{Keypair, PublicKey},
+	core::connection::ConnectionId,
+	swarm::{
+		NetworkBehaviour,
+		NetworkBehaviourAction,
+		NotifyHandler,
+		OneShotHandler,
+		OneShotHandlerConfig,
+		PollParameters,
+		SubstreamProtocol,
+		DialPeerCondition,
+	},
+};
+use x25519_dalek::EphemeralSecret;
+
+mod cell;
+use cell::{Cell, CellProtocol, CellUpgrade, HandshakeReply, InnerMessage, RelayCommand, DATA_FRAGMENT_SIZE};
+pub use cell::RELAY_PROTOCOL;
+mod crypto;
+use crypto::{HopKeys, NONCE_SIZE};
+mod hidden;
+use hidden::{Introduction, Purpose};
+pub use hidden::IntroductionId;
+mod policy;
+pub use policy::RelayPolicy;
+
+use crate::{NetworkKey, UserId, config::{CoverTrafficConfig, RelayConfig}};
+
+/// Largest end-to-end message, big enough for JSON encoded `SendData` payloads
+const MAX_MESSAGE_SIZE: usize = 5 * 1024 * 1024;
+const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE / DATA_FRAGMENT_SIZE + 1;
+/// Messages per circuit that may be partially received at the same time
+const MAX_PARTIAL_MESSAGES: usize = 16;
+/// Bytes partial messages of one circuit may reserve, room for two of the largest messages
+const MAX_CIRCUIT_PARTIAL_BYTES: usize = 2 * MAX_FRAGMENTS * DATA_FRAGMENT_SIZE;
+/// Bytes partial messages of all circuits together may reserve
+const MAX_PARTIAL_BYTES: usize = 8 * MAX_FRAGMENTS * DATA_FRAGMENT_SIZE;
+/// Partial messages that got no fragment for this long are dropped
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Id of a circuit on this node, either built by us or relayed for another node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CircuitId(u64);
+
+#[derive(Debug)]
+pub enum OnionEvent {
+	/// Every hop of a circuit built by this node answered, queued payloads were sent
+	Built(CircuitId),
+	/// Circuit built by this node could not be completed
+	Failed(CircuitId, String),
+	/// End-to-end payload arrived, either at the last hop or back at the creator
+	Received(CircuitId, Vec<u8>),
+	/// Circuit was torn down by one of its nodes or a link of it was lost
+	Closed(CircuitId),
+	/// Someone wants to connect to a hidden user on this node and waits at the rendezvous point,
+	/// answer with `Onion::accept_introduction` or `Onion::decline_introduction`
+	Introduction(IntroductionId, UserId, PeerId),
+	/// Circuit to an introduction point of a hidden user on this node was lost, with the user and the point
+	IntroductionLost(UserId, PeerId),
+}
+
+/// Fragments of an end-to-end message received so far
+struct Partial {
+	parts: Vec<Option<Vec<u8>>>,
+	missing: usize,
+	/// Room for every fragment, reserved when the first one arrived
+	reserved: usize,
+	/// When the last fragment arrived
+	updated: Instant,
+}
+
+/// Bytes reserved by partial messages of every circuit on this node
+#[derive(Debug, Clone, Default)]
+struct PartialBytes(Arc<AtomicUsize>);
+impl PartialBytes {
+	fn get(&self) -> usize { self.0.load(Ordering::Relaxed) }
+	fn add(&self, bytes: usize) { self.0.fetch_add(bytes, Ordering::Relaxed); }
+	fn sub(&self, bytes: usize) { self.0.fetch_sub(bytes, Ordering::Relaxed); }
+}
+
+/// Messages of a circuit being reassembled from `RelayCommand::Data` fragments, by message number
+/// A message that does not fit the circuit's or the node's budget evicts the circuit's least recently updated ones
+#[derive(Default)]
+struct Reassembly {
+	partials: HashMap<u32, Partial>,
+	/// Bytes reserved by `partials`, released to `total` when the circuit is dropped
+	reserved: usize,
+	total: PartialBytes,
+}
+impl Reassembly {
+	fn new(total: PartialBytes) -> Reassembly {
+		Reassembly { partials: HashMap::new(), reserved: 0, total }
+	}
+	/// Add a fragment, returns the message once all its fragments arrived
+	fn add(&mut self, message: u32, index: u32, count: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+		self.add_at(message, index, count, data, Instant::now())
+	}
+	fn add_at(&mut self, message: u32, index: u32, count: u32, data: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+		let count = count as usize;
+		if count == 0 || index as usize >= count || count > MAX_FRAGMENTS || data.len() > DATA_FRAGMENT_SIZE { return None }
+		self.expire(now);
+		if !self.partials.contains_key(&message) {
+			let reserved = count * DATA_FRAGMENT_SIZE;
+			if self.partials.len() >= MAX_PARTIAL_MESSAGES || !self.make_room(reserved) { return None }
+			self.reserved += reserved;
+			self.total.add(reserved);
+			self.partials.insert(message, Partial { parts: vec![None; count], missing: count, reserved, updated: now });
+		}
+		let partial = self.partials.get_mut(&message).expect("Inserted above");
+		if partial.parts.len() != count { return None }
+		partial.updated = now;
+		let part = &mut partial.parts[index as usize];
+		if part.is_none() { partial.missing -= 1 }
+		*part = Some(data);
+		if partial.missing > 0 { return None }
+		let partial = self.remove(message)?;
+		Some(partial.parts.into_iter().flatten().flatten().collect())
+	}
+	fn remove(&mut self, message: u32) -> Option<Partial> {
+		let partial = self.partials.remove(&message)?;
+		self.reserved -= partial.reserved;
+		self.total.sub(partial.reserved);
+		Some(partial)
+	}
+	/// Evict partial messages until `bytes` more fit both budgets, false if there is nothing left to evict
+	fn make_room(&mut self, bytes: usize) -> bool {
+		while self.reserved + bytes > MAX_CIRCUIT_PARTIAL_BYTES || self.total.get() + bytes > MAX_PARTIAL_BYTES {
+			let oldest = match self.partials.iter().min_by_key(|(_, partial)| partial.updated) {
+				Some((message, _)) => *message,
+				None => return false,
+			};
+			log::debug!("Evicted partial message {} to make room for another", oldest);
+			self.remove(oldest);
+		}
+		true
+	}
+	/// Drop messages that got no fragment for `PARTIAL_TIMEOUT`
+	fn expire(&mut self, now: Instant) {
+		let stale: Vec<u32> = self.partials.iter()
+			.filter(|(_, partial)| now.saturating_duration_since(partial.updated) >= PARTIAL_TIMEOUT)
+			.map(|(message, _)| *message)
+			.collect();
+		for message in stale {
+			log::debug!("Dropped partial message {}, no fragment arrived for {:?}", message, PARTIAL_TIMEOUT);
+			self.remove(message);
+		}
+	}
+}
+impl Drop for Reassembly {
+	fn drop(&mut self) {
+		self.total.sub(self.reserved);
+	}
+}
+
+/// Circuit built by this node
+struct OwnCircuit {
+	id: CircuitId,
+	/// Every hop in order, the last one is the destination
+	path: Vec<PeerId>,
+	/// Keys of the hops that completed their handshake
+	hops: Vec<HopKeys>,
+	/// Handshake sent to the next hop that has not answered yet
+	handshake: Option<([u8; 32], EphemeralSecret)>,
+	/// Payloads sent before the circuit was ready
+	queued: Vec<Vec<u8>>,
+	received: Reassembly,
+	/// Set when a cell was sent since the last cover traffic tick
+	active: bool,
+	purpose: Purpose,
+	/// Keys shared with a hidden user's node across a rendezvous point, and whether we are the client
+	e2e: Option<(HopKeys, bool)>,
+}
+impl OwnCircuit {
+	fn is_built(&self) -> bool { self.hops.len() == self.path.len() }
+	/// Built, and joined by the hidden user's node if it leads to a rendezvous
+	fn is_ready(&self) -> bool {
+		self.is_built() && (self.e2e.is_some() || !matches!(self.purpose, Purpose::RendezvousClient { .. }))
+	}
+	fn first(&self) -> &PeerId { &self.path[0] }
+}
+
+/// Circuit created by another node through this one
+struct RelayedCircuit {
+	id: CircuitId,
+	keys: HopKeys,
+	/// Successor and the number of our link to it, once the creator extended the circuit past us
+	next: Option<(PeerId, u64)>,
+	/// Set once the creator sent end-to-end data, making us the last hop
+	endpoint: bool,
+	received: Reassembly,
+	/// Set when a cell was sent back to the creator since the last cover traffic tick
+	active: bool,
+}
+
+/// Builds circuits for this node and relays cells of circuits built by others
+pub struct Onion {
+	key: Keypair,
+	policy: RelayPolicy,
+	next_id: u64,
+	next_message: u32,
+	/// Interval between dummy cells on idle circuits, `None` if cover traffic is disabled
+	cover: Option<Duration>,
+	/// Started on the first poll, timers need the runtime
+	cover_timer: Option<Interval>,
+	/// Drops stale partial messages of circuits that stopped sending, started on the first poll
+	expiry_timer: Option<Interval>,
+	/// Shared by the `Reassembly` of every circuit
+	partial_bytes: PartialBytes,
+	/// Circuits built by this node, by the number of their link to the first hop
+	circuits: HashMap<u64, OwnCircuit>,
+	/// Circuits relayed by this node, by predecessor and the number it chose for the link
+	relayed: HashMap<(PeerId, u64), RelayedCircuit>,
+	/// Links to successors of relayed circuits, mapped to the link to the predecessor
+	extended: HashMap<(PeerId, u64), (PeerId, u64)>,
+	/// Where to find circuits by their local id
+	ids: HashMap<CircuitId, CircuitLink>,
+	connected: HashSet<PeerId>,
+	/// Cells waiting for a connection to their neighbour
+	pending: HashMap<PeerId, Vec<Cell>>,
+	/// Keys of hidden users on this node
+	hidden_users: HashMap<UserId, NetworkKey>,
+	/// Introductions waiting for an answer
+	introductions: HashMap<IntroductionId, Introduction>,
+	/// Circuits of hidden users we are an introduction point for, with the time their request was issued
+	introducing: HashMap<UserId, (CircuitId, u64)>,
+	/// Circuits waiting at this rendezvous point, by cookie
+	rendezvous_points: HashMap<[u8; cell::COOKIE_SIZE], CircuitId>,
+	/// Circuits joined at this rendezvous point, in both directions
+	joined: HashMap<CircuitId, CircuitId>,
+	events: VecDeque<NetworkBehaviourAction<CellUpgrade, OnionEvent>>,
+}
+
+#[derive(Debug, Clone)]
+enum CircuitLink {
+	/// Number of the link to the first hop
+	Own(u64),
+	/// Predecessor and the number it chose
+	Relayed(PeerId, u64),
+}
+
+impl Onion {
+	/// `key` signs handshakes so circuit creators know they reached the right node
+	pub fn new(key: Keypair, cover_traffic: &CoverTrafficConfig, relay: &RelayConfig) -> Onion {
+		Onion {
+			key,
+			policy: RelayPolicy::new(relay),
+			next_id: 0,
+			next_message: 0,
+			cover: if cover_traffic.enabled { Some(cover_traffic.interval()) } else { None },
+			cover_timer: None,
+			expiry_timer: None,
+			partial_bytes: PartialBytes::default(),
+			circuits: HashMap::new(),
+			relayed: HashMap::new(),
+			extended: HashMap::new(),
+			ids: HashMap::new(),
+			connected: HashSet::new(),
+			pending: HashMap::new(),
+			hidden_users: HashMap::new(),
+			introductions: HashMap::new(),
+			introducing: HashMap::new(),
+			rendezvous_points: HashMap::new(),
+			joined: HashMap::new(),
+			events: VecDeque::new(),
+		}
+	}
+	/// Build a circuit through `path`, its last node is the destination
+	/// Payloads sent on the circuit are queued until `OnionEvent::Built`
+	pub fn build_circuit(&mut self, path: Vec<PeerId>) -> CircuitId {
+		self.build_circuit_for(path, Purpose::Data)
+	}
+	fn build_circuit_for(&mut self, path: Vec<PeerId>, purpose: Purpose) -> CircuitId {
+		let link = self.new_number();
+		let id = CircuitId(link);
+		if path.is_empty() {
+			self.generate(OnionEvent::Failed(id, "Circuit has no hops".to_owned()));
+			return id;
+		}
+		self.ids.insert(id, CircuitLink::Own(link));
+		let (secret, handshake) = crypto::handshake();
+		let first = path[0].clone();
+		self.circuits.insert(link, OwnCircuit {
+			id,
+			path,
+			hops: Vec::new(),
+			handshake: Some((handshake, secret)),
+			queued: Vec::new(),
+			received: Reassembly::new(self.partial_bytes.clone()),
+			active: false,
+			purpose,
+			e2e: None,
+		});
+		self.send_cell(first, Cell::Create { circuit: link, handshake });
+		id
+	}
+	/// Send an end-to-end payload, to the last hop if we built the circuit or back to its creator if we are the last hop
+	pub fn send(&mut self, id: CircuitId, data: Vec<u8>) {
+		if data.len() > MAX_MESSAGE_SIZE {
+			log::warn!("Dropped message of {} bytes on circuit {:?}, larger than {} bytes", data.len(), id, MAX_MESSAGE_SIZE);
+			return;
+		}
+		match self.ids.get(&id).cloned() {
+			Some(CircuitLink::Own(link)) => {
+				let circuit = self.circuits.get_mut(&link).expect("Own circuit ids point to circuits");
+				if circuit.is_ready() {
+					let destination = circuit.hops.len() - 1;
+					let data = match &circuit.e2e {
+						Some((keys, client)) => keys.seal_message(*client, &data),
+						None => data,
+					};
+					for fragment in self.fragments(data) {
+						self.send_forward(link, destination, fragment);
+					}
+				} else {
+					circuit.queued.push(data);
+				}
+			},
+			Some(CircuitLink::Relayed(prev, number)) => {
+				for fragment in self.fragments(data) {
+					self.send_backward(&(prev.clone(), number), fragment);
+				}
+			},
+			None => log::debug!("Sending on closed circuit {:?}", id),
+		}
+	}
+	/// Tear down a circuit on every node it passes through
+	pub fn close(&mut self, id: CircuitId) {
+		match self.ids.get(&id).cloned() {
+			Some(CircuitLink::Own(link)) => {
+				if let Some(circuit) = self.circuits.get(&link) {
+					let first = circuit.first().clone();
+					self.send_cell(first, Cell::Destroy { circuit: link, forward: true });
+				}
+				if let Some(circuit) = self.remove_own(link) {
+					self.hidden_own_removed(&circuit, false);
+				}
+			},
+			Some(CircuitLink::Relayed(prev, number)) => {
+				self.send_cell(prev.clone(), Cell::Destroy { circuit: number, forward: false });
+				self.remove_relayed(&(prev, number));
+			},
+			None => {},
+		}
+	}
+	/// Node the cells of a circuit built by another node come from, `None` for our own circuits
+	pub fn predecessor(&self, id: CircuitId) -> Option<&PeerId> {
+		match self.ids.get(&id) {
+			Some(CircuitLink::Relayed(prev, _)) => Some(prev),
+			_ => None,
+		}
+	}
+	/// Allow `peer` as destination of relayed circuits if only friends are, see `RelayDestinations::Friends`
+	pub fn add_friend(&mut self, peer: PeerId) {
+		self.policy.add_friend(peer);
+	}
+	/// Relay circuits to `peer`, which has a slot on this node because it is behind NAT
+	pub fn add_reserved(&mut self, peer: PeerId) {
+		self.policy.add_reserved(peer);
+	}
+	pub fn remove_reserved(&mut self, peer: &PeerId) {
+		self.policy.remove_reserved(peer);
+	}
+	/// Accept circuits from `relay`, which gave this node a slot because it is behind NAT
+	pub fn add_relay(&mut self, relay: PeerId) {
+		self.policy.add_relay(relay);
+	}
+	pub fn remove_relay(&mut self, relay: &PeerId) {
+		self.policy.remove_relay(relay);
+	}
+	/// Whether this node relays for anyone, only these nodes are intermediate hops of other nodes' paths
+	pub fn is_public(&self) -> bool {
+		self.policy.is_public()
+	}
+	/// Tear down every circuit, used when the node shuts down
+	pub fn close_all(&mut self) {
+		let ids: Vec<CircuitId> = self.ids.keys().cloned().collect();
+		for id in ids { self.close(id) }
+	}
+
+	/// Tear down a circuit and report it as failed or closed, unlike `close`
+	fn abort(&mut self, id: CircuitId, reason: String) {
+		match self.ids.get(&id).cloned() {
+			Some(CircuitLink::Own(link)) => {
+				if let Some(circuit) = self.circuits.get(&link) {
+					let first = circuit.first().clone();
+					self.send_cell(first, Cell::Destroy { circuit: link, forward: true });
+				}
+				self.own_lost(link, reason);
+			},
+			Some(CircuitLink::Relayed(..)) => {
+				self.close(id);
+				self.generate(OnionEvent::Closed(id));
+			},
+			None => {},
+		}
+	}
+	/// Send the payloads queued on an own circuit once it is ready
+	fn flush(&mut self, link: u64) {
+		let circuit = match self.circuits.get_mut(&link) {
+			Some(circuit) if circuit.is_ready() => circuit,
+			_ => return,
+		};
+		let id = circuit.id;
+		for data in std::mem::take(&mut circuit.queued) { self.send(id, data) }
+	}
+	/// Split an end-to-end message into commands that each fit a cell
+	fn fragments(&mut self, data: Vec<u8>) -> Vec<RelayCommand> {
+		self.next_message = self.next_message.wrapping_add(1);
+		let message = self.next_message;
+		let chunks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(DATA_FRAGMENT_SIZE).collect() };
+		let count = chunks.len() as u32;
+		chunks.into_iter().enumerate()
+			.map(|(index, chunk)| RelayCommand::Data { message, index: index as u32, count, data: chunk.to_vec() })
+			.collect()
+	}
+	/// Send a dummy cell on every circuit that was idle since the last tick
+	/// Circuits we built address it to a random hop, circuits we are the last hop of send it back to the creator
+	fn send_cover(&mut self) {
+		let mut idle = Vec::new();
+		for (link, circuit) in self.circuits.iter_mut() {
+			if circuit.is_built() && !circuit.active {
+				idle.push((*link, circuit.hops.len()));
+			}
+			circuit.active = false;
+		}
+		for (link, hops) in idle {
+			let hop = rand::thread_rng().gen_range(0, hops);
+			self.send_forward(link, hop, RelayCommand::Padding);
+		}
+		let mut idle = Vec::new();
+		for (link, circuit) in self.relayed.iter_mut() {
+			if circuit.endpoint && !circuit.active {
+				idle.push(link.clone());
+			}
+			circuit.active = false;
+		}
+		for link in idle {
+			self.send_backward(&link, RelayCommand::Padding);
+		}
+	}
+	fn expire_partials(&mut self) {
+		let now = Instant::now();
+		for circuit in self.circuits.values_mut() {
+			circuit.received.expire(now);
+		}
+		for circuit in self.relayed.values_mut() {
+			circuit.received.expire(now);
+		}
+	}
+	fn new_number(&mut self) -> u64 {
+		self.next_id += 1;
+		self.next_id
+	}
+	fn generate(&mut self, event: OnionEvent) {
+		self.events.push_back(NetworkBehaviourAction::GenerateEvent(event));
+	}
+	fn send_cell(&mut self, peer: PeerId, cell: Cell) {
+		if self.connected.contains(&peer) {
+			self.events.push_back(NetworkBehaviourAction::NotifyHandler { peer_id: peer, handler: NotifyHandler::Any, event: CellUpgrade(cell) });
+		} else {
+			// Dial once, cells are flushed in `inject_connected`
+			let pending = self.pending.entry(peer.clone()).or_default();
+			if pending.is_empty() {
+				self.events.push_back(NetworkBehaviourAction::DialPeer { peer_id: peer, condition: DialPeerCondition::Disconnected });
+			}
+			pending.push(cell);
+		}
+	}
+	/// Send `command` from the creator of circuit `link` to hop number `hop`, layered for every hop up to it
+	fn send_forward(&mut self, link: u64, hop: usize, command: RelayCommand) {
+		let circuit = &self.circuits[&link];
+		// Nonce each hop will see, as every hop before it replaces the nonce
+		let mut nonces = vec![crypto::random_nonce()];
+		for keys in &circuit.hops[..hop] {
+			nonces.push(keys.next_forward_nonce(nonces.last().expect("Starts with a nonce")));
+		}
+		let mut payload = circuit.hops[hop].seal_forward(&nonces[hop], &command.encode());
+		for (keys, nonce) in circuit.hops[..=hop].iter().zip(&nonces) {
+			keys.apply_forward(nonce, &mut payload);
+		}
+		let nonce = nonces[0];
+		let first = circuit.first().clone();
+		// Cover traffic only fills gaps, it does not count as activity
+		let padding = matches!(command, RelayCommand::Padding);
+		self.circuits.get_mut(&link).expect("Checked above").active |= !padding;
+		self.send_cell(first, Cell::Relay { circuit: link, forward: true, nonce, payload });
+	}
+	/// Send `command` from this relay back to the creator of the circuit
+	fn send_backward(&mut self, link: &(PeerId, u64), command: RelayCommand) {
+		let circuit = match self.relayed.get_mut(link) {
+			Some(circuit) => circuit,
+			None => return,
+		};
+		circuit.active |= !matches!(command, RelayCommand::Padding);
+		let nonce = crypto::random_nonce();
+		let payload = circuit.keys.seal_backward(&nonce, &command.encode());
+		self.send_cell(link.0.clone(), Cell::Relay { circuit: link.1, forward: false, nonce, payload });
+	}
+	fn remove_own(&mut self, link: u64) -> Option<OwnCircuit> {
+		let circuit = self.circuits.remove(&link)?;
+		self.ids.remove(&circuit.id);
+		Some(circuit)
+	}
+	fn remove_relayed(&mut self, link: &(PeerId, u64)) -> Option<RelayedCircuit> {
+		let circuit = self.relayed.remove(link)?;
+		self.ids.remove(&circuit.id);
+		if let Some(next) = &circuit.next {
+			self.extended.remove(next);
+		}
+		self.hidden_relayed_removed(circuit.id);
+		Some(circuit)
+	}
+	/// Fail or close a circuit built by this node
+	fn own_lost(&mut self, link: u64, reason: String) {
+		if let Some(circuit) = self.remove_own(link) {
+			self.hidden_own_removed(&circuit, true);
+			if circuit.is_built() {
+				self.generate(OnionEvent::Closed(circuit.id));
+			} else {
+				log::info!("Failed to build circuit {:?}: {}", circuit.id, reason);
+				self.generate(OnionEvent::Failed(circuit.id, reason));
+			}
+		}
+	}
+	/// Check a handshake reply came from `expected` and derive the keys shared with it
+	fn verify_reply(expected: &PeerId, handshake: [u8; 32], secret: EphemeralSecret, reply: &HandshakeReply) -> Result<HopKeys, String> {
+		let key = PublicKey::from_protobuf_encoding(&reply.key).map_err(|err| format!("Invalid relay key: {:?}", err))?;
+		if &key.clone().into_peer_id() != expected {
+			return Err(format!("Handshake answered by a different node than {:?}", expected));
+		}
+		if !key.verify(&crypto::handshake_transcript(&handshake, &reply.handshake), &reply.signature) {
+			return Err(format!("Invalid handshake signature from {:?}", expected));
+		}
+		let shared = crypto::shared_secret(secret, &reply.handshake);
+		Ok(HopKeys::derive(&shared, &handshake, &reply.handshake))
+	}
+	/// Next hop of an own circuit answered, extend to the one after it or report the circuit as built
+	fn hop_answered(&mut self, link: u64, reply: HandshakeReply) {
+		let circuit = match self.circuits.get_mut(&link) {
+			Some(circuit) => circuit,
+			None => return,
+		};
+		let (handshake, secret) = match circuit.handshake.take() {
+			Some(pending) => pending,
+			None => { log::warn!("Unexpected handshake reply on circuit {:?}", circuit.id); return },
+		};
+		let (id, hop) = (circuit.id, circuit.path[circuit.hops.len()].clone());
+		match Self::verify_reply(&hop, handshake, secret, &reply) {
+			Ok(keys) => circuit.hops.push(keys),
+			Err(reason) => {
+				self.close(id);
+				self.generate(OnionEvent::Failed(id, reason));
+				return;
+			},
+		}
+		if circuit.is_built() {
+			log::info!("Built circuit {:?} with {} hops", id, circuit.hops.len());
+			self.generate(OnionEvent::Built(id));
+			self.purpose_built(link);
+			self.flush(link);
+		} else {
+			let next = circuit.path[circuit.hops.len()].clone();
+			let last = circuit.hops.len() - 1;
+			let (secret, handshake) = crypto::handshake();
+			circuit.handshake = Some((handshake, secret));
+			self.send_forward(link, last, RelayCommand::Extend { next: next.into_bytes(), handshake });
+		}
+	}
+	fn receive_cell(&mut self, peer: PeerId, cell: Cell) {
+		match cell {
+			Cell::Create { circuit, handshake } => self.accept_circuit(peer, circuit, handshake),
+			Cell::Created { circuit, reply } => {
+				if self.circuits.get(&circuit).map_or(false, |own| own.first() == &peer && own.hops.is_empty()) {
+					self.hop_answered(circuit, reply);
+				} else if let Some(prev) = self.extended.get(&(peer.clone(), circuit)).cloned() {
+					self.send_backward(&prev, RelayCommand::Extended(reply));
+				} else {
+					log::debug!("{:?} answered unknown circuit {}", peer, circuit);
+				}
+			},
+			Cell::Relay { circuit, forward: true, nonce, payload } => self.relay_forward((peer, circuit), nonce, payload),
+			Cell::Relay { circuit, forward: false, nonce, mut payload } => {
+				if let Some(prev) = self.extended.get(&(peer.clone(), circuit)).cloned() {
+					// Replace the nonce, add our layer and pass towards the creator
+					if let Some(relayed) = self.relayed.get(&prev) {
+						let nonce = relayed.keys.next_backward_nonce(&nonce);
+						relayed.keys.apply_backward(&nonce, &mut payload);
+						if self.policy.spend_cell() {
+							self.send_cell(prev.0, Cell::Relay { circuit: prev.1, forward: false, nonce, payload });
+						} else {
+							log::debug!("Dropped cell on circuit {:?} over the relay bandwidth cap", relayed.id);
+						}
+					}
+				} else if self.circuits.get(&circuit).map_or(false, |own| own.first() == &peer) {
+					self.receive_backward(circuit, nonce, payload);
+				} else {
+					log::debug!("{:?} sent a cell on unknown circuit {}", peer, circuit);
+				}
+			},
+			Cell::Destroy { circuit, forward: true } => {
+				if let Some(relayed) = self.remove_relayed(&(peer, circuit)) {
+					if let Some((next, number)) = relayed.next {
+						self.send_cell(next, Cell::Destroy { circuit: number, forward: true });
+					}
+					self.generate(OnionEvent::Closed(relayed.id));
+				}
+			},
+			Cell::Destroy { circuit, forward: false } => {
+				if let Some(prev) = self.extended.get(&(peer.clone(), circuit)).cloned() {
+					if let Some(relayed) = self.remove_relayed(&prev) {
+						self.send_cell(prev.0, Cell::Destroy { circuit: prev.1, forward: false });
+						self.generate(OnionEvent::Closed(relayed.id));
+					}
+				} else if self.circuits.get(&circuit).map_or(false, |own| own.first() == &peer) {
+					self.own_lost(circuit, "Circuit destroyed by a relay".to_owned());
+				}
+			},
+		}
+	}
+	/// Another node asked us to be the next hop of its circuit
+	fn accept_circuit(&mut self, peer: PeerId, number: u64, handshake: [u8; 32]) {
+		if self.relayed.contains_key(&(peer.clone(), number)) {
+			log::warn!("{:?} reused circuit number {}", peer, number);
+			return;
+		}
+		if !self.policy.accepts_circuit(&peer, self.relayed.len()) {
+			log::info!("Refused circuit from {:?}, {} circuits are open", peer, self.relayed.len());
+			self.send_cell(peer, Cell::Destroy { circuit: number, forward: false });
+			return;
+		}
+		let (secret, reply_handshake) = crypto::handshake();
+		let signature = match self.key.sign(&crypto::handshake_transcript(&handshake, &reply_handshake)) {
+			Ok(signature) => signature,
+			Err(err) => { log::error!("Failed to sign circuit handshake: {:?}", err); return },
+		};
+		let shared = crypto::shared_secret(secret, &handshake);
+		let id = CircuitId(self.new_number());
+		self.ids.insert(id, CircuitLink::Relayed(peer.clone(), number));
+		self.relayed.insert((peer.clone(), number), RelayedCircuit {
+			id,
+			keys: HopKeys::derive(&shared, &handshake, &reply_handshake),
+			next: None,
+			endpoint: false,
+			received: Reassembly::new(self.partial_bytes.clone()),
+			active: false,
+		});
+		let reply = HandshakeReply { handshake: reply_handshake, key: self.key.public().into_protobuf_encoding(), signature };
+		self.send_cell(peer, Cell::Created { circuit: number, reply });
+	}
+	/// Remove our layer from a cell travelling away from the creator, handle it if it is addressed to us
+	fn relay_forward(&mut self, link: (PeerId, u64), nonce: [u8; NONCE_SIZE], mut payload: Vec<u8>) {
+		let relayed = match self.relayed.get_mut(&link) {
+			Some(relayed) => relayed,
+			None => { log::debug!("{:?} sent a cell on unknown circuit {}", link.0, link.1); return },
+		};
+		relayed.keys.apply_forward(&nonce, &mut payload);
+		let body = match relayed.keys.open_forward(&nonce, &payload) {
+			Some(body) => body,
+			None => {
+				match relayed.next.clone() {
+					Some((next, number)) if self.policy.spend_cell() => {
+						let nonce = relayed.keys.next_forward_nonce(&nonce);
+						self.send_cell(next, Cell::Relay { circuit: number, forward: true, nonce, payload })
+					},
+					Some(_) => log::debug!("Dropped cell on circuit {:?} over the relay bandwidth cap", relayed.id),
+					None => log::debug!("Dropped unrecognized cell at the end of circuit {:?}", relayed.id),
+				}
+				return;
+			},
+		};
+		match RelayCommand::decode(&body) {
+			Ok(RelayCommand::Extend { next, handshake }) => {
+				let next = match PeerId::from_bytes(next) {
+					Ok(next) if relayed.next.is_none() && !relayed.endpoint => next,
+					_ => return self.send_backward(&link, RelayCommand::ExtendFailed("Invalid extend".to_owned())),
+				};
+				if !self.policy.may_extend(&next) {
+					return self.send_backward(&link, RelayCommand::ExtendFailed(format!("Relaying to {:?} is not allowed", next)));
+				}
+				let number = self.new_number();
+				self.relayed.get_mut(&link).expect("Checked above").next = Some((next.clone(), number));
+				self.extended.insert((next.clone(), number), link);
+				self.send_cell(next, Cell::Create { circuit: number, handshake });
+			},
+			Ok(RelayCommand::Data { message, index, count, data }) if relayed.next.is_none() => {
+				relayed.endpoint = true;
+				let id = relayed.id;
+				if let Some(data) = relayed.received.add(message, index, count, data) {
+					if let Some(data) = self.pass_joined(id, data) {
+						self.generate(OnionEvent::Received(id, data));
+					}
+				}
+			},
+			// Cover traffic addressed to us, nothing to do
+			Ok(RelayCommand::Padding) => {},
+			Ok(command @ RelayCommand::EstablishIntro { .. })
+			| Ok(command @ RelayCommand::Introduce { .. })
+			| Ok(command @ RelayCommand::EstablishRendezvous { .. })
+			| Ok(command @ RelayCommand::Rendezvous { .. }) if relayed.next.is_none() => self.hidden_forward(&link, command),
+			Ok(command) => log::warn!("Unexpected relay command on circuit {:?}: {:?}", relayed.id, command),
+			Err(err) => log::warn!("Invalid relay command on circuit {:?}: {:?}", relayed.id, err),
+		}
+	}
+	/// Remove the layers of a cell travelling back to us and handle it with the hop that sent it
+	fn receive_backward(&mut self, link: u64, mut nonce: [u8; NONCE_SIZE], mut payload: Vec<u8>) {
+		let circuit = self.circuits.get_mut(&link).expect("Checked by caller");
+		let mut found = None;
+		for (hop, keys) in circuit.hops.iter().enumerate() {
+			keys.apply_backward(&nonce, &mut payload);
+			if let Some(body) = keys.open_backward(&nonce, &payload) {
+				found = Some((hop, body));
+				break;
+			}
+			nonce = keys.prev_backward_nonce(&nonce);
+		}
+		let (hop, body) = match found {
+			Some(found) => found,
+			None => { log::debug!("Dropped unrecognized cell on circuit {:?}", circuit.id); return },
+		};
+		let last = hop + 1 == circuit.hops.len();
+		match RelayCommand::decode(&body) {
+			Ok(RelayCommand::Extended(reply)) if last => self.hop_answered(link, reply),
+			Ok(RelayCommand::ExtendFailed(reason)) if last => {
+				let first = circuit.first().clone();
+				self.send_cell(first, Cell::Destroy { circuit: link, forward: true });
+				self.own_lost(link, reason);
+			},
+			Ok(RelayCommand::Data { message, index, count, data }) if last && circuit.is_ready() => {
+				let id = circuit.id;
+				let data = match circuit.received.add(message, index, count, data) {
+					Some(data) => data,
+					None => return,
+				};
+				let data = match &circuit.e2e {
+					Some((keys, client)) => match keys.open_message(!*client, &data) {
+						Some(data) => data,
+						None => { log::warn!("Dropped message with an invalid end-to-end digest on circuit {:?}", id); return },
+					},
+					None => data,
+				};
+				self.generate(OnionEvent::Received(id, data));
+			},
+			Ok(RelayCommand::Padding) => {},
+			Ok(command @ RelayCommand::RendezvousEstablished)
+			| Ok(command @ RelayCommand::IntroduceAck(_))
+			| Ok(command @ RelayCommand::Joined(_))
+			| Ok(command @ RelayCommand::Introduced(_)) if last && circuit.is_built() => self.hidden_backward(link, command),
+			Ok(command) => log::warn!("Unexpected relay command from hop {} of circuit {:?}: {:?}", hop, circuit.id, command),
+			Err(err) => log::warn!("Invalid relay command on circuit {:?}: {:?}", circuit.id, err),
+		}
+	}
+	/// Every circuit using a link to `peer` is gone
+	fn links_lost(&mut self, peer: &PeerId) {
+		let own: Vec<u64> = self.circuits.iter()
+			.filter(|(_, circuit)| circuit.first() == peer)
+			.map(|(link, _)| *link).collect();
+		for link in own { self.own_lost(link, format!("Lost connection to {:?}", peer)) }
+
+		// Relayed circuits coming from `peer` are torn down towards the destination
+		let from: Vec<(PeerId, u64)> = self.relayed.keys().filter(|(prev, _)| prev == peer).cloned().collect();
+		for link in from {
+			if let Some(relayed) = self.remove_relayed(&link) {
+				if let Some((next, number)) = relayed.next {
+					self.send_cell(next, Cell::Destroy { circuit: number, forward: true });
+				}
+				self.generate(OnionEvent::Closed(relayed.id));
+			}
+		}
+		// And those going to `peer` towards their creator
+		let to: Vec<(PeerId, u64)> = self.extended.iter().filter(|((next, _), _)| next == peer).map(|(_, prev)| prev.clone()).collect();
+		for prev in to {
+			if let Some(relayed) = self.remove_relayed(&prev) {
+				self.send_cell(prev.0, Cell::Destroy { circuit: prev.1, forward: false });
+				self.generate(OnionEvent::Closed(relayed.id));
+			}
+		}
+	}
+}
+
+impl NetworkBehaviour for Onion {
+	type ProtocolsHandler = OneShotHandler<CellProtocol, CellUpgrade, InnerMessage>;
+	type OutEvent = OnionEvent;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		OneShotHandler::new(SubstreamProtocol::new(CellProtocol { relay: self.policy.is_public() }), OneShotHandlerConfig::default())
+	}
+
+	fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+		Vec::new()
+	}
+
+	fn inject_connected(&mut self, peer: &PeerId) {
+		self.connected.insert(peer.clone());
+		for cell in self.pending.remove(peer).unwrap_or_default() {
+			self.events.push_back(NetworkBehaviourAction::NotifyHandler { peer_id: peer.clone(), handler: NotifyHandler::Any, event: CellUpgrade(cell) });
+		}
+	}
+
+	fn inject_disconnected(&mut self, peer: &PeerId) {
+		self.connected.remove(peer);
+		self.links_lost(peer);
+	}
+
+	fn inject_dial_failure(&mut self, peer: &PeerId) {
+		self.pending.remove(peer);
+		// A relay that could not reach the next hop tells the creator instead of silently dropping the circuit
+		let to: Vec<(PeerId, u64)> = self.extended.iter().filter(|((next, _), _)| next == peer).map(|(_, prev)| prev.clone()).collect();
+		for prev in to {
+			self.send_backward(&prev, RelayCommand::ExtendFailed(format!("Could not reach {:?}", peer)));
+			if let Some(relayed) = self.relayed.get_mut(&prev) {
+				if let Some(next) = relayed.next.take() {
+					self.extended.remove(&next);
+				}
+			}
+		}
+		self.links_lost(peer);
+	}
+
+	fn inject_event(&mut self, peer: PeerId, _: ConnectionId, event: InnerMessage) {
+		if let InnerMessage::Received(cell) = event {
+			self.receive_cell(peer, cell);
+		}
+	}
+
+	fn poll(&mut self, cx: &mut Context<'_>, _: &mut impl PollParameters) -> Poll<NetworkBehaviourAction<CellUpgrade, OnionEvent>> {
+		if let (Some(interval), true) = (self.cover, self.cover_timer.is_none()) {
+			self.cover_timer = Some(tokio::time::interval(interval));
+		}
+		while self.cover_timer.as_mut().map_or(false, |timer| timer.poll_tick(cx).is_ready()) {
+			self.send_cover();
+		}
+		if self.expiry_timer.is_none() {
+			self.expiry_timer = Some(tokio::time::interval(PARTIAL_TIMEOUT));
+		}
+		while self.expiry_timer.as_mut().map_or(false, |timer| timer.poll_tick(cx).is_ready()) {
+			self.expire_partials();
+		}
+		if let Some(event) = self.events.pop_front() {
+			return Poll::Ready(event);
+		}
+		Poll::Pending
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fragments(data: &[u8]) -> Vec<(u32, u32, Vec<u8>)> {
+		let chunks: Vec<&[u8]> = data.chunks(DATA_FRAGMENT_SIZE).collect();
+		chunks.iter().enumerate().map(|(index, chunk)| (index as u32, chunks.len() as u32, chunk.to_vec())).collect()
+	}
+
+	#[test]
+	fn fragments_are_reassembled_in_any_order() {
+		let data: Vec<u8> = (0..DATA_FRAGMENT_SIZE * 3 + 10).map(|i| i as u8).collect();
+		let mut fragments = fragments(&data);
+		fragments.reverse();
+		fragments.swap(1, 2);
+		let mut received = Reassembly::default();
+		let last = fragments.pop().expect("Message has fragments");
+		for (index, count, chunk) in fragments {
+			assert_eq!(received.add(1, index, count, chunk), None);
+		}
+		assert_eq!(received.add(1, last.0, last.1, last.2), Some(data));
+		assert!(received.partials.is_empty());
+	}
+
+	#[test]
+	fn duplicate_fragments_are_not_counted_twice() {
+		let data = vec![1; DATA_FRAGMENT_SIZE + 1];
+		let fragments = fragments(&data);
+		let mut received = Reassembly::default();
+		let (index, count, chunk) = fragments[0].clone();
+		assert_eq!(received.add(1, index, count, chunk.clone()), None);
+		assert_eq!(received.add(1, index, count, chunk), None);
+		let (index, count, chunk) = fragments[1].clone();
+		assert_eq!(received.add(1, index, count, chunk), Some(data));
+	}
+
+	#[test]
+	fn interleaved_messages_are_kept_apart() {
+		let (first, second) = (vec![1; DATA_FRAGMENT_SIZE * 2], vec![2; DATA_FRAGMENT_SIZE * 2]);
+		let mut received = Reassembly::default();
+		for ((index, count, a), (_, _, b)) in fragments(&first).into_iter().zip(fragments(&second)) {
+			let done = (received.add(1, index, count, a), received.add(2, index, count, b));
+			if index + 1 == count {
+				assert_eq!(done, (Some(first.clone()), Some(second.clone())));
+			} else {
+				assert_eq!(done, (None, None));
+			}
+		}
+	}
+
+	#[test]
+	fn invalid_fragments_are_rejected() {
+		let mut received = Reassembly::default();
+		// Larger than MAX_MESSAGE_SIZE
+		assert_eq!(received.add(1, 0, MAX_FRAGMENTS as u32 + 1, vec![1]), None);
+		assert!(received.partials.is_empty());
+		assert_eq!(received.add(1, 0, 0, vec![1]), None);
+		assert_eq!(received.add(1, 2, 2, vec![1]), None);
+		assert!(received.partials.is_empty());
+		// Fragments of one message must agree on its size
+		assert_eq!(received.add(1, 0, 2, vec![1]), None);
+		assert_eq!(received.add(1, 1, 3, vec![2]), None);
+		assert_eq!(received.add(1, 1, 2, vec![2]), Some(vec![1, 2]));
+	}
+
+	#[test]
+	fn partial_messages_are_limited() {
+		let mut received = Reassembly::default();
+		for message in 0..MAX_PARTIAL_MESSAGES as u32 {
+			assert_eq!(received.add(message, 0, 2, vec![1]), None);
+		}
+		let extra = MAX_PARTIAL_MESSAGES as u32;
+		assert_eq!(received.add(extra, 0, 2, vec![1]), None);
+		assert_eq!(received.add(extra, 1, 2, vec![2]), None);
+		// Completing one makes room again
+		assert_eq!(received.add(0, 1, 2, vec![2]), Some(vec![1, 2]));
+		assert_eq!(received.add(extra, 0, 1, vec![3]), Some(vec![3]));
+	}
+	#[test]
+	fn stale_partials_expire() {
+		let total = PartialBytes::default();
+		let mut received = Reassembly::new(total.clone());
+		let start = Instant::now();
+		assert_eq!(received.add_at(1, 0, 2, vec![1], start), None);
+		assert_eq!(received.add_at(2, 0, 2, vec![2], start + PARTIAL_TIMEOUT / 2), None);
+		received.expire(start + PARTIAL_TIMEOUT);
+		assert_eq!(received.partials.len(), 1);
+		assert_eq!(total.get(), 2 * DATA_FRAGMENT_SIZE);
+		// Message 1 starts over without its first fragment
+		assert_eq!(received.add_at(1, 1, 2, vec![1], start + PARTIAL_TIMEOUT), None);
+		assert_eq!(received.add_at(2, 1, 2, vec![3], start + PARTIAL_TIMEOUT), Some(vec![2, 3]));
+		drop(received);
+		assert_eq!(total.get(), 0);
+	}
+
+	#[test]
+	fn circuit_budget_evicts_least_recently_updated() {
+		let mut received = Reassembly::default();
+		let (count, start) = (MAX_FRAGMENTS as u32, Instant::now());
+		let at = |seconds| start + Duration::from_secs(seconds);
+		// Two of the largest messages fill the circuit's budget
+		assert_eq!(received.add_at(1, 0, count, vec![1], at(0)), None);
+		assert_eq!(received.add_at(2, 0, count, vec![2], at(1)), None);
+		assert_eq!(received.add_at(1, 1, count, vec![1], at(2)), None);
+		assert_eq!(received.add_at(3, 0, count, vec![3], at(3)), None);
+		assert!(received.partials.contains_key(&1) && received.partials.contains_key(&3));
+		assert!(!received.partials.contains_key(&2));
+		assert_eq!(received.reserved, MAX_CIRCUIT_PARTIAL_BYTES);
+	}
+
+	#[test]
+	fn global_budget_is_shared_between_circuits() {
+		let total = PartialBytes::default();
+		let mut circuits: Vec<Reassembly> = (0..5).map(|_| Reassembly::new(total.clone())).collect();
+		let count = MAX_FRAGMENTS as u32;
+		for received in &mut circuits[..4] {
+			assert_eq!(received.add(1, 0, count, vec![1]), None);
+			assert_eq!(received.add(2, 0, count, vec![2]), None);
+		}
+		assert_eq!(total.get(), MAX_PARTIAL_BYTES);
+		// Nothing of its own to evict, other circuits keep their messages
+		assert_eq!(circuits[4].add(1, 0, 2, vec![1]), None);
+		assert!(circuits[4].partials.is_empty());
+		assert_eq!(total.get(), MAX_PARTIAL_BYTES);
+		// A closed circuit releases its share
+		circuits.remove(0);
+		assert_eq!(circuits[3].add(1, 0, 2, vec![1]), None);
+		assert_eq!(circuits[3].add(1, 1, 2, vec![2]), Some(vec![1, 2]));
+		assert_eq!(total.get(), 6 * MAX_FRAGMENTS * DATA_FRAGMENT_SIZE);
+	}
+}
@@ -0,0 +1,137 @@
+// What this node does for circuits built by other nodes, set by `RelayConfig`
+
+use std::{
+	collections::HashSet,
+	time::Instant,
+};
+use libp2p::PeerId;
+
+use super::cell::CELL_SIZE;
+use crate::config::{RelayConfig, RelayDestinations};
+
+/// Relayed bytes that may be sent right now, topped up at a fixed rate
+struct Budget {
+	/// Bytes per second
+	rate: f64,
+	available: f64,
+	updated: Instant,
+}
+
+/// Which circuits this node relays and how much traffic it passes on for them
+pub struct RelayPolicy {
+	enabled: bool,
+	max_circuits: usize,
+	destinations: RelayDestinations,
+	friends: HashSet<PeerId>,
+	/// Nodes behind NAT with a slot on this node, always reachable through it
+	reserved: HashSet<PeerId>,
+	/// Nodes this node has a slot on, circuits they pass on end here
+	relays: HashSet<PeerId>,
+	/// Unlimited if `None`
+	budget: Option<Budget>,
+}
+
+impl RelayPolicy {
+	pub fn new(config: &RelayConfig) -> RelayPolicy {
+		let friends = config.friends.iter().filter_map(|friend| match friend.parse() {
+			Ok(peer) => Some(peer),
+			Err(_) => { log::warn!("Ignored invalid relay friend {:?}", friend); None },
+		}).collect();
+		RelayPolicy {
+			enabled: config.enabled,
+			max_circuits: config.max_circuits,
+			destinations: config.destinations,
+			friends,
+			reserved: HashSet::new(),
+			relays: HashSet::new(),
+			budget: config.bandwidth_kbits_per_sec.map(|kbits| {
+				let rate = kbits as f64 * 1000.0 / 8.0;
+				Budget { rate, available: rate, updated: Instant::now() }
+			}),
+		}
+	}
+	/// Allow `peer` as a destination with `RelayDestinations::Friends`
+	pub fn add_friend(&mut self, peer: PeerId) {
+		self.friends.insert(peer);
+	}
+	/// Relay circuits to `peer` even if this node does not relay otherwise, see `NatRelayConfig`
+	pub fn add_reserved(&mut self, peer: PeerId) {
+		self.reserved.insert(peer);
+	}
+	pub fn remove_reserved(&mut self, peer: &PeerId) {
+		self.reserved.remove(peer);
+	}
+	/// Take circuits from `relay` even if this node does not relay, this node is behind NAT and has a slot on it
+	pub fn add_relay(&mut self, relay: PeerId) {
+		self.relays.insert(relay);
+	}
+	pub fn remove_relay(&mut self, relay: &PeerId) {
+		self.relays.remove(relay);
+	}
+	/// Whether this node relays for anyone, advertised to peers
+	pub fn is_public(&self) -> bool {
+		self.enabled && self.destinations == RelayDestinations::Anyone
+	}
+	/// Whether `from` may create another circuit through this node while `circuits` are open
+	/// Without `enabled` only circuits over our own relay and circuits that may lead to nodes with a slot here are taken
+	pub fn accepts_circuit(&self, from: &PeerId, circuits: usize) -> bool {
+		let allowed = self.enabled || self.relays.contains(from) || !self.reserved.is_empty();
+		allowed && circuits < self.max_circuits
+	}
+	/// Whether a circuit through this node may be extended to `next`
+	pub fn may_extend(&self, next: &PeerId) -> bool {
+		self.reserved.contains(next) || (self.enabled && (self.destinations == RelayDestinations::Anyone || self.friends.contains(next)))
+	}
+	/// Whether this node acts as introduction and rendezvous point, the hidden users it serves are not known up front
+	pub fn serves_hidden(&self) -> bool {
+		self.is_public()
+	}
+	/// Take one relayed cell from the bandwidth budget, `false` if it has to be dropped
+	pub fn spend_cell(&mut self) -> bool {
+		self.spend_cells(1)
+	}
+	/// Take `cells` relayed cells from the bandwidth budget at once, `false` if all of them have to be dropped
+	pub fn spend_cells(&mut self, cells: usize) -> bool {
+		let budget = match &mut self.budget {
+			Some(budget) => budget,
+			None => return true,
+		};
+		let now = Instant::now();
+		let cost = (cells * CELL_SIZE) as f64;
+		// At most a second of traffic is saved up, but always enough for the cells asked for
+		let burst = budget.rate.max(cost);
+		budget.available = (budget.available + now.duration_since(budget.updated).as_secs_f64() * budget.rate).min(burst);
+		budget.updated = now;
+		if budget.available < cost { return false }
+		budget.available -= cost;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn opted_out_nodes_only_take_circuits_over_their_relay() {
+		let mut policy = RelayPolicy::new(&RelayConfig { enabled: false, ..RelayConfig::default() });
+		let (relay, other) = (PeerId::random(), PeerId::random());
+		assert!(!policy.accepts_circuit(&other, 0));
+		policy.add_relay(relay.clone());
+		assert!(policy.accepts_circuit(&relay, 0));
+		assert!(!policy.accepts_circuit(&other, 0));
+		policy.remove_relay(&relay);
+		assert!(!policy.accepts_circuit(&relay, 0));
+		let policy = RelayPolicy::new(&RelayConfig { max_circuits: 1, ..RelayConfig::default() });
+		assert!(policy.accepts_circuit(&other, 0));
+		assert!(!policy.accepts_circuit(&other, 1));
+	}
+
+	#[test]
+	fn bandwidth_cap_is_in_kilobits() {
+		// 4125 bytes a second, room for one cell
+		let mut policy = RelayPolicy::new(&RelayConfig { bandwidth_kbits_per_sec: Some(33), ..RelayConfig::default() });
+		assert!(policy.spend_cell());
+		assert!(!policy.spend_cell());
+	}
+}
@@ -0,0 +1,72 @@
+// Throughput measurement between two nodes, a probe is a block of padding answered with a short acknowledgement
+
+use std::{
+	io,
+	time::Duration,
+};
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use serde_derive::{Serialize, Deserialize};
+use libp2p::{
+	core::upgrade,
+	request_response::{RequestResponseCodec, ProtocolName},
+};
+
+/// Largest probe a node will read, regardless of configuration
+pub const MAX_PROBE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ProbeProtocol();
+impl ProtocolName for ProbeProtocol {
+	fn protocol_name(&self) -> &[u8] {
+		b"/dither/probe/1.0.0"
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ProbeResponse {
+	/// Number of bytes read
+	Received(usize),
+	/// Peer was probed too recently
+	Refused,
+}
+
+/// Throughput in kilobits per second of `bytes` transferred in `elapsed`
+pub fn kbps(bytes: usize, elapsed: Duration) -> u32 {
+	let secs = elapsed.as_secs_f64().max(0.001);
+	(bytes as f64 * 8.0 / 1000.0 / secs).min(u32::MAX as f64) as u32
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeCodec();
+
+#[async_trait]
+impl RequestResponseCodec for ProbeCodec {
+	type Protocol = ProbeProtocol;
+	type Request = Vec<u8>;
+	type Response = ProbeResponse;
+
+	async fn read_request<T>(&mut self, _: &ProbeProtocol, io: &mut T) -> io::Result<Self::Request>
+	where T: AsyncRead + Unpin + Send
+	{
+		upgrade::read_one(io, MAX_PROBE_SIZE).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+	}
+	async fn read_response<T>(&mut self, _: &ProbeProtocol, io: &mut T) -> io::Result<Self::Response>
+	where T: AsyncRead + Unpin + Send
+	{
+		let data = upgrade::read_one(io, 1024).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+	async fn write_request<T>(&mut self, _: &ProbeProtocol, io: &mut T, request: Self::Request) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, request).await
+	}
+	async fn write_response<T>(&mut self, _: &ProbeProtocol, io: &mut T, response: Self::Response) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, serde_json::to_vec(&response)?).await
+	}
+}
@@ -0,0 +1,184 @@
+// Moving connections relayed to a node behind NAT onto a direct connection
+//
+// Both nodes exchange their addresses over the circuit, the initiator measures the round trip while doing so
+// After `CircuitMessage::UpgradeSync` the other node dials at once and the initiator half a round trip later,
+// so both dials leave at about the same time and open the mappings in both NATs for each other
+
+use std::{
+	collections::HashMap,
+	convert::TryFrom,
+	hash::Hash,
+	time::{Duration, Instant},
+};
+use tokio::time::Delay;
+use libp2p::{PeerId, Multiaddr, multiaddr::Protocol};
+
+/// Addresses sent in a single upgrade message
+pub const MAX_ADDRESSES: usize = 16;
+/// Times the initiator starts over after both dials failed
+pub const MAX_ATTEMPTS: u32 = 3;
+/// How long a circuit stays open after its connections moved, for cells that were already on their way
+pub const CIRCUIT_GRACE: Duration = Duration::from_secs(2);
+
+/// Upgrade of a relayed circuit in progress
+pub struct HolePunch {
+	/// Node on the other side of the circuit
+	pub node: PeerId,
+	/// Set on the node that asked for the upgrade, it closes the circuit once it is no longer used
+	pub initiator: bool,
+	/// When the initiator sent its addresses, the reply measures the round trip over the relay
+	pub sent: Instant,
+	/// Addresses the other side sent
+	pub addresses: Vec<Multiaddr>,
+	/// Fires when the initiator dials, half a round trip after `CircuitMessage::UpgradeSync` was sent
+	pub dial: Option<Delay>,
+	/// Set once this attempt dialed, so failures of other dials to the node are not mistaken for ours
+	pub dialed: bool,
+	pub attempts: u32,
+	pub deadline: Instant,
+}
+impl HolePunch {
+	pub fn new(node: PeerId, initiator: bool, timeout: Duration) -> HolePunch {
+		let now = Instant::now();
+		HolePunch { node, initiator, sent: now, addresses: Vec::new(), dial: None, dialed: false, attempts: 1, deadline: now + timeout }
+	}
+	/// Our addresses are sent again at `now`, starting another attempt
+	pub fn restart(&mut self, now: Instant) {
+		self.sent = now;
+		self.dialed = false;
+	}
+	/// Other side answered at `now` with its addresses, returns how long the initiator waits before it dials
+	pub fn answered(&mut self, addresses: Vec<Multiaddr>, now: Instant) -> Duration {
+		self.addresses = addresses;
+		now.saturating_duration_since(self.sent) / 2
+	}
+	/// Dials of this attempt failed, returns whether another attempt is left
+	pub fn dial_failed(&mut self) -> bool {
+		self.attempts += 1;
+		self.attempts <= MAX_ATTEMPTS
+	}
+	pub fn is_expired(&self, now: Instant) -> bool {
+		self.deadline <= now
+	}
+}
+
+/// Upgraded circuits kept open for `CIRCUIT_GRACE` after their connections moved
+#[derive(Debug)]
+pub struct Draining<C>(HashMap<C, Instant>);
+impl<C> Default for Draining<C> {
+	fn default() -> Draining<C> {
+		Draining(HashMap::new())
+	}
+}
+impl<C: Copy + Eq + Hash> Draining<C> {
+	pub fn insert(&mut self, circuit: C, now: Instant) {
+		self.0.insert(circuit, now + CIRCUIT_GRACE);
+	}
+	pub fn remove(&mut self, circuit: &C) {
+		self.0.remove(circuit);
+	}
+	/// Takes out the circuits past their grace that are no longer `in_use`, they can be closed
+	pub fn done(&mut self, now: Instant, in_use: impl Fn(&C) -> bool) -> Vec<C> {
+		let done: Vec<C> = self.0.iter().filter(|(circuit, until)| **until <= now && !in_use(circuit)).map(|(circuit, _)| *circuit).collect();
+		for circuit in &done {
+			self.0.remove(circuit);
+		}
+		done
+	}
+}
+
+/// Addresses another node could dial us on, circuit addresses would only lead back to the relay
+pub fn dialable(addresses: impl Iterator<Item = Multiaddr>) -> Vec<Vec<u8>> {
+	let mut dialable: Vec<Vec<u8>> = Vec::new();
+	for addr in addresses.filter(|addr| !addr.iter().any(|protocol| protocol == Protocol::P2pCircuit)) {
+		let addr = addr.to_vec();
+		if !dialable.contains(&addr) { dialable.push(addr) }
+	}
+	dialable.truncate(MAX_ADDRESSES);
+	dialable
+}
+
+/// Inverse of `dialable`, invalid addresses are skipped
+pub fn parse_addresses(addresses: Vec<Vec<u8>>) -> Vec<Multiaddr> {
+	addresses.into_iter().take(MAX_ADDRESSES).filter_map(|addr| Multiaddr::try_from(addr).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(addr: &str) -> Multiaddr {
+		addr.parse().expect("Valid address")
+	}
+
+	#[test]
+	fn circuit_and_duplicate_addresses_are_not_dialable() {
+		let direct = addr("/ip4/10.0.0.2/tcp/4001");
+		let relayed = addr("/ip4/10.0.0.1/tcp/4001/p2p-circuit");
+		let other = addr("/ip6/::1/tcp/4001");
+		let sent = dialable(vec![direct.clone(), relayed, direct.clone(), other.clone()].into_iter());
+		assert_eq!(parse_addresses(sent), vec![direct, other]);
+	}
+
+	#[test]
+	fn dialable_addresses_are_limited() {
+		let addresses = (0..MAX_ADDRESSES as u16 * 2).map(|port| addr(&format!("/ip4/10.0.0.2/tcp/{}", port)));
+		let sent = dialable(addresses);
+		assert_eq!(sent.len(), MAX_ADDRESSES);
+		// A peer sending more only gets the first ones dialed
+		let mut received = sent.clone();
+		received.extend(sent);
+		assert_eq!(parse_addresses(received).len(), MAX_ADDRESSES);
+	}
+
+	#[test]
+	fn invalid_addresses_are_skipped() {
+		let valid = addr("/ip4/10.0.0.2/tcp/4001");
+		let received = vec![vec![0xff, 0xff, 0xff], valid.to_vec(), vec![4, 10]];
+		assert_eq!(parse_addresses(received), vec![valid]);
+	}
+
+	#[test]
+	fn attempts_run_out() {
+		let mut punch = HolePunch::new(PeerId::random(), true, Duration::from_secs(15));
+		for _ in 1..MAX_ATTEMPTS {
+			assert!(punch.dial_failed());
+		}
+		assert!(!punch.dial_failed());
+	}
+
+	#[test]
+	fn initiator_dials_half_a_round_trip_later() {
+		let mut punch = HolePunch::new(PeerId::random(), true, Duration::from_secs(15));
+		let sent = Instant::now();
+		punch.dialed = true;
+		punch.restart(sent);
+		assert!(!punch.dialed);
+		let addresses = vec![addr("/ip4/10.0.0.2/tcp/4001")];
+		assert_eq!(punch.answered(addresses.clone(), sent + Duration::from_millis(300)), Duration::from_millis(150));
+		assert_eq!(punch.addresses, addresses);
+	}
+
+	#[test]
+	fn punches_expire_at_their_deadline() {
+		let punch = HolePunch::new(PeerId::random(), false, Duration::from_secs(15));
+		let now = Instant::now();
+		assert!(!punch.is_expired(now));
+		assert!(punch.is_expired(now + Duration::from_secs(15)));
+	}
+
+	#[test]
+	fn circuits_close_after_their_grace() {
+		let mut draining = Draining::default();
+		let now = Instant::now();
+		draining.insert(1, now);
+		draining.insert(2, now);
+		draining.insert(3, now);
+		draining.remove(&3);
+		assert!(draining.done(now, |_| false).is_empty());
+		// Circuits with sends waiting on them stay open past their grace
+		assert_eq!(draining.done(now + CIRCUIT_GRACE, |circuit| *circuit == 2), vec![1]);
+		assert!(draining.done(now + CIRCUIT_GRACE, |circuit| *circuit == 2).is_empty());
+		assert_eq!(draining.done(now + CIRCUIT_GRACE, |_| false), vec![2]);
+	}
+}
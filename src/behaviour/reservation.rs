@@ -0,0 +1,76 @@
+// Slots on relays for nodes behind NAT, a node with a slot can be reached through a circuit over the relay
+//
+// libp2p 0.28 has no circuit relay implementation to reserve slots with, and its transports can not dial
+// `/p2p-circuit` addresses. Relayed connections are onion circuits instead, so a slot only has to tell
+// the relay to extend circuits to the node even if `RelayConfig` would not
+
+use std::io;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use serde_derive::{Serialize, Deserialize};
+use libp2p::{
+	core::upgrade,
+	request_response::{RequestResponseCodec, ProtocolName},
+};
+
+/// Only relays answer it, so identify tells peers which nodes give out slots
+pub const RESERVATION_PROTOCOL: &[u8] = b"/dither/reservation/1.0.0";
+
+#[derive(Debug, Clone)]
+pub struct ReservationProtocol();
+impl ProtocolName for ReservationProtocol {
+	fn protocol_name(&self) -> &[u8] {
+		RESERVATION_PROTOCOL
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReservationRequest {
+	/// Reserve a slot or renew the one the node has
+	Reserve,
+	/// Give the slot up
+	Release,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReservationResponse {
+	/// Slot is kept for this many seconds unless it is renewed
+	Accepted(u64),
+	Released,
+	Refused(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ReservationCodec();
+
+#[async_trait]
+impl RequestResponseCodec for ReservationCodec {
+	type Protocol = ReservationProtocol;
+	type Request = ReservationRequest;
+	type Response = ReservationResponse;
+
+	async fn read_request<T>(&mut self, _: &ReservationProtocol, io: &mut T) -> io::Result<Self::Request>
+	where T: AsyncRead + Unpin + Send
+	{
+		let data = upgrade::read_one(io, 1024).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+	async fn read_response<T>(&mut self, _: &ReservationProtocol, io: &mut T) -> io::Result<Self::Response>
+	where T: AsyncRead + Unpin + Send
+	{
+		let data = upgrade::read_one(io, 1024).await
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+	async fn write_request<T>(&mut self, _: &ReservationProtocol, io: &mut T, request: Self::Request) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, serde_json::to_vec(&request)?).await
+	}
+	async fn write_response<T>(&mut self, _: &ReservationProtocol, io: &mut T, response: Self::Response) -> io::Result<()>
+	where T: AsyncWrite + Unpin + Send
+	{
+		upgrade::write_one(io, serde_json::to_vec(&response)?).await
+	}
+}
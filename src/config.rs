@@ -1,16 +1,250 @@
 
 use serde_derive::{Serialize, Deserialize};
 use std::{
-	error::Error,
 	io::{BufReader, Read},
 	fs::File,
-	path::Path,
+	path::{Path, PathBuf},
+	time::Duration,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+use libp2p::Multiaddr;
+
+use crate::{DitherError, EventPolicy};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DitherConfig {
 	pub dev_mode: bool,
 	pub pubsub_topic: String,
+	/// File storing the node's keypair, created if missing
+	/// If `None`, a new identity is generated every time the node starts
+	#[serde(default)]
+	pub key_file: Option<PathBuf>,
+	/// File storing verified bootstrap addresses, these are dialed again on start
+	#[serde(default)]
+	pub peers_file: Option<PathBuf>,
+	/// Addresses the node listens on, e.g. `/ip4/0.0.0.0/tcp/4001`, `/ip6/::/tcp/0` or `/ip4/0.0.0.0/tcp/4002/ws`
+	/// Port 0 picks a random port, addresses that were bound are sent as `DitherEvent::NewListenAddr`
+	#[serde(default = "DitherConfig::default_listen_addresses")]
+	pub listen_addresses: Vec<Multiaddr>,
+	/// Multiplexer and connection tuning
+	#[serde(default)]
+	pub transport: TransportConfig,
+	/// What happens to events while an application is not reading them fast enough
+	#[serde(default)]
+	pub event_policy: EventPolicy,
+	/// Events buffered per channel before `event_policy` applies
+	#[serde(default = "DitherConfig::default_event_buffer")]
+	pub event_buffer: usize,
+	/// Throughput measurements between this node and its peers
+	#[serde(default)]
+	pub bandwidth_probe: BandwidthProbeConfig,
+	/// Relays between this node and the destination of `DitherAction::ConnectAnonymously`
+	#[serde(default = "DitherConfig::default_circuit_hops")]
+	pub circuit_hops: usize,
+	/// Introduction points published for users created with `DitherAction::CreateHiddenUser`
+	#[serde(default = "DitherConfig::default_introduction_points")]
+	pub introduction_points: usize,
+	/// Dummy cells on idle onion circuits
+	#[serde(default)]
+	pub cover_traffic: CoverTrafficConfig,
+	/// What this node does for onion circuits built by other nodes
+	#[serde(default)]
+	pub relay: RelayConfig,
+	/// Reaching nodes behind NAT through bootstrap nodes
+	#[serde(default)]
+	pub nat_relay: NatRelayConfig,
+	/// Moving connections relayed to nodes behind NAT onto direct connections
+	#[serde(default)]
+	pub hole_punching: HolePunchConfig,
+}
+
+/// Probing costs traffic on both ends, so it is off unless enabled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthProbeConfig {
+	/// Probe connected peers and answer their probes
+	pub enabled: bool,
+	/// Minimum time between two probes in either direction between this node and a peer
+	pub interval_secs: u64,
+	/// Size of a probe, capped at 1 MiB
+	pub probe_bytes: usize,
+}
+impl Default for BandwidthProbeConfig {
+	fn default() -> BandwidthProbeConfig {
+		BandwidthProbeConfig {
+			enabled: false,
+			interval_secs: 300,
+			probe_bytes: 256 * 1024,
+		}
+	}
+}
+impl BandwidthProbeConfig {
+	pub fn interval(&self) -> Duration {
+		Duration::from_secs(self.interval_secs)
+	}
+}
+
+/// Cover traffic hides when a circuit is used at the cost of a constant stream of cells, so it is off unless enabled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoverTrafficConfig {
+	/// Send dummy cells on circuits built by this node or ending at it
+	pub enabled: bool,
+	/// A circuit that sent no cell for this many milliseconds sends a dummy cell
+	pub interval_millis: u64,
+}
+impl Default for CoverTrafficConfig {
+	fn default() -> CoverTrafficConfig {
+		CoverTrafficConfig {
+			enabled: false,
+			interval_millis: 500,
+		}
+	}
+}
+impl CoverTrafficConfig {
+	pub fn interval(&self) -> Duration {
+		Duration::from_millis(self.interval_millis)
+	}
+}
+
+/// Nodes a relay extends circuits to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayDestinations {
+	Anyone,
+	/// Only `RelayConfig::friends` and bootstrap nodes this node connected to
+	Friends,
+}
+impl Default for RelayDestinations {
+	fn default() -> RelayDestinations { RelayDestinations::Anyone }
+}
+
+/// Only nodes that relay to anyone tell their peers, so path selection of other nodes leaves the rest out
+/// Nodes that opt out take no circuits of other nodes, apart from those over a relay they have a slot on (see `NatRelayConfig`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+	/// Take part in circuits of other nodes and act as introduction or rendezvous point, low powered nodes should opt out
+	pub enabled: bool,
+	/// Circuits built by other nodes through or to this node at the same time
+	pub max_circuits: usize,
+	/// Relayed traffic in kilobits per second (the unit of bandwidth measurements), cells over the cap are dropped, unlimited if `None`
+	pub bandwidth_kbits_per_sec: Option<u32>,
+	pub destinations: RelayDestinations,
+	/// `PeerId`s (base58) allowed as destinations with `RelayDestinations::Friends`
+	pub friends: Vec<String>,
+}
+impl Default for RelayConfig {
+	fn default() -> RelayConfig {
+		RelayConfig {
+			enabled: true,
+			max_circuits: 256,
+			bandwidth_kbits_per_sec: None,
+			destinations: RelayDestinations::default(),
+			friends: Vec::new(),
+		}
+	}
+}
+
+/// A node behind NAT keeps a slot on its bootstrap nodes, others reach it through a circuit over one of them
+/// Relaying costs the relay traffic, so both sides are off unless enabled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NatRelayConfig {
+	/// Give slots to nodes that ask for one and relay circuits to them, regardless of `RelayConfig`
+	pub serve: bool,
+	/// Nodes with a slot at the same time
+	pub max_reservations: usize,
+	/// A slot that is not renewed for this many seconds is given up
+	pub reservation_secs: u64,
+	/// Ask every bootstrap node for a slot and publish `/p2p-circuit` addresses through it in the definitions of local users
+	pub reserve: bool,
+	/// With `reserve`, also ask other peers that identify as relays giving out slots
+	pub reserve_identified: bool,
+}
+impl Default for NatRelayConfig {
+	fn default() -> NatRelayConfig {
+		NatRelayConfig {
+			serve: false,
+			max_reservations: 64,
+			reservation_secs: 300,
+			reserve: false,
+			reserve_identified: false,
+		}
+	}
+}
+impl NatRelayConfig {
+	pub fn reservation(&self) -> Duration {
+		Duration::from_secs(self.reservation_secs)
+	}
+}
+
+/// `DitherAction::Connect` through a relay (see `NatRelayConfig`) tries to reach the node directly with both sides dialing at the same time
+/// With `TransportConfig::port_reuse` dials leave from the listen port, so NATs that keep a node's mapping for every destination let them through
+/// Connections stay on the relay if the NATs on the way drop the dials, e.g. if both map every destination to another port
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HolePunchConfig {
+	/// Start upgrades of our relayed connections and take part in upgrades started by other nodes
+	pub enabled: bool,
+	/// Connections are left on the relay if no direct connection was made within this many seconds
+	pub timeout_secs: u64,
+}
+impl Default for HolePunchConfig {
+	fn default() -> HolePunchConfig {
+		HolePunchConfig {
+			enabled: true,
+			timeout_secs: 15,
+		}
+	}
+}
+impl HolePunchConfig {
+	pub fn timeout(&self) -> Duration {
+		Duration::from_secs(self.timeout_secs)
+	}
+}
+
+/// Stream multiplexer used on connections to other nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Multiplexer {
+	Yamux,
+	Mplex,
+	/// Offer both (yamux preferred), so nodes configured with either one can connect
+	Negotiate,
+}
+impl Default for Multiplexer {
+	fn default() -> Multiplexer { Multiplexer::Negotiate }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+	pub multiplexer: Multiplexer,
+	/// Disable Nagle's algorithm on TCP sockets
+	pub tcp_nodelay: bool,
+	/// Listen with `SO_REUSEPORT` and dial from the listen port, so NATs map hole punching dials to the port peers were sent
+	/// Off by default: a second node on the same port shares it with the first instead of failing with `AddrInUse`,
+	/// and listen addresses are the interface addresses at the time of listening, interfaces that change later are not reported
+	pub port_reuse: bool,
+	/// Close connections without protocol activity for this many seconds, if `None` connections are kept alive
+	pub idle_timeout_secs: Option<u64>,
+	/// Limit on open substreams per connection, multiplexer default if `None`
+	pub max_substreams: Option<usize>,
+}
+impl Default for TransportConfig {
+	fn default() -> TransportConfig {
+		TransportConfig {
+			multiplexer: Multiplexer::default(),
+			tcp_nodelay: true,
+			port_reuse: false,
+			idle_timeout_secs: None,
+			max_substreams: None,
+		}
+	}
+}
+impl TransportConfig {
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		self.idle_timeout_secs.map(Duration::from_secs)
+	}
 }
 
 impl DitherConfig {
@@ -18,15 +252,38 @@ impl DitherConfig {
 		DitherConfig {
 			dev_mode: true,
 			pubsub_topic: "chat".to_owned(),
+			key_file: None,
+			peers_file: None,
+			listen_addresses: Self::default_listen_addresses(),
+			transport: TransportConfig::default(),
+			event_policy: EventPolicy::default(),
+			event_buffer: Self::default_event_buffer(),
+			bandwidth_probe: BandwidthProbeConfig::default(),
+			circuit_hops: Self::default_circuit_hops(),
+			introduction_points: Self::default_introduction_points(),
+			cover_traffic: CoverTrafficConfig::default(),
+			relay: RelayConfig::default(),
+			nat_relay: NatRelayConfig::default(),
+			hole_punching: HolePunchConfig::default(),
 		}
 	}
-	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<DitherConfig, Box<dyn Error>> {
+	/// Random TCP port on every IPv4 and IPv6 interface
+	pub fn default_listen_addresses() -> Vec<Multiaddr> {
+		vec![
+			"/ip4/0.0.0.0/tcp/0".parse().expect("Valid multiaddr"),
+			"/ip6/::/tcp/0".parse().expect("Valid multiaddr"),
+		]
+	}
+	pub fn default_event_buffer() -> usize { 64 }
+	pub fn default_circuit_hops() -> usize { 2 }
+	pub fn default_introduction_points() -> usize { 3 }
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<DitherConfig, DitherError> {
 		let file = File::open(path)?;
 		let reader = BufReader::new(file);
 		
 		return Self::from_reader(reader);
 	}
-	pub fn from_reader(reader: impl Read) -> Result<DitherConfig, Box<dyn Error>> {
-		Ok(serde_json::from_reader(reader)?)
+	pub fn from_reader(reader: impl Read) -> Result<DitherConfig, DitherError> {
+		serde_json::from_reader(reader).map_err(DitherError::Config)
 	}
 }
\ No newline at end of file
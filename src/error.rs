@@ -0,0 +1,112 @@
+// Errors returned by the Dither API and sent to applications as `DitherEvent::Error`
+
+use std::{error::Error, fmt, io};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use libp2p::{
+	Multiaddr,
+	core::{transport::TransportError, connection::ConnectionLimit},
+	noise::NoiseError,
+	kad::{NoKnownPeers, record::store},
+};
+
+use crate::{UserId, SendFailure};
+
+#[derive(Debug)]
+pub enum DitherError {
+	/// Listening on or dialing an address failed
+	Transport(String),
+	/// Noise keys could not be created from the node key
+	Noise(NoiseError),
+	/// Configuration could not be parsed
+	Config(serde_json::Error),
+	/// Reading or writing node state on disk failed
+	Io(io::Error),
+	/// Key file is corrupt or can be read by other users
+	KeyFile(String),
+	/// Data from the network or disk could not be decoded
+	Encoding(String),
+	/// Storing or looking up a record on the DHT failed
+	Dht(String),
+	/// User is not known or not hosted on any reachable node
+	UnknownUser(UserId),
+	/// `DitherAction::SendData` was not delivered
+	SendFailed(SendFailure),
+	/// Remote node could not be authenticated, e.g. a bootstrap node answered with the wrong `PeerId`
+	AuthFailure(String),
+	/// Address is missing its `/p2p/<PeerId>` component
+	InvalidAddress(Multiaddr),
+	/// Channel between layers is full
+	ChannelFull,
+	/// Other side of a channel between layers was dropped
+	ChannelClosed,
+	/// Task running a layer stopped unexpectedly (e.g. panicked)
+	TaskFailed(String),
+	/// Onion circuit could not be built
+	Circuit(String),
+	/// `DitherAction` is not implemented by this node
+	Unsupported(String),
+}
+
+impl fmt::Display for DitherError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			DitherError::Transport(err) => write!(f, "Transport error: {}", err),
+			DitherError::Noise(err) => write!(f, "Noise error: {}", err),
+			DitherError::Config(err) => write!(f, "Failed to parse config: {}", err),
+			DitherError::Io(err) => write!(f, "IO error: {}", err),
+			DitherError::KeyFile(err) => write!(f, "Invalid key file: {}", err),
+			DitherError::Encoding(err) => write!(f, "Failed to decode: {}", err),
+			DitherError::Dht(err) => write!(f, "DHT error: {}", err),
+			DitherError::UnknownUser(user) => write!(f, "Unknown user: {}", user),
+			DitherError::SendFailed(failure) => write!(f, "Failed to send data: {:?}", failure),
+			DitherError::AuthFailure(err) => write!(f, "Authentication failed: {}", err),
+			DitherError::InvalidAddress(addr) => write!(f, "Address must end with /p2p/<PeerId>: {}", addr),
+			DitherError::ChannelFull => write!(f, "Channel is full"),
+			DitherError::ChannelClosed => write!(f, "Channel is closed"),
+			DitherError::TaskFailed(err) => write!(f, "Task failed: {}", err),
+			DitherError::Circuit(err) => write!(f, "Circuit error: {}", err),
+			DitherError::Unsupported(action) => write!(f, "Unsupported action: {}", action),
+		}
+	}
+}
+
+impl Error for DitherError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			DitherError::Noise(err) => Some(err),
+			DitherError::Config(err) => Some(err),
+			DitherError::Io(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for DitherError {
+	fn from(err: io::Error) -> DitherError { DitherError::Io(err) }
+}
+impl From<NoiseError> for DitherError {
+	fn from(err: NoiseError) -> DitherError { DitherError::Noise(err) }
+}
+impl From<TransportError<io::Error>> for DitherError {
+	fn from(err: TransportError<io::Error>) -> DitherError { DitherError::Transport(err.to_string()) }
+}
+impl From<ConnectionLimit> for DitherError {
+	fn from(err: ConnectionLimit) -> DitherError { DitherError::Transport(err.to_string()) }
+}
+impl From<store::Error> for DitherError {
+	fn from(err: store::Error) -> DitherError { DitherError::Dht(format!("{:?}", err)) }
+}
+impl From<NoKnownPeers> for DitherError {
+	fn from(_: NoKnownPeers) -> DitherError { DitherError::Dht("No known peers".to_owned()) }
+}
+impl<T> From<SendError<T>> for DitherError {
+	fn from(_: SendError<T>) -> DitherError { DitherError::ChannelClosed }
+}
+impl<T> From<TrySendError<T>> for DitherError {
+	fn from(err: TrySendError<T>) -> DitherError {
+		match err {
+			TrySendError::Full(_) => DitherError::ChannelFull,
+			TrySendError::Closed(_) => DitherError::ChannelClosed,
+		}
+	}
+}
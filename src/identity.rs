@@ -0,0 +1,152 @@
+// Persistent node identity, so our `PeerId` survives restarts
+
+use std::{
+	fs::{self, OpenOptions},
+	io::{self, Write},
+	path::Path,
+};
+use libp2p::identity::{Keypair, ed25519};
+
+use crate::DitherError;
+
+/// Written before the key bytes so we can tell our key files from random data
+const KEY_FILE_HEADER: &[u8] = b"dither-ed25519-v1\n";
+/// Length of an encoded ed25519 keypair (secret + public)
+const KEY_LEN: usize = 64;
+
+/// Load node keypair from `path`, generating and saving a new one if the file does not exist
+pub fn load_or_generate(path: &Path) -> Result<Keypair, DitherError> {
+	match fs::metadata(path) {
+		Ok(metadata) => {
+			check_permissions(&metadata)?;
+			let key = decode(&fs::read(path)?)?;
+			log::info!("Loaded node key from: {:?}", path);
+			Ok(key)
+		},
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			let key = ed25519::Keypair::generate();
+			save(path, &key)?;
+			log::info!("Generated new node key at: {:?}", path);
+			Ok(Keypair::Ed25519(key))
+		},
+		Err(err) => Err(err.into()),
+	}
+}
+
+fn decode(data: &[u8]) -> Result<Keypair, DitherError> {
+	if data.len() != KEY_FILE_HEADER.len() + KEY_LEN || !data.starts_with(KEY_FILE_HEADER) {
+		return Err(DitherError::KeyFile("Not a dither ed25519 key".to_owned()));
+	}
+	let mut bytes = data[KEY_FILE_HEADER.len()..].to_vec();
+	// Also checks that the public half matches the secret half
+	let key = ed25519::Keypair::decode(&mut bytes).map_err(|_| DitherError::KeyFile("Key is corrupt".to_owned()))?;
+	Ok(Keypair::Ed25519(key))
+}
+
+fn save(path: &Path, key: &ed25519::Keypair) -> Result<(), DitherError> {
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut file = options.open(path)?;
+	file.write_all(KEY_FILE_HEADER)?;
+	file.write_all(&key.encode())?;
+	file.sync_all()?;
+	Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(metadata: &fs::Metadata) -> Result<(), DitherError> {
+	use std::os::unix::fs::PermissionsExt;
+	if metadata.permissions().mode() & 0o077 != 0 {
+		return Err(DitherError::KeyFile("Must only be accessible by its owner (chmod 600)".to_owned()));
+	}
+	Ok(())
+}
+#[cfg(not(unix))]
+fn check_permissions(_metadata: &fs::Metadata) -> Result<(), DitherError> { Ok(()) }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::PathBuf;
+	use libp2p::PeerId;
+
+	/// Empty directory for a single test, removed when dropped
+	struct TempDir(PathBuf);
+	impl TempDir {
+		fn new(name: &str) -> TempDir {
+			let dir = std::env::temp_dir().join(format!("dither-identity-{}-{}", std::process::id(), name));
+			let _ = fs::remove_dir_all(&dir);
+			fs::create_dir_all(&dir).unwrap();
+			TempDir(dir)
+		}
+		fn path(&self, file: &str) -> PathBuf { self.0.join(file) }
+	}
+	impl Drop for TempDir {
+		fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+	}
+
+	#[cfg(unix)]
+	fn write_private(path: &Path, data: &[u8]) {
+		use std::os::unix::fs::OpenOptionsExt;
+		OpenOptions::new().write(true).create_new(true).mode(0o600).open(path).unwrap().write_all(data).unwrap();
+	}
+
+	#[test]
+	fn generated_key_is_loaded_again() {
+		let dir = TempDir::new("round-trip");
+		let path = dir.path("keys/node.key");
+		let generated = load_or_generate(&path).unwrap();
+		let loaded = load_or_generate(&path).unwrap();
+		assert_eq!(PeerId::from(generated.public()), PeerId::from(loaded.public()));
+		assert_eq!(fs::read(&path).unwrap().len(), KEY_FILE_HEADER.len() + KEY_LEN);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		}
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn bad_header_is_rejected() {
+		let dir = TempDir::new("bad-header");
+		let path = dir.path("node.key");
+		let mut data = b"dither-rsa-v1\n----".to_vec();
+		data.extend_from_slice(&ed25519::Keypair::generate().encode());
+		assert_eq!(data.len(), KEY_FILE_HEADER.len() + KEY_LEN);
+		write_private(&path, &data);
+		assert!(matches!(load_or_generate(&path), Err(DitherError::KeyFile(_))));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn truncated_key_is_rejected() {
+		let dir = TempDir::new("truncated");
+		let path = dir.path("node.key");
+		let mut data = KEY_FILE_HEADER.to_vec();
+		data.extend_from_slice(&ed25519::Keypair::generate().encode()[..KEY_LEN / 2]);
+		write_private(&path, &data);
+		assert!(matches!(load_or_generate(&path), Err(DitherError::KeyFile(_))));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn readable_by_others_is_rejected() {
+		use std::os::unix::fs::PermissionsExt;
+		let dir = TempDir::new("permissions");
+		let path = dir.path("node.key");
+		load_or_generate(&path).unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		assert!(matches!(load_or_generate(&path), Err(DitherError::KeyFile(_))));
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+		assert!(matches!(load_or_generate(&path), Err(DitherError::KeyFile(_))));
+	}
+}
@@ -3,45 +3,61 @@
 
 use std::{
 	collections::hash_map::DefaultHasher,
-	collections::HashMap,
+	collections::{HashMap, HashSet},
 	hash::{Hash, Hasher},
-	time::Duration,
-	error::Error,
+	time::{Duration, Instant},
+	fmt,
 };
 
 use tokio::sync::mpsc::{self, Sender, Receiver};
 use tokio::task::JoinHandle;
 use libp2p::{
 	Swarm,
-	Transport,
-	core::upgrade,
 	floodsub::{self, Floodsub, Topic, FloodsubEvent},
 	//gossipsub::{protocol::MessageId, GossipsubMessage, GossipsubEvent, MessageAuthenticity, Topic, self},
 	mdns::TokioMdns, // `TokioMdns` is available through the `mdns-tokio` feature.
-	mplex,
-	noise,
-	swarm::SwarmBuilder, // `TokioTcpConfig` is available through the `tcp-tokio` feature.
-	tcp::TokioTcpConfig,
+	ping::PingSuccess,
+	core::connection::ListenerId,
+	multiaddr::Protocol,
+	swarm::{SwarmBuilder, SwarmEvent},
 	swarm::NetworkBehaviour,
 };
 pub use libp2p::{
 	PeerId,
 	Multiaddr,
 	identity::Keypair,
+	core::ConnectedPoint,
 };
 
 mod behaviour;
 use behaviour::DitherBehaviour;
-pub use behaviour::DitherEvent;
+pub use behaviour::{DitherEvent, UserConnection, UserConnectionId, SendId, SendFailure, CircuitId, IntroductionId, MAX_DATA_SIZE};
 
 pub mod types;
 pub use types::*;
 pub mod config;
-pub use config::DitherConfig;
+pub use config::{DitherConfig, TransportConfig, Multiplexer};
+pub mod error;
+pub use error::DitherError;
+pub mod request;
+pub use request::{DitherRequest, DitherReply, DitherResult, ReplySender};
+mod identity;
+mod peers;
+use peers::PeerList;
+pub use peers::PeerInfo;
+mod transport;
+mod tcp;
+pub mod queue;
+pub use queue::{EventPolicy, DroppedEvents};
+mod outbox;
+use outbox::Outbox;
 pub mod user;
 pub use user::*;
 pub mod routing;
-pub use routing;
+use routing::{Router, RoutingTable, RouteMeasurement};
+
+/// Time the swarm is given to send close frames before it is dropped on shutdown
+const SHUTDOWN_FLUSH: Duration = Duration::from_millis(500);
 
 /// The Dither object, runs the swarm
 /// Contains all the necessary information to run a Node
@@ -60,8 +76,46 @@ pub struct Dither {
 	/// All information is stored in the `User` objects
 	/// Applications can Authenticate into existing users with some token(s) (e.g. password, 2auth key, temp application token) or private key
 	users: HashMap<UserId, User>,
+	/// Keys of users created on this node, used to sign their published definitions
+	user_keys: HashMap<UserId, NetworkKey>,
+	/// Introduction points of hidden users on this node and the circuits to them
+	introductions: HashMap<UserId, Vec<(PeerId, CircuitId)>>,
 	/// Dither configuration
 	config: DitherConfig,
+	/// Bootstrap nodes that were verified before, dialed again on every start
+	peers: PeerList,
+	/// Addresses that reach this node through relays it has a slot on, by relay
+	relay_addresses: HashMap<PeerId, Multiaddr>,
+	/// `DitherAction::Discover`s waiting for the DHT
+	pending_discovers: HashMap<UserId, Vec<ReplySender>>,
+	/// `DitherAction::Connect`s waiting for their user to be discovered, set if the connection should go through a circuit
+	pending_connects: HashMap<UserId, Vec<(Application, bool, Option<ReplySender>)>>,
+	/// `DitherAction::SendData`s waiting for acknowledgement
+	pending_sends: HashMap<SendId, ReplySender>,
+	/// Application of every open connection, its events go to that application
+	connection_apps: HashMap<UserConnectionId, Application>,
+	/// Application of sends without a caller waiting for the result
+	send_apps: HashMap<SendId, Application>,
+	/// Bootstrap dials in progress, maps dialed address to expected `PeerId`, full bootstrap address and caller
+	bootstraps: HashMap<Multiaddr, (PeerId, Multiaddr, Option<ReplySender>)>,
+	/// Events waiting for the node's `ThreadHandle` and applications registered with `DitherAction::Register`
+	outbox: Outbox,
+	/// Receiving end of the node's own event queue, handed out by `Dither::start`
+	events: Option<Receiver<DitherEvent>>,
+	/// Floodsub topics subscribed to through a registered application's channel
+	topics: HashMap<String, Application>,
+	/// Every floodsub topic the node is subscribed to
+	subscriptions: HashSet<String>,
+	/// Peers with at least one open connection
+	connected: HashMap<PeerId, PeerInfo>,
+	/// Measured routes between peers and path finding over them
+	router: Router,
+	/// When this node last advertised the links it measured
+	links_advertised: Option<Instant>,
+	/// Requests of registered applications are forwarded here tagged with their `Application`, set once the node is started
+	app_requests: Option<Sender<(Application, DitherRequest)>>,
+	/// Listeners started by `Dither::connect`, removed on shutdown
+	listeners: Vec<ListenerId>,
 	/// `Swarm` object for managing behaviour and connected nodes
 	swarm: Swarm<DitherBehaviour, PeerId>,
 }
@@ -70,6 +124,9 @@ pub struct Dither {
 pub enum DitherAction {
 	/// Create new user, `User` and `NetworkKey` object will be sent back to the application that requested a new user
 	CreateUser(),
+	/// Like `DitherAction::CreateUser`, but the user's definition publishes `DitherConfig::introduction_points`
+	/// connected peers instead of this node, contacts reach it through a rendezvous point of their choice
+	CreateHiddenUser(),
 	/// Bootstrap node for initial nat connection (choose this with care, MITM attacks beware)
 	Bootstrap(Multiaddr),
 	
@@ -86,118 +143,712 @@ pub enum DitherAction {
 	/// This will attempt to connect to a User on the network
 	/// If user is found, UserConnection will be sent to the application
 	Connect(UserId, Application),
+	/// Like `DitherAction::Connect`, but through an onion circuit over `DitherConfig::circuit_hops` relays
+	/// Relays only learn the node before and after them, the user's node does not learn which node connected
+	ConnectAnonymously(UserId, Application),
+	/// Accept `UserConnection`s to local users from other nodes for this application
+	Accept(Application),
 	/// Send data on an application to specific UserId
 	/// If Public Id and Hosting Nodes of User is known, data is sent to desired node encrypted with public key
+	/// Answered with `DitherEvent::SendQueued`, then `DitherEvent::SendResult` once the node acknowledges or delivery fails
 	SendData(UserConnection, Vec<u8>),
 	
-	//PubSubSubscribe(String),
-	//PubSubUnsubscribe(String),
-	//PubSubBroadcast(String, Vec<u8>),
+	/// Register an application with the node
+	/// Answered with `DitherReply::Registered`, a request sender and event receiver used only by this application
+	/// `DitherEvent`s that belong to the application (received data, its connections) are sent only to its receiver
+	Register(Application),
+	
+	PubSubSubscribe(String),
+	PubSubUnsubscribe(String),
+	PubSubBroadcast(String, Vec<u8>),
 	//FloodSub(String, String), // Going to be a lot more complicated
-	/// [Debug] Print listening addrs to console
-	PrintListening,
+	/// Addresses the node is listening on, answered with `DitherReply::ListenAddresses`
+	GetListenAddresses,
+	/// Connected peers and what they told us about themselves, answered with `DitherReply::Peers`
+	GetPeers,
+	/// Users created on this node, answered with `DitherReply::LocalUsers`
+	GetLocalUsers,
+	/// Snapshot of the routing table, answered with `DitherReply::Routes`
+	GetRoutes,
+	/// Floodsub topics the node is subscribed to, answered with `DitherReply::Subscriptions`
+	GetSubscriptions,
+	/// Stop listening, close all connections and save persistent state, then end the node's task with `StopReason::Shutdown`
+	/// Answered with `DitherReply::Done` once the node has stopped
+	Shutdown,
+}
+
+/// Why the node's task ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+	/// `DitherAction::Shutdown` was received
+	Shutdown,
+	/// Every sender of the node's `ThreadHandle` was dropped
+	SendersClosed,
 }
 
 pub struct ThreadHandle<Return, ActionObject, EventObject> {
 	pub join: JoinHandle<Return>,
 	pub sender: Sender<ActionObject>,
 	pub receiver: Receiver<EventObject>,
+	/// Events lost on `receiver` because it was not read fast enough
+	pub dropped: DroppedEvents,
 }
 
 impl Dither {
-	pub fn new(config: DitherConfig) -> Result<Dither, Box<dyn Error>> {
-		let key = Keypair::generate_ed25519();
+	pub fn new(config: DitherConfig) -> Result<Dither, DitherError> {
+		let key = match &config.key_file {
+			Some(path) => identity::load_or_generate(path)?,
+			None => Keypair::generate_ed25519(),
+		};
 		let peer_id = PeerId::from(key.public());
 		
-		let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
-			.into_authentic(&key)?;
-		let transport = TokioTcpConfig::new().nodelay(true)
-			.upgrade(upgrade::Version::V1)
-			.authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-			.multiplex(mplex::MplexConfig::new())
-			.boxed();
-			
-		let behaviour = behaviour::DitherBehaviour::new(peer_id.clone(), TokioMdns::new()?);
+		let transport = transport::build(&key, &config)?;
+		
+		let peers = PeerList::load(config.peers_file.clone())?;
+		let behaviour = behaviour::DitherBehaviour::new(peer_id.clone(), &key, TokioMdns::new()?, &config);
+		let (event_sender, events) = mpsc::channel(config.event_buffer);
+		let outbox = Outbox::new(event_sender, config.event_policy, config.event_buffer);
 		
 		Ok(Dither {
-			node: Node {
-				
-			}
 			key: key.clone(),
 			peer_id: peer_id.clone(),
 			swarm: SwarmBuilder::new(transport, behaviour, peer_id)
 				.executor(Box::new(|fut| { tokio::spawn(fut); }))
 				.build(),
 			config,
-			users: Vec::new(),
+			peers,
+			relay_addresses: HashMap::new(),
+			bootstraps: HashMap::new(),
+			outbox,
+			events: Some(events),
+			topics: HashMap::new(),
+			subscriptions: HashSet::new(),
+			connected: HashMap::new(),
+			router: Router::new(RoutingTable::new(peer_id.clone())),
+			links_advertised: None,
+			app_requests: None,
+			listeners: Vec::new(),
+			pending_discovers: HashMap::new(),
+			pending_connects: HashMap::new(),
+			pending_sends: HashMap::new(),
+			connection_apps: HashMap::new(),
+			send_apps: HashMap::new(),
+			users: HashMap::new(),
+			user_keys: HashMap::new(),
+			introductions: HashMap::new(),
 		})
 	}
-	pub fn connect(&mut self) -> Result<(), Box<dyn Error>> {
-		Swarm::listen_on(&mut self.swarm, "/ip4/0.0.0.0/tcp/0".parse()?)?;
-		log::info!("Local peer id: {:?}", self.user.peer_id);
+	/// `PeerId` of the node, other nodes bootstrap with `<address>/p2p/<PeerId>`
+	pub fn peer_id(&self) -> &PeerId {
+		&self.peer_id
+	}
+	pub fn connect(&mut self) -> Result<(), DitherError> {
+		for addr in self.config.listen_addresses.clone() {
+			let listener = Swarm::listen_on(&mut self.swarm, addr.clone()).map_err(|err| {
+				log::error!("Failed to listen on {:?}: {:?}", addr, err);
+				DitherError::from(err)
+			})?;
+			self.listeners.push(listener);
+		}
+		log::info!("Local peer id: {:?}", self.peer_id);
 		
+		for addr in self.peers.peers().to_vec() {
+			if let Err(err) = self.bootstrap(addr.clone(), &mut None) {
+				log::warn!("Failed to dial known bootstrap {:?}: {:?}", addr, err);
+			}
+		}
+		Ok(())
+	}
+	/// Dial bootstrap node, `addr` must end with `/p2p/<PeerId>` so the remote can be verified
+	fn bootstrap(&mut self, addr: Multiaddr, reply: &mut Option<ReplySender>) -> Result<(), DitherError> {
+		let (dial_addr, peer) = peers::split_peer_addr(addr.clone())
+			.ok_or_else(|| DitherError::InvalidAddress(addr.clone()))?;
+		log::info!("Bootstrapping with {:?} at {:?}", peer, dial_addr);
+		Swarm::dial_addr(&mut self.swarm, dial_addr.clone())?;
+		self.bootstraps.insert(dial_addr, (peer, addr, reply.take()));
 		Ok(())
 	}
-	fn parse_dither_action(&mut self, action: DitherAction) -> Result<(), Box<dyn Error>> {
+	/// Answer the caller if it is waiting for a result, otherwise broadcast `event`
+	fn respond(&mut self, reply: Option<ReplySender>, result: DitherResult, event: DitherEvent, origin: Option<&Application>) {
+		match reply {
+			Some(reply) => { let _ = reply.send(result); },
+			None => self.outbox.push(origin, event),
+		}
+	}
+	/// Give `application` its own request and event channels
+	fn register(&mut self, application: Application) -> Result<DitherReply, DitherError> {
+		let mut node_sender = self.app_requests.clone().ok_or(DitherError::ChannelClosed)?;
+		let (app_sender, mut app_receiver) = mpsc::channel::<DitherRequest>(self.config.event_buffer);
+		let (event_receiver, dropped) = self.outbox.register(application.clone());
+		log::info!("Registered Application: {:?}", application);
+		tokio::spawn(async move {
+			while let Some(request) = app_receiver.recv().await {
+				if node_sender.send((application.clone(), request)).await.is_err() { break }
+			}
+		});
+		Ok(DitherReply::Registered(app_sender, event_receiver, dropped))
+	}
+	/// Send `event` to the registered application it belongs to, everything else goes to the node's `ThreadHandle`
+	/// Events about a connection or send go to the application of the connection
+	fn route_event(&mut self, event: DitherEvent) {
+		let application = match &event {
+			DitherEvent::ReceivedData(application, _) => Some(self.topics.get(application.tag()).unwrap_or(application).clone()),
+			DitherEvent::Connected(connection) | DitherEvent::IncomingConnection(connection) => {
+				self.connection_apps.insert(connection.id(), connection.application().clone());
+				Some(connection.application().clone())
+			},
+			DitherEvent::SendQueued(id, _) | DitherEvent::ConnectionUpgraded(id, _) | DitherEvent::UpgradeFailed(id, _) => self.connection_apps.get(id).cloned(),
+			DitherEvent::ConnectionClosed(id, _) => self.connection_apps.remove(id),
+			DitherEvent::SendResult(send_id, _) => self.send_apps.remove(send_id),
+			_ => None,
+		};
+		// Forget topics of applications that are gone
+		let outbox = &self.outbox;
+		self.topics.retain(|_, owner| outbox.is_registered(owner));
+		self.outbox.push(application.as_ref(), event);
+	}
+	/// `origin` is the registered application the request came through, `None` for the node's own `ThreadHandle`
+	fn parse_dither_action(&mut self, action: DitherAction, origin: Option<&Application>, reply: &mut Option<ReplySender>) -> Result<(), DitherError> {
 		match action {
-			/// Create new user, `User` and `NetworkKey` object will be sent back to the application that requested a new user
-			CreateUser(),
-			Bootstrap(Multiaddr),
-			Discover(UserId),
-			Authenticate(UserId, UserToken),
-			Connect(UserId, Application),
-			SendData(UserConnection, Vec<u8>),
-		
-			DitherAction::PrintListening => {
-				for addr in Swarm::listeners(&self.swarm) {
-					log::info!("Listening on: {:?}", addr);
+			DitherAction::CreateUser() | DitherAction::CreateHiddenUser() => {
+				let key = NetworkKey::new();
+				let user = match action {
+					DitherAction::CreateHiddenUser() => self.hidden_user(&key)?,
+					_ => {
+						let mut user = User::new(&key, self.peer_id.clone());
+						user.set_relay_addresses(self.relay_addresses.values().cloned().collect());
+						user
+					},
+				};
+				log::info!("Created User: {:?}", user.id());
+				self.publish_local_user(&user, &key)?;
+				self.swarm.connections.add_local_user(user.id().clone());
+				self.users.insert(user.id().clone(), user);
+				self.user_keys.insert(key.id().clone(), key.clone());
+				let reply_key = key.clone();
+				self.respond(reply.take(), Ok(DitherReply::UserCreated(reply_key.id().clone(), reply_key)), DitherEvent::UserCreated(key.id().clone(), key), origin);
+			},
+			DitherAction::Bootstrap(addr) => self.bootstrap(addr, reply)?,
+			DitherAction::Discover(user_id) => {
+				if let Some(user) = self.users.get(&user_id).cloned() {
+					self.respond(reply.take(), Ok(DitherReply::UserDiscovered(user.clone())), DitherEvent::UserDiscovered(user), origin);
+				} else {
+					if let Some(reply) = reply.take() {
+						self.pending_discovers.entry(user_id.clone()).or_default().push(reply);
+					}
+					self.swarm.discover_user(user_id);
+				}
+			},
+			DitherAction::Connect(user_id, application) => self.connect_to(user_id, application, false, reply, origin)?,
+			DitherAction::ConnectAnonymously(user_id, application) => self.connect_to(user_id, application, true, reply, origin)?,
+			DitherAction::SendData(connection, data) => {
+				let send_id = self.swarm.send_data(&connection, data);
+				match reply.take() {
+					Some(reply) => { self.pending_sends.insert(send_id, reply); },
+					None => { self.send_apps.insert(send_id, connection.application().clone()); },
+				}
+			},
+			DitherAction::Accept(application) => self.swarm.connections.accept(application),
+			DitherAction::Register(application) => {
+				let registered = self.register(application)?;
+				if let Some(reply) = reply.take() {
+					let _ = reply.send(Ok(registered));
+				}
+			},
+			DitherAction::PubSubSubscribe(topic) => {
+				if let Some(application) = origin {
+					self.topics.insert(topic.clone(), application.clone());
 				}
+				self.subscriptions.insert(topic.clone());
+				self.swarm.subscribe(Topic::new(topic))
+			},
+			DitherAction::PubSubUnsubscribe(topic) => {
+				self.topics.remove(&topic);
+				self.subscriptions.remove(&topic);
+				self.swarm.unsubscribe(Topic::new(topic))
+			},
+			DitherAction::PubSubBroadcast(topic, data) => self.swarm.broadcast(Topic::new(topic), data),
+			DitherAction::GetListenAddresses => {
+				let addresses = Swarm::listeners(&self.swarm).cloned().collect();
+				Self::answer_query(reply, DitherReply::ListenAddresses(addresses));
+			},
+			DitherAction::GetPeers => Self::answer_query(reply, DitherReply::Peers(self.connected.values().cloned().collect())),
+			DitherAction::GetLocalUsers => Self::answer_query(reply, DitherReply::LocalUsers(self.user_keys.keys().cloned().collect())),
+			DitherAction::GetRoutes => {
+				let routes = self.router.table().routes().map(|(peers, measurement)| (peers.clone(), measurement.clone())).collect();
+				Self::answer_query(reply, DitherReply::Routes(routes));
 			},
-			//_ => { log::error!("Unimplemented DitherAction: {:?}", action) },
+			DitherAction::GetSubscriptions => {
+				let subscriptions = self.subscriptions.iter().map(|topic| (topic.clone(), self.topics.get(topic).cloned())).collect();
+				Self::answer_query(reply, DitherReply::Subscriptions(subscriptions));
+			},
+			_ => {
+				log::error!("Unimplemented DitherAction: {:?}", action);
+				return Err(DitherError::Unsupported(format!("{:?}", action)));
+			},
+		}
+		// Actions without a specific result are done once parsed
+		if let Some(reply) = reply.take() {
+			let _ = reply.send(Ok(DitherReply::Done));
+		}
+		Ok(())
+	}
+	/// Share the links this node measured once `ADVERTISE_INTERVAL` passed since the last time
+	fn advertise_links(&mut self) {
+		let now = Instant::now();
+		if self.links_advertised.map_or(false, |at| now.duration_since(at) < behaviour::ADVERTISE_INTERVAL) { return }
+		self.links_advertised = Some(now);
+		let me = &self.peer_id;
+		let links: Vec<(PeerId, u32, u32)> = self.router.table().routes()
+			.filter(|((from, _), measurement)| from == me && !measurement.is_stale(now))
+			.map(|((_, to), measurement)| (to.clone(), measurement.latency().round() as u32, measurement.bandwidth()))
+			.collect();
+		self.swarm.advertise_links(&links);
+	}
+	/// Queries only make sense with someone waiting for the answer
+	fn answer_query(reply: &mut Option<ReplySender>, answer: DitherReply) {
+		match reply.take() {
+			Some(reply) => { let _ = reply.send(Ok(answer)); },
+			None => log::warn!("Query sent without a reply channel: {:?}", answer),
+		}
+	}
+	/// Connect to `user_id` now if it is known, or once it was discovered
+	fn connect_to(&mut self, user_id: UserId, application: Application, anonymous: bool, reply: &mut Option<ReplySender>, origin: Option<&Application>) -> Result<(), DitherError> {
+		if let Some(user) = self.users.get(&user_id).cloned() {
+			let connection = self.connect_user(&user, application, anonymous)?;
+			self.connection_apps.insert(connection.id(), connection.application().clone());
+			self.respond(reply.take(), Ok(DitherReply::Connected(connection.clone())), DitherEvent::Connected(connection), origin);
+		} else {
+			self.pending_connects.entry(user_id.clone()).or_default().push((application, anonymous, reply.take()));
+			self.swarm.discover_user(user_id);
 		}
 		Ok(())
 	}
-	pub fn start(mut self) -> ThreadHandle<(), DitherAction, DitherEvent> {
+	/// Keep circuits to introduction points for a new hidden user, returns the user publishing them
+	fn hidden_user(&mut self, key: &NetworkKey) -> Result<User, DitherError> {
+		let candidates: Vec<PeerId> = self.connected.keys().cloned().collect();
+		let mut circuits = Vec::new();
+		for point in self.router.cheapest_peers(&candidates, self.config.introduction_points) {
+			if let Some(circuit) = self.introduction_circuit(key.id(), &point) {
+				circuits.push((point, circuit));
+			}
+		}
+		if circuits.is_empty() {
+			return Err(DitherError::Circuit("No peers to use as introduction points".to_owned()));
+		}
+		self.swarm.onion.add_hidden_user(key.clone());
+		let user = User::new_hidden(key, circuits.iter().map(|(point, _)| point.clone()).collect());
+		self.introductions.insert(key.id().clone(), circuits);
+		Ok(user)
+	}
+	/// Circuit making `point` an introduction point of hidden `user_id`, `None` if there are no peers to relay it
+	fn introduction_circuit(&mut self, user_id: &UserId, point: &PeerId) -> Option<CircuitId> {
+		let candidates: Vec<PeerId> = self.connected.keys().cloned().collect();
+		// The introduction point must not learn which node hosts the user
+		let mut path = self.router.circuit_relays(point, &candidates, self.config.circuit_hops);
+		if path.is_empty() { return None }
+		path.push(point.clone());
+		Some(self.swarm.onion.establish_introduction(user_id.clone(), path))
+	}
+	/// Replace a lost introduction point of a hidden user and publish its definition again with the points it has now
+	fn introduction_lost(&mut self, user_id: UserId, lost: PeerId) -> Result<(), DitherError> {
+		let mut circuits = match self.introductions.remove(&user_id) {
+			Some(circuits) => circuits,
+			None => return Ok(()),
+		};
+		circuits.retain(|(point, _)| *point != lost);
+		let candidates: Vec<PeerId> = self.connected.keys()
+			.filter(|peer| !circuits.iter().any(|(point, _)| point == *peer))
+			.cloned().collect();
+		for point in self.router.cheapest_peers(&candidates, self.config.introduction_points.saturating_sub(circuits.len())) {
+			if let Some(circuit) = self.introduction_circuit(&user_id, &point) {
+				circuits.push((point, circuit));
+			}
+		}
+		let points: Vec<PeerId> = circuits.iter().map(|(point, _)| point.clone()).collect();
+		let live: Vec<CircuitId> = circuits.iter().map(|(_, circuit)| *circuit).collect();
+		self.introductions.insert(user_id.clone(), circuits);
+		// Without points the definition would look like a user without nodes, the last one published stays up
+		if points.is_empty() {
+			return Err(DitherError::Circuit(format!("No introduction points left for hidden user {:?}", user_id)));
+		}
+		log::info!("Introduction points of hidden user {:?} are now {:?}", user_id, points);
+		if let (Some(user), Some(key)) = (self.users.get_mut(&user_id), self.user_keys.get(&user_id)) {
+			user.set_introduction_points(points);
+			self.swarm.publish_hidden_user(user, key, &live)?;
+		}
+		Ok(())
+	}
+	/// Publish the definition of a user on this node, hidden users through their introduction points so no record names this node
+	fn publish_local_user(&mut self, user: &User, key: &NetworkKey) -> Result<(), DitherError> {
+		match self.introductions.get(user.id()) {
+			Some(circuits) => {
+				let circuits: Vec<CircuitId> = circuits.iter().map(|(_, circuit)| *circuit).collect();
+				self.swarm.publish_hidden_user(user, key, &circuits)
+			},
+			None => self.swarm.publish_user(user, key),
+		}
+	}
+	/// Open `UserConnection` to one of the nodes hosting `user`, through a circuit if `anonymous`
+	/// Hidden users are always reached through a rendezvous point, nodes behind NAT through their relay
+	fn connect_user(&mut self, user: &User, application: Application, anonymous: bool) -> Result<UserConnection, DitherError> {
+		if user.is_hidden() {
+			return self.connect_hidden(user, application);
+		}
+		let node = user.user_nodes().iter().find(|node| **node != self.peer_id)
+			.ok_or_else(|| DitherError::UnknownUser(user.id().clone()))?;
+		// A node we are already connected to does not need its relay
+		let relayed = if self.connected.contains_key(node) { None } else {
+			user.relay_addresses().iter().filter_map(peers::split_relay_addr).find(|(_, _, target)| target == node)
+		};
+		if !anonymous && relayed.is_none() {
+			log::info!("Connecting to {:?} on {:?} for {:?}", user.id(), node, application);
+			return Ok(self.swarm.connections.connect(user.id().clone(), node.clone(), application));
+		}
+		let mut relays = Vec::new();
+		if anonymous {
+			let candidates: Vec<PeerId> = self.connected.keys().cloned().collect();
+			relays = self.router.circuit_relays(node, &candidates, self.config.circuit_hops);
+			if relays.is_empty() {
+				return Err(DitherError::Circuit("No peers to relay the circuit through".to_owned()));
+			}
+		}
+		if let Some((relay_addr, relay, _)) = relayed {
+			self.swarm.add_address(&relay, relay_addr);
+			relays.retain(|peer| *peer != relay);
+			relays.push(relay);
+		}
+		log::info!("Connecting to {:?} on {:?} for {:?} through {} relays", user.id(), node, application, relays.len());
+		let connection = self.swarm.connect_circuit(user.id().clone(), node.clone(), application, relays);
+		// Anonymous connections must not tell the node where we are
+		if let (false, Some(circuit)) = (anonymous, connection.circuit()) {
+			self.swarm.upgrade_circuit(circuit, node.clone());
+		}
+		Ok(connection)
+	}
+	/// Publish the definitions of local users again with the current relay addresses of this node
+	/// Hidden users never publish where their node is
+	fn publish_relay_addresses(&mut self) -> Result<(), DitherError> {
+		let addresses: Vec<Multiaddr> = self.relay_addresses.values().cloned().collect();
+		for (user_id, key) in &self.user_keys {
+			if let Some(user) = self.users.get_mut(user_id) {
+				if user.is_hidden() { continue }
+				user.set_relay_addresses(addresses.clone());
+				self.swarm.publish_user(user, key)?;
+			}
+		}
+		Ok(())
+	}
+	/// Meet hidden `user` at a rendezvous point, the introduction goes to its cheapest introduction point
+	fn connect_hidden(&mut self, user: &User, application: Application) -> Result<UserConnection, DitherError> {
+		let intro = self.router.cheapest_peers(user.introduction_points(), 1).pop()
+			.ok_or_else(|| DitherError::UnknownUser(user.id().clone()))?;
+		let candidates: Vec<PeerId> = self.connected.keys().filter(|peer| **peer != intro).cloned().collect();
+		let rendezvous = self.router.cheapest_peers(&candidates, 1).pop()
+			.ok_or_else(|| DitherError::Circuit("No peer to use as rendezvous point".to_owned()))?;
+		let relays = self.router.circuit_relays(&rendezvous, &candidates, self.config.circuit_hops);
+		let mut intro_path = self.router.circuit_relays(&intro, &candidates, self.config.circuit_hops);
+		if relays.is_empty() || intro_path.is_empty() {
+			return Err(DitherError::Circuit("No peers to relay the circuit through".to_owned()));
+		}
+		intro_path.push(intro);
+		log::info!("Connecting to hidden user {:?} at {:?} for {:?}", user.id(), rendezvous, application);
+		Ok(self.swarm.connect_hidden(user, application, rendezvous, relays, intro_path))
+	}
+	/// Answer callers waiting on `event`, returns the event if nobody was waiting for it
+	fn parse_behaviour_event(&mut self, event: DitherEvent) -> Result<Option<DitherEvent>, DitherError> {
+		match event {
+			DitherEvent::UserDiscovered(user) => {
+				self.users.insert(user.id().clone(), user.clone());
+				let discovers = self.pending_discovers.remove(user.id()).unwrap_or_default();
+				let connects = self.pending_connects.remove(user.id()).unwrap_or_default();
+				let answered = !discovers.is_empty() || connects.iter().any(|(_, _, reply)| reply.is_some());
+				for reply in discovers {
+					let _ = reply.send(Ok(DitherReply::UserDiscovered(user.clone())));
+				}
+				for (application, anonymous, reply) in connects {
+					match (self.connect_user(&user, application.clone(), anonymous), reply) {
+						(Ok(connection), Some(reply)) => {
+							self.connection_apps.insert(connection.id(), application);
+							let _ = reply.send(Ok(DitherReply::Connected(connection)));
+						},
+						(Ok(connection), None) => self.route_event(DitherEvent::Connected(connection)),
+						(Err(err), Some(reply)) => { let _ = reply.send(Err(err)); },
+						(Err(err), None) => self.outbox.push(Some(&application), DitherEvent::Error(err)),
+					}
+				}
+				Ok(if answered { None } else { Some(DitherEvent::UserDiscovered(user)) })
+			},
+			DitherEvent::UserNotFound(user_id) => {
+				let discovers = self.pending_discovers.remove(&user_id).unwrap_or_default();
+				let connects = self.pending_connects.remove(&user_id).unwrap_or_default();
+				let answered = !discovers.is_empty() || connects.iter().any(|(_, _, reply)| reply.is_some());
+				let replies = discovers.into_iter().chain(connects.into_iter().filter_map(|(_, _, reply)| reply));
+				for reply in replies {
+					let _ = reply.send(Err(DitherError::UnknownUser(user_id.clone())));
+				}
+				Ok(if answered { None } else { Some(DitherEvent::UserNotFound(user_id)) })
+			},
+			DitherEvent::SendResult(send_id, result) => match self.pending_sends.remove(&send_id) {
+				Some(reply) => {
+					let _ = reply.send(result.map(|()| DitherReply::Delivered).map_err(DitherError::SendFailed));
+					Ok(None)
+				},
+				None => Ok(Some(DitherEvent::SendResult(send_id, result))),
+			},
+			DitherEvent::PingEvent(event) => {
+				if let Ok(PingSuccess::Ping { rtt }) = &event.result {
+					self.router.table_mut().record_latency(event.peer.clone(), *rtt);
+				}
+				self.router.table_mut().expire(Instant::now());
+				self.advertise_links();
+				Ok(Some(DitherEvent::PingEvent(event)))
+			},
+			DitherEvent::BandwidthMeasured(peer_id, sample) => {
+				self.router.table_mut().record_bandwidth(&peer_id, sample);
+				Ok(Some(DitherEvent::BandwidthMeasured(peer_id, sample)))
+			},
+			DitherEvent::Identified(peer_id, info) => {
+				if let Some(peer) = self.connected.get_mut(&peer_id) {
+					peer.listen_addresses = info.listen_addrs.clone();
+					peer.protocols = info.protocols.clone();
+					peer.agent_version = Some(info.agent_version.clone());
+				}
+				let relays = info.protocols.iter().any(|protocol| protocol.as_bytes() == behaviour::RELAY_PROTOCOL);
+				self.router.set_relays(peer_id.clone(), relays);
+				let gives_slots = info.protocols.iter().any(|protocol| protocol.as_bytes() == behaviour::RESERVATION_PROTOCOL);
+				let nat_relay = &self.config.nat_relay;
+				if nat_relay.reserve && nat_relay.reserve_identified && gives_slots && !self.swarm.reserves_on(&peer_id) {
+					self.swarm.reserve(peer_id.clone());
+				}
+				Ok(Some(DitherEvent::Identified(peer_id, info)))
+			},
+			DitherEvent::LinksAdvertised(node, links) => {
+				// Our own links are measured, not taken from what others say about them
+				if node != self.peer_id {
+					// Only nodes that relay for anyone advertise their links, the signature shows it came from the node itself
+					self.router.set_relays(node.clone(), true);
+					for (peer, latency, bandwidth) in links.into_iter().filter(|(peer, _, _)| *peer != node) {
+						self.router.table_mut().add_route(node.clone(), peer, RouteMeasurement::new(latency as f32, bandwidth, None));
+					}
+				}
+				Ok(None)
+			},
+			DitherEvent::IntroductionLost(user_id, point) => {
+				self.introduction_lost(user_id, point)?;
+				Ok(None)
+			},
+			DitherEvent::Introduced(introduction, user_id, rendezvous) => {
+				let candidates: Vec<PeerId> = self.connected.keys().cloned().collect();
+				let relays = self.router.circuit_relays(&rendezvous, &candidates, self.config.circuit_hops);
+				if relays.is_empty() {
+					log::warn!("No peers to reach rendezvous point {:?} for {:?} through", rendezvous, user_id);
+					self.swarm.onion.decline_introduction(introduction);
+				} else {
+					self.swarm.onion.accept_introduction(introduction, relays);
+				}
+				Ok(None)
+			},
+			DitherEvent::RelayReserved(relay) => {
+				// The bootstrap address is the one other nodes can reach the relay on, other relays are reached where they listen
+				let address = self.peers.peers().iter()
+					.find(|addr| peers::split_peer_addr((*addr).clone()).map_or(false, |(_, peer)| peer == relay))
+					.cloned()
+					.or_else(|| self.connected.get(&relay)
+						.and_then(|peer| peer.listen_addresses.iter().find(|addr| peers::is_public(addr)))
+						.map(|addr| addr.clone().with(Protocol::P2p(relay.clone().into()))))
+					.map(|addr| peers::relay_addr(&addr, &self.peer_id));
+				match address {
+					Some(address) => {
+						log::info!("Reachable through {:?}", address);
+						self.relay_addresses.insert(relay.clone(), address);
+						self.publish_relay_addresses()?;
+					},
+					None => log::warn!("No known address of relay {:?}", relay),
+				}
+				Ok(Some(DitherEvent::RelayReserved(relay)))
+			},
+			DitherEvent::RelayLost(relay, reason) => {
+				if self.relay_addresses.remove(&relay).is_some() {
+					self.publish_relay_addresses()?;
+				}
+				Ok(Some(DitherEvent::RelayLost(relay, reason)))
+			},
+			// Callers waiting on a reply already know the id
+			DitherEvent::SendQueued(_, send_id) if self.pending_sends.contains_key(&send_id) => Ok(None),
+			event => Ok(Some(event)),
+		}
+	}
+	fn parse_swarm_event<E: fmt::Debug>(&mut self, event: SwarmEvent<DitherEvent, E>) -> Result<(), DitherError> {
+		match event {
+			SwarmEvent::Behaviour(event) => {
+				// When Receive Event, send to receiver thread
+				log::info!("New Event: {:?}", event);
+				if let Some(event) = self.parse_behaviour_event(event)? {
+					self.route_event(event);
+				}
+			},
+			SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established } => {
+				if let ConnectedPoint::Dialer { address } = &endpoint {
+					if !self.bootstrap_connected(&peer_id, address)? { return Ok(()) }
+				}
+				self.connected.entry(peer_id.clone()).or_insert_with(|| PeerInfo::new(peer_id.clone()))
+					.connections.push(endpoint.get_remote_address().clone());
+				self.swarm.peer_connected(&peer_id);
+				// Only the first connection to a peer changes whether we are connected to it
+				if num_established.get() == 1 {
+					log::info!("Connected to {:?} via {:?}", peer_id, endpoint);
+					self.outbox.push(None, DitherEvent::PeerConnected(peer_id, endpoint));
+				}
+			},
+			SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, cause } => {
+				if let Some(info) = self.connected.get_mut(&peer_id) {
+					info.connections.retain(|addr| addr != endpoint.get_remote_address());
+				}
+				// Peers banned while connecting were never reported as connected
+				if num_established == 0 && self.connected.remove(&peer_id).is_some() {
+					log::info!("Disconnected from {:?} via {:?}: {:?}", peer_id, endpoint, cause);
+					self.outbox.push(None, DitherEvent::PeerDisconnected(peer_id, endpoint));
+				}
+			},
+			SwarmEvent::NewListenAddr(addr) => {
+				log::info!("Listening on: {:?}", addr);
+				self.outbox.push(None, DitherEvent::NewListenAddr(addr));
+			},
+			SwarmEvent::ExpiredListenAddr(addr) => {
+				log::info!("No longer listening on: {:?}", addr);
+				self.outbox.push(None, DitherEvent::ExpiredListenAddr(addr));
+			},
+			SwarmEvent::ListenerError { error } => log::error!("Listener error: {:?}", error),
+			SwarmEvent::UnreachableAddr { peer_id, address, error, attempts_remaining } => {
+				if attempts_remaining == 0 {
+					self.swarm.direct_dial_failed(&peer_id);
+				}
+				self.dial_failed(Some(peer_id), address, error.to_string());
+			},
+			SwarmEvent::UnknownPeerUnreachableAddr { address, error } => self.dial_failed(None, address, error.to_string()),
+			_ => log::debug!("Swarm Event: {:?}", event),
+		}
+		Ok(())
+	}
+	/// Verify `peer_id` if `address` was dialed as a bootstrap, returns false if the peer was banned for answering in its place
+	fn bootstrap_connected(&mut self, peer_id: &PeerId, address: &Multiaddr) -> Result<bool, DitherError> {
+		if let Some((expected, addr, reply)) = self.bootstraps.remove(address) {
+			// Noise has authenticated `peer_id`, so a mismatch means someone else answered on this address
+			if *peer_id != expected {
+				log::error!("Bootstrap {:?} answered as {:?}, expected {:?}", address, peer_id, expected);
+				Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+				let err = || DitherError::AuthFailure(format!("Bootstrap {} answered as {}", addr, peer_id));
+				self.respond(reply, Err(err()), DitherEvent::BootstrapFailed(addr.clone(), err()), None);
+				return Ok(false);
+			}
+			self.swarm.add_peer(peer_id.clone());
+			self.swarm.add_address(peer_id, address.clone());
+			// Bootstrap nodes are trusted like friends, `RelayDestinations::Friends` relays to them
+			self.swarm.onion.add_friend(peer_id.clone());
+			if self.config.nat_relay.reserve {
+				self.swarm.reserve(peer_id.clone());
+			}
+			self.swarm.kademlia.bootstrap()?;
+			if self.peers.insert(addr.clone()) {
+				self.peers.save()?;
+			}
+			self.respond(reply, Ok(DitherReply::Bootstrapped(peer_id.clone())), DitherEvent::Bootstrapped(peer_id.clone(), addr), None);
+		}
+		Ok(true)
+	}
+	fn dial_failed(&mut self, peer_id: Option<PeerId>, address: Multiaddr, reason: String) {
+		log::warn!("Failed to dial {:?} at {:?}: {}", peer_id, address, reason);
+		self.bootstrap_failed(address.clone(), reason.clone());
+		self.outbox.push(None, DitherEvent::DialFailed(peer_id, address, DitherError::Transport(reason)));
+	}
+	fn bootstrap_failed(&mut self, address: Multiaddr, reason: String) {
+		if let Some((_, addr, reply)) = self.bootstraps.remove(&address) {
+			log::warn!("Failed to bootstrap with {:?}: {}", addr, reason);
+			self.respond(reply, Err(DitherError::Transport(reason.clone())), DitherEvent::BootstrapFailed(addr, DitherError::Transport(reason)), None);
+		}
+	}
+	/// Stop listeners, close connections and write persistent state
+	async fn shutdown(&mut self) -> Result<(), DitherError> {
+		log::info!("Shutting down");
+		for listener in self.listeners.drain(..) {
+			let _ = Swarm::remove_listener(&mut self.swarm, listener);
+		}
+		self.swarm.connections.close_all();
+		self.swarm.onion.close_all();
+		self.swarm.release_relays();
+		// Let the swarm send the close frames, events at this point have nobody left to handle them
+		let swarm = &mut self.swarm;
+		let _ = tokio::time::timeout(SHUTDOWN_FLUSH, async move {
+			loop {
+				let event = swarm.next_event().await;
+				log::debug!("Swarm Event during shutdown: {:?}", event);
+			}
+		}).await;
+		self.peers.save()
+	}
+	/// Run the node, the returned `join` resolves once the node stopped and its state was saved
+	pub fn start(mut self) -> ThreadHandle<Result<StopReason, DitherError>, DitherRequest, DitherEvent> {
 		// Listen for
-		let (outer_sender, mut receiver) = mpsc::channel(64);
-		let (mut sender, outer_receiver) = mpsc::channel(64);
-		
-		//let self_sender = outer_sender.clone();
+		let (outer_sender, mut receiver) = mpsc::channel(self.config.event_buffer);
+		let outer_receiver = self.events.take().expect("Node is only started once");
+		let dropped = self.outbox.dropped();
+		let (app_requests, mut app_receiver) = mpsc::channel(self.config.event_buffer);
+		self.app_requests = Some(app_requests);
 		
 		// Receiver thread
 		let join = tokio::spawn(async move {
-			loop {
-				let potential_action = {
+			let (reason, shutdown_reply) = loop {
+				let potential_request: Option<(Option<Application>, DitherRequest)> = {
 					tokio::select! {
 						// Await Actions from Higher Layers
-						received_action = receiver.recv() => {
-							if received_action.is_none() {
+						received_request = receiver.recv() => {
+							if received_request.is_none() {
 								log::info!("All Senders Closed, Stopping...");
-								break;
+								break (StopReason::SendersClosed, None);
 							}
-							received_action
+							received_request.map(|request| (None, request))
 						},
-						// Await events from swarm
-						event = self.swarm.next() => {
-							// When Receive Event, send to receiver thread
-							log::info!("New Event: {:?}", event);
-							if let Err(err) = sender.try_send(event) {
-								log::error!("Network Thread could not send event: {:?}", err);
+						// Await Actions from registered applications
+						Some((application, request)) = app_receiver.recv() => Some((Some(application), request)),
+						// Await events from swarm, unless the node's own receiver is behind and the policy says to wait for it
+						event = self.swarm.next_event(), if !self.outbox.is_full() => {
+							if let Err(err) = self.parse_swarm_event(event) {
+								log::error!("Network Thread could not handle swarm event: {:?}", err);
+								self.outbox.push(None, DitherEvent::Error(err));
 							}
 							None
 						}
+						// Hand queued events to receivers as they make room
+						_ = self.outbox.flush(), if !self.outbox.is_empty() => None,
 					}
 				};
-				if let Some(action) = potential_action {
-					log::info!("Network Action: {:?}", action);
-					if let Err(err) = self.parse_dither_action(action) {
+				if let Some((origin, DitherRequest { action, mut reply })) = potential_request {
+					if let DitherAction::Shutdown = action {
+						break (StopReason::Shutdown, reply);
+					}
+					log::info!("Network Action: {:?} from {:?}", action, origin);
+					// Results of an application's actions go back to that application
+					if let Err(err) = self.parse_dither_action(action, origin.as_ref(), &mut reply) {
 						log::error!("Failed to parse DitherAction: {:?}", err);
+						match reply {
+							Some(reply) => { let _ = reply.send(Err(err)); },
+							None => self.outbox.push(origin.as_ref(), DitherEvent::Error(err)),
+						}
 					}
 				}
+			};
+			let result = self.shutdown().await.map(|()| reason);
+			if let Some(reply) = shutdown_reply {
+				let _ = reply.send(match &result {
+					Ok(_) => Ok(DitherReply::Done),
+					Err(err) => Err(DitherError::TaskFailed(err.to_string())),
+				});
 			}
-			log::info!("Network Layer Ended");
+			log::info!("Network Layer Ended: {:?}", result);
+			result
 		});
-		ThreadHandle { join, sender: outer_sender, receiver: outer_receiver }
+		ThreadHandle { join, sender: outer_sender, receiver: outer_receiver, dropped }
 	}
 }
 
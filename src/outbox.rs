@@ -0,0 +1,201 @@
+// Event queues of the node's own `ThreadHandle` and of every registered application
+
+use std::{
+	collections::HashMap,
+	task::Poll,
+};
+use tokio::sync::mpsc::{self, Sender, Receiver};
+use futures::future;
+
+use crate::{
+	Application,
+	DitherEvent,
+	queue::{EventQueue, EventPolicy, DroppedEvents},
+};
+
+pub struct Outbox {
+	policy: EventPolicy,
+	capacity: usize,
+	node: EventQueue<DitherEvent>,
+	applications: HashMap<Application, EventQueue<DitherEvent>>,
+}
+
+impl Outbox {
+	pub fn new(sender: Sender<DitherEvent>, policy: EventPolicy, capacity: usize) -> Outbox {
+		Outbox {
+			policy,
+			capacity,
+			node: EventQueue::new(sender, policy, capacity, DitherEvent::coalesces),
+			applications: HashMap::new(),
+		}
+	}
+	pub fn dropped(&self) -> DroppedEvents { self.node.dropped() }
+	/// New event channel for `application`, replacing any previous one
+	pub fn register(&mut self, application: Application) -> (Receiver<DitherEvent>, DroppedEvents) {
+		let (sender, receiver) = mpsc::channel(self.capacity);
+		let queue = EventQueue::new(sender, self.policy, self.capacity, DitherEvent::coalesces);
+		let dropped = queue.dropped();
+		if self.applications.insert(application.clone(), queue).is_some() {
+			log::warn!("Application {:?} registered again, closing previous channels", application);
+		}
+		(receiver, dropped)
+	}
+	/// Registered and its receiver is still around
+	pub fn is_registered(&self, application: &Application) -> bool {
+		self.applications.get(application).map_or(false, |queue| !queue.is_closed())
+	}
+	/// Queue `event` for `application`, or for the node's `ThreadHandle` if `None` or not registered
+	/// An application that is behind only loses its own events, counted in its `DroppedEvents`
+	pub fn push(&mut self, application: Option<&Application>, event: DitherEvent) {
+		match application.and_then(|application| self.applications.get_mut(application)).filter(|queue| !queue.is_closed()) {
+			Some(queue) => if !queue.push(event) {
+				log::debug!("Application {:?} is not reading its events, dropped one", application);
+			},
+			None => if !self.node.push(event) {
+				log::debug!("ThreadHandle is not reading its events, dropped one");
+			},
+		}
+	}
+	/// The node's own receiver is behind and the policy says to wait for it
+	/// Applications never hold up the node, their events are refused instead
+	pub fn is_full(&self) -> bool {
+		self.node.is_full()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.node.is_empty() && self.applications.values().all(EventQueue::is_empty)
+	}
+	/// Move queued events into their channels, ready once every queue is empty
+	pub async fn flush(&mut self) {
+		let Outbox { node, applications, .. } = self;
+		future::poll_fn(|cx| {
+			let mut ready = node.poll_flush(cx).is_ready();
+			for queue in applications.values_mut() {
+				ready &= queue.poll_flush(cx).is_ready();
+			}
+			applications.retain(|application, queue| {
+				if queue.is_closed() { log::info!("Application {:?} closed its channels, unregistering", application) }
+				!queue.is_closed()
+			});
+			if ready { Poll::Ready(()) } else { Poll::Pending }
+		}).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use libp2p::{PeerId, ping::{PingEvent, PingSuccess}};
+
+	fn ping(peer: &PeerId) -> DitherEvent {
+		DitherEvent::PingEvent(PingEvent { peer: peer.clone(), result: Ok(PingSuccess::Pong) })
+	}
+	fn other() -> DitherEvent {
+		DitherEvent::Discovered(Vec::new())
+	}
+
+	#[tokio::test]
+	async fn events_go_to_their_application() {
+		let (sender, mut node) = mpsc::channel(4);
+		let mut outbox = Outbox::new(sender, EventPolicy::Block, 4);
+		let chat = Application::new("chat");
+		let (mut receiver, _) = outbox.register(chat.clone());
+		assert!(outbox.is_registered(&chat));
+		outbox.push(Some(&chat), other());
+		outbox.push(Some(&Application::new("unregistered")), ping(&PeerId::random()));
+		outbox.push(None, other());
+		assert!(matches!(receiver.try_recv(), Ok(DitherEvent::Discovered(_))));
+		assert!(receiver.try_recv().is_err());
+		assert!(matches!(node.try_recv(), Ok(DitherEvent::PingEvent(_))));
+		assert!(matches!(node.try_recv(), Ok(DitherEvent::Discovered(_))));
+	}
+
+	#[tokio::test]
+	async fn slow_application_only_loses_its_own_events() {
+		let (sender, mut node) = mpsc::channel(1);
+		let mut outbox = Outbox::new(sender, EventPolicy::Block, 1);
+		let chat = Application::new("chat");
+		let (mut receiver, dropped) = outbox.register(chat.clone());
+		for _ in 0..4 {
+			outbox.push(Some(&chat), other());
+		}
+		// The node keeps going while chat is behind
+		assert!(!outbox.is_full());
+		assert_eq!(dropped.count(), 2);
+		outbox.push(None, other());
+		assert!(matches!(node.try_recv(), Ok(DitherEvent::Discovered(_))));
+		assert!(receiver.recv().await.is_some());
+		outbox.flush().await;
+		assert!(outbox.is_empty());
+		assert!(receiver.recv().await.is_some());
+		assert!(receiver.try_recv().is_err());
+		assert_eq!(outbox.dropped().count(), 0);
+	}
+
+	#[tokio::test]
+	async fn block_waits_for_the_node() {
+		let (sender, mut node) = mpsc::channel(1);
+		let mut outbox = Outbox::new(sender, EventPolicy::Block, 1);
+		outbox.push(None, other());
+		outbox.push(None, other());
+		assert!(outbox.is_full());
+		assert!(node.recv().await.is_some());
+		outbox.flush().await;
+		assert!(!outbox.is_full() && outbox.is_empty());
+		assert!(node.recv().await.is_some());
+		assert_eq!(outbox.dropped().count(), 0);
+	}
+
+	#[tokio::test]
+	async fn drop_oldest_counts_per_channel() {
+		let (sender, _node) = mpsc::channel(1);
+		let mut outbox = Outbox::new(sender, EventPolicy::DropOldest, 1);
+		let chat = Application::new("chat");
+		let (_receiver, dropped) = outbox.register(chat.clone());
+		for _ in 0..4 {
+			outbox.push(Some(&chat), other());
+		}
+		assert!(!outbox.is_full());
+		assert_eq!(dropped.count(), 2);
+		assert_eq!(outbox.dropped().count(), 0);
+		outbox.push(None, other());
+		outbox.push(None, other());
+		outbox.push(None, other());
+		assert_eq!(outbox.dropped().count(), 1);
+		assert_eq!(dropped.count(), 2);
+	}
+
+	#[tokio::test]
+	async fn coalesce_replaces_pings_to_the_same_peer() {
+		let (sender, mut node) = mpsc::channel(1);
+		let mut outbox = Outbox::new(sender, EventPolicy::Coalesce, 1);
+		let (first, second) = (PeerId::random(), PeerId::random());
+		outbox.push(None, ping(&first));
+		outbox.push(None, ping(&first));
+		outbox.push(None, ping(&first));
+		assert_eq!(outbox.dropped().count(), 1);
+		// Nothing to replace a ping to another peer with
+		outbox.push(None, ping(&second));
+		assert_eq!(outbox.dropped().count(), 1);
+		assert!(outbox.is_full());
+		let mut peers = Vec::new();
+		future::join(outbox.flush(), async {
+			while peers.len() < 3 {
+				if let Some(DitherEvent::PingEvent(event)) = node.recv().await { peers.push(event.peer) }
+			}
+		}).await;
+		assert_eq!(peers, vec![first.clone(), first, second]);
+	}
+
+	#[tokio::test]
+	async fn closed_application_falls_back_to_node() {
+		let (sender, mut node) = mpsc::channel(4);
+		let mut outbox = Outbox::new(sender, EventPolicy::Block, 4);
+		let chat = Application::new("chat");
+		let (receiver, _) = outbox.register(chat.clone());
+		drop(receiver);
+		outbox.push(Some(&chat), other());
+		assert!(!outbox.is_registered(&chat));
+		outbox.push(Some(&chat), other());
+		assert!(matches!(node.try_recv(), Ok(DitherEvent::Discovered(_))));
+	}
+}
@@ -0,0 +1,106 @@
+// Bootstrap nodes we have successfully connected to before, kept across restarts
+
+use std::{
+	fs,
+	io,
+	path::PathBuf,
+};
+use libp2p::{
+	PeerId,
+	Multiaddr,
+	multiaddr::Protocol,
+};
+
+use crate::DitherError;
+
+/// Split `/ip4/../tcp/../p2p/<PeerId>` into the address to dial and the expected `PeerId`
+pub fn split_peer_addr(mut addr: Multiaddr) -> Option<(Multiaddr, PeerId)> {
+	match addr.pop() {
+		Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok().map(|peer| (addr, peer)),
+		_ => None,
+	}
+}
+
+/// Address that reaches `node` through the relay at `relay`, which ends with `/p2p/<PeerId>` of the relay
+pub fn relay_addr(relay: &Multiaddr, node: &PeerId) -> Multiaddr {
+	relay.clone().with(Protocol::P2pCircuit).with(Protocol::P2p(node.clone().into()))
+}
+
+/// Split `<relay>/p2p/<PeerId>/p2p-circuit/p2p/<PeerId>` into the address to dial the relay, the relay and the node behind it
+pub fn split_relay_addr(addr: &Multiaddr) -> Option<(Multiaddr, PeerId, PeerId)> {
+	let (mut relay, node) = split_peer_addr(addr.clone())?;
+	match relay.pop() {
+		Some(Protocol::P2pCircuit) => {},
+		_ => return None,
+	}
+	let (relay_addr, relay) = split_peer_addr(relay)?;
+	Some((relay_addr, relay, node))
+}
+
+/// Whether nodes on other networks could dial `addr`, private, loopback and circuit addresses only work nearby
+pub fn is_public(addr: &Multiaddr) -> bool {
+	addr.iter().all(|protocol| match protocol {
+		Protocol::Ip4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+		Protocol::Ip6(ip) => !(ip.is_loopback() || ip.is_unspecified()),
+		Protocol::P2pCircuit => false,
+		_ => true,
+	})
+}
+
+/// What is known about a connected peer, returned by `DitherAction::GetPeers`
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+	pub peer_id: PeerId,
+	/// Remote address of every open connection to the peer
+	pub connections: Vec<Multiaddr>,
+	/// Addresses and protocols are empty until the peer was identified
+	pub listen_addresses: Vec<Multiaddr>,
+	pub protocols: Vec<String>,
+	pub agent_version: Option<String>,
+}
+
+impl PeerInfo {
+	pub fn new(peer_id: PeerId) -> PeerInfo {
+		PeerInfo { peer_id, connections: Vec::new(), listen_addresses: Vec::new(), protocols: Vec::new(), agent_version: None }
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct PeerList {
+	/// File the list is stored in, list is kept in memory only if `None`
+	path: Option<PathBuf>,
+	/// Full addresses including the `/p2p/<PeerId>` component
+	peers: Vec<Multiaddr>,
+}
+
+impl PeerList {
+	/// Load list from `path`, starting empty if the file does not exist yet
+	pub fn load(path: Option<PathBuf>) -> Result<PeerList, DitherError> {
+		let peers = match &path {
+			Some(path) => match fs::read(path) {
+				Ok(data) => serde_json::from_slice(&data).map_err(|err| DitherError::Encoding(err.to_string()))?,
+				Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+				Err(err) => return Err(err.into()),
+			},
+			None => Vec::new(),
+		};
+		Ok(PeerList { path, peers })
+	}
+	pub fn peers(&self) -> &[Multiaddr] { &self.peers }
+	/// Remember bootstrap address, returns true if it was not known before
+	pub fn insert(&mut self, addr: Multiaddr) -> bool {
+		if self.peers.contains(&addr) { return false }
+		self.peers.push(addr);
+		true
+	}
+	/// Write list to disk
+	pub fn save(&self) -> Result<(), DitherError> {
+		if let Some(path) = &self.path {
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			fs::write(path, serde_json::to_vec_pretty(&self.peers).map_err(|err| DitherError::Encoding(err.to_string()))?)?;
+		}
+		Ok(())
+	}
+}
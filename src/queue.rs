@@ -0,0 +1,243 @@
+// Buffer between a producer that must not stall and a bounded channel to a consumer that may fall behind
+
+use std::{
+	collections::VecDeque,
+	sync::{Arc, atomic::{AtomicU64, Ordering}},
+	task::{Context, Poll},
+};
+use tokio::sync::mpsc::{self, Sender, Receiver, error::TrySendError};
+use futures::future;
+use serde_derive::{Serialize, Deserialize};
+
+/// What to do with events while the receiving side is not keeping up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventPolicy {
+	/// Refuse new events until there is room again, producers that can wait (e.g. the node polling the swarm for its own `ThreadHandle`) stop until then
+	Block,
+	/// Drop the oldest queued event to make room
+	DropOldest,
+	/// Replace a queued event that carries the same kind of information (e.g. a newer ping to the same peer), refuse like `Block` otherwise
+	Coalesce,
+}
+impl Default for EventPolicy {
+	fn default() -> EventPolicy { EventPolicy::Block }
+}
+
+/// Number of events lost on a channel, shared between the queue and whoever reads the channel
+#[derive(Debug, Clone, Default)]
+pub struct DroppedEvents(Arc<AtomicU64>);
+impl DroppedEvents {
+	pub fn count(&self) -> u64 { self.0.load(Ordering::Relaxed) }
+	fn add(&self, count: u64) { self.0.fetch_add(count, Ordering::Relaxed); }
+}
+
+/// Events waiting for room in `sender`
+pub struct EventQueue<T> {
+	sender: Sender<T>,
+	queue: VecDeque<T>,
+	policy: EventPolicy,
+	capacity: usize,
+	/// Whether the newer of two events can replace the older one
+	coalesce: fn(&T, &T) -> bool,
+	dropped: DroppedEvents,
+	closed: bool,
+}
+
+impl<T> EventQueue<T> {
+	pub fn new(sender: Sender<T>, policy: EventPolicy, capacity: usize, coalesce: fn(&T, &T) -> bool) -> EventQueue<T> {
+		EventQueue {
+			sender,
+			queue: VecDeque::new(),
+			policy,
+			capacity,
+			coalesce,
+			dropped: DroppedEvents::default(),
+			closed: false,
+		}
+	}
+	pub fn dropped(&self) -> DroppedEvents { self.dropped.clone() }
+	/// Receiver was dropped, events pushed from now on are discarded
+	pub fn is_closed(&self) -> bool { self.closed }
+	pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+	/// The producer should wait for `flush` before pushing more events
+	pub fn is_full(&self) -> bool {
+		self.policy != EventPolicy::DropOldest && self.queue.len() >= self.capacity
+	}
+	/// Send `event` now if there is room, otherwise queue it according to the policy
+	/// Returns false if the queue is full and the policy refused `event`, it is counted as dropped then
+	pub fn push(&mut self, event: T) -> bool {
+		if self.closed { return true }
+		let event = if self.queue.is_empty() {
+			match self.sender.try_send(event) {
+				Ok(()) => return true,
+				Err(TrySendError::Full(event)) => event,
+				Err(TrySendError::Closed(_)) => { self.closed = true; return true },
+			}
+		} else { event };
+		if self.queue.len() >= self.capacity {
+			match self.policy {
+				EventPolicy::Block => {
+					self.dropped.add(1);
+					return false;
+				},
+				EventPolicy::DropOldest => {
+					self.queue.pop_front();
+					self.dropped.add(1);
+				},
+				EventPolicy::Coalesce => {
+					let coalesce = self.coalesce;
+					let replaced = match self.queue.iter_mut().find(|queued| coalesce(queued, &event)) {
+						Some(queued) => { *queued = event; true },
+						None => false,
+					};
+					self.dropped.add(1);
+					return replaced;
+				},
+			}
+		}
+		self.queue.push_back(event);
+		true
+	}
+	/// Move queued events into the channel, ready once the queue is empty or the receiver is gone
+	pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		while !self.queue.is_empty() {
+			match self.sender.poll_ready(cx) {
+				Poll::Ready(Ok(())) => {
+					let event = self.queue.pop_front().expect("Queue is not empty");
+					if self.sender.try_send(event).is_err() { self.close() }
+				},
+				Poll::Ready(Err(_)) => self.close(),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+		Poll::Ready(())
+	}
+	pub async fn flush(&mut self) {
+		future::poll_fn(|cx| self.poll_flush(cx)).await
+	}
+	fn close(&mut self) {
+		self.closed = true;
+		self.dropped.add(self.queue.len() as u64);
+		self.queue.clear();
+	}
+}
+
+/// Channel applying `policy` once `capacity` events are waiting for the receiver
+/// The returned sender only blocks under `EventPolicy::Block` (or `Coalesce` with nothing to coalesce), nothing is refused
+pub fn channel<T: Send + 'static>(policy: EventPolicy, capacity: usize, coalesce: fn(&T, &T) -> bool) -> (Sender<T>, Receiver<T>, DroppedEvents) {
+	let (sender, mut input) = mpsc::channel(capacity);
+	let (output, receiver) = mpsc::channel(capacity);
+	let mut queue = EventQueue::new(output, policy, capacity, coalesce);
+	let dropped = queue.dropped();
+	tokio::spawn(async move {
+		loop {
+			tokio::select! {
+				event = input.recv(), if !queue.is_full() => match event {
+					Some(event) => { queue.push(event); },
+					None => break,
+				},
+				_ = queue.flush(), if !queue.is_empty() => {},
+			}
+			if queue.is_closed() { return }
+		}
+		// Deliver what is left once every sender is gone
+		queue.flush().await;
+	});
+	(sender, receiver, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Events of the same kind end in the same digit
+	fn same_kind(queued: &u32, newer: &u32) -> bool { queued % 10 == newer % 10 }
+
+	/// Flush `queue` while reading `count` events from `receiver`
+	async fn drain(queue: &mut EventQueue<u32>, receiver: &mut Receiver<u32>, count: usize) -> Vec<u32> {
+		let mut received = Vec::new();
+		future::join(queue.flush(), async {
+			while received.len() < count {
+				received.push(receiver.recv().await.expect("Sender is kept by the queue"));
+			}
+		}).await;
+		received
+	}
+
+	#[tokio::test]
+	async fn block_refuses_past_capacity() {
+		let (sender, mut receiver) = mpsc::channel(1);
+		let mut queue = EventQueue::new(sender, EventPolicy::Block, 2, same_kind);
+		assert!(queue.push(0));
+		assert!(queue.push(1));
+		assert!(!queue.is_full());
+		assert!(queue.push(2));
+		assert!(queue.is_full());
+		// Producers that push anyway do not grow the queue
+		assert!(!queue.push(3));
+		assert_eq!(queue.dropped().count(), 1);
+		assert_eq!(drain(&mut queue, &mut receiver, 3).await, vec![0, 1, 2]);
+		assert!(queue.is_empty() && !queue.is_full());
+		assert!(queue.push(4));
+		assert_eq!(queue.dropped().count(), 1);
+	}
+
+	#[tokio::test]
+	async fn drop_oldest_counts_dropped_events() {
+		let (sender, mut receiver) = mpsc::channel(1);
+		let mut queue = EventQueue::new(sender, EventPolicy::DropOldest, 2, same_kind);
+		let dropped = queue.dropped();
+		for event in 0..5 {
+			queue.push(event);
+			assert!(!queue.is_full());
+		}
+		assert_eq!(dropped.count(), 2);
+		assert_eq!(drain(&mut queue, &mut receiver, 3).await, vec![0, 3, 4]);
+	}
+
+	#[tokio::test]
+	async fn coalesce_replaces_events_of_the_same_kind() {
+		let (sender, mut receiver) = mpsc::channel(1);
+		let mut queue = EventQueue::new(sender, EventPolicy::Coalesce, 2, same_kind);
+		let dropped = queue.dropped();
+		for event in &[0, 1, 2, 11] {
+			queue.push(*event);
+		}
+		assert_eq!(dropped.count(), 1);
+		assert!(queue.is_full());
+		// Nothing to replace, so it is refused like under `Block`
+		assert!(!queue.push(3));
+		assert_eq!(dropped.count(), 2);
+		assert_eq!(drain(&mut queue, &mut receiver, 3).await, vec![0, 11, 2]);
+	}
+
+	#[tokio::test]
+	async fn queued_events_count_as_dropped_when_receiver_is_gone() {
+		let (sender, receiver) = mpsc::channel(1);
+		let mut queue = EventQueue::new(sender, EventPolicy::Block, 4, same_kind);
+		for event in 0..3 {
+			queue.push(event);
+		}
+		drop(receiver);
+		queue.flush().await;
+		assert!(queue.is_closed() && queue.is_empty());
+		assert_eq!(queue.dropped().count(), 2);
+		queue.push(3);
+		assert!(queue.is_empty());
+	}
+
+	#[tokio::test]
+	async fn channel_applies_policy() {
+		let (mut sender, mut receiver, dropped) = channel(EventPolicy::DropOldest, 1, same_kind);
+		for event in 0..10 {
+			sender.send(event).await.expect("Queue task is running");
+		}
+		drop(sender);
+		let mut received = Vec::new();
+		while let Some(event) = receiver.recv().await {
+			received.push(event);
+		}
+		assert_eq!(received.len() as u64 + dropped.count(), 10);
+		assert_eq!(received.last(), Some(&9));
+	}
+}
@@ -0,0 +1,67 @@
+// Actions sent to the node together with a channel to answer the caller on
+
+use tokio::sync::{mpsc::{Sender, Receiver}, oneshot};
+use libp2p::{PeerId, Multiaddr};
+
+use crate::{Application, DitherAction, DitherError, DitherEvent, DroppedEvents, NetworkKey, PeerInfo, User, UserConnection, UserId, routing::RouteMeasurement};
+
+/// Typed result of a `DitherAction`
+#[derive(Debug)]
+pub enum DitherReply {
+	/// Action was carried out, there is nothing to return
+	Done,
+	/// `DitherAction::CreateUser` or `DitherAction::CreateHiddenUser`
+	UserCreated(UserId, NetworkKey),
+	/// `DitherAction::Bootstrap`, once the bootstrap node is verified
+	Bootstrapped(PeerId),
+	/// `DitherAction::Discover`
+	UserDiscovered(User),
+	/// `DitherAction::Connect`
+	Connected(UserConnection),
+	/// `DitherAction::SendData`, once the receiving node acknowledged the data
+	Delivered,
+	/// `DitherAction::Register`, requests and events of the registered application and the count of its events that were dropped
+	Registered(Sender<DitherRequest>, Receiver<DitherEvent>, DroppedEvents),
+	/// `DitherAction::GetListenAddresses`
+	ListenAddresses(Vec<Multiaddr>),
+	/// `DitherAction::GetPeers`
+	Peers(Vec<PeerInfo>),
+	/// `DitherAction::GetLocalUsers`
+	LocalUsers(Vec<UserId>),
+	/// `DitherAction::GetRoutes`, measurements from -> to
+	Routes(Vec<((PeerId, PeerId), RouteMeasurement)>),
+	/// `DitherAction::GetSubscriptions`, floodsub topics and the registered application that subscribed to them
+	Subscriptions(Vec<(String, Option<Application>)>),
+}
+
+pub type DitherResult = Result<DitherReply, DitherError>;
+pub type ReplySender = oneshot::Sender<DitherResult>;
+
+/// `DitherAction` sent to the node
+/// If `reply` is set the result is sent there instead of being broadcast as a `DitherEvent`
+#[derive(Debug)]
+pub struct DitherRequest {
+	pub action: DitherAction,
+	pub reply: Option<ReplySender>,
+}
+
+impl DitherRequest {
+	/// Create request and the receiver its result will arrive on
+	pub fn new(action: DitherAction) -> (DitherRequest, oneshot::Receiver<DitherResult>) {
+		let (reply, receiver) = oneshot::channel();
+		(DitherRequest { action, reply: Some(reply) }, receiver)
+	}
+	/// Send `action` on `sender` and wait for its result
+	pub async fn call(sender: &mut Sender<DitherRequest>, action: DitherAction) -> DitherResult {
+		let (request, receiver) = DitherRequest::new(action);
+		sender.send(request).await?;
+		receiver.await.map_err(|_| DitherError::ChannelClosed)?
+	}
+}
+
+/// Fire and forget, results are broadcast as `DitherEvent`s
+impl From<DitherAction> for DitherRequest {
+	fn from(action: DitherAction) -> DitherRequest {
+		DitherRequest { action, reply: None }
+	}
+}
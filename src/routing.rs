@@ -1,27 +1,431 @@
+use std::{
+	cmp::Ordering,
+	collections::{BinaryHeap, HashMap, HashSet},
+	time::{Duration, Instant},
+};
+use libp2p::{PeerId, Multiaddr};
 
-use std::time::Instant;
-#[derive(Debug)]
+/// Measurements older than this are dropped from the `RoutingTable`
+/// Pings are sent every 15 seconds, so a route expires after about 4 missed pings
+pub const MEASUREMENT_TTL: Duration = Duration::from_secs(60);
+/// Weight of a new latency sample in the running average
+const LATENCY_SMOOTHING: f32 = 0.3;
+
+/// Throughput measured to a peer, in kilobits / second
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthSample {
+	/// Measured with a dedicated probe
+	Probe(u32),
+	/// Estimated from data that was delivered, includes processing time on the other end so it only ever underestimates
+	Traffic(u32),
+}
+
+#[derive(Debug, Clone)]
 pub struct RouteMeasurement {
 	latency: f32, // Measured in milliseconds
-	bandwidth: u32, // Measured in kb / second
+	bandwidth: u32, // Measured in kilobits / second
 	last_measured: Instant, // Time last measured
 	pub_address: Option<Multiaddr>,
 }
 
+impl RouteMeasurement {
+	pub fn new(latency: f32, bandwidth: u32, pub_address: Option<Multiaddr>) -> RouteMeasurement {
+		RouteMeasurement { latency, bandwidth, last_measured: Instant::now(), pub_address }
+	}
+	pub fn latency(&self) -> f32 { self.latency }
+	pub fn bandwidth(&self) -> u32 { self.bandwidth }
+	pub fn last_measured(&self) -> Instant { self.last_measured }
+	pub fn pub_address(&self) -> Option<&Multiaddr> { self.pub_address.as_ref() }
+	pub fn is_stale(&self, now: Instant) -> bool {
+		now.duration_since(self.last_measured) > MEASUREMENT_TTL
+	}
+}
+
 #[derive(Debug)]
 pub struct RoutingTable {
 	my_id: PeerId,
 	/// Maps peer id tuples to connection speed
+	/// Links from this node are measured, links between other nodes are advertised by relays on their own links
 	map: HashMap<(PeerId, PeerId), RouteMeasurement>,
 	current_routes: Vec<(PeerId, PeerId)>,
 }
 
 impl RoutingTable {
-	fn add_route(&mut self, requesting: PeerId, destination: PeerId) {
-		self.map.insert((requesting, destination), )
+	pub fn new(my_id: PeerId) -> RoutingTable {
+		RoutingTable { my_id, map: HashMap::new(), current_routes: Vec::new() }
+	}
+	pub fn my_id(&self) -> &PeerId { &self.my_id }
+	pub fn add_route(&mut self, requesting: PeerId, destination: PeerId, measurement: RouteMeasurement) {
+		self.map.insert((requesting, destination), measurement);
+	}
+	pub fn get(&self, from: &PeerId, to: &PeerId) -> Option<&RouteMeasurement> {
+		self.map.get(&(from.clone(), to.clone()))
+	}
+	/// Record a round trip time from this node to `peer`, smoothed with previous samples
+	pub fn record_latency(&mut self, peer: PeerId, rtt: Duration) {
+		let sample = rtt.as_secs_f32() * 1000.0;
+		match self.map.get_mut(&(self.my_id.clone(), peer.clone())) {
+			// A stale average says nothing about the route now
+			Some(measurement) if !measurement.is_stale(Instant::now()) => {
+				measurement.latency += LATENCY_SMOOTHING * (sample - measurement.latency);
+				measurement.last_measured = Instant::now();
+			},
+			Some(measurement) => {
+				measurement.latency = sample;
+				measurement.last_measured = Instant::now();
+			},
+			None => self.add_route(self.my_id.clone(), peer, RouteMeasurement::new(sample, 0, None)),
+		}
+	}
+	/// Record throughput from this node to `peer`, only peers with a fresh latency measurement are tracked
+	pub fn record_bandwidth(&mut self, peer: &PeerId, sample: BandwidthSample) {
+		match self.map.get_mut(&(self.my_id.clone(), peer.clone())) {
+			Some(measurement) => measurement.bandwidth = match sample {
+				BandwidthSample::Probe(bandwidth) => bandwidth,
+				// Real traffic may not have used the whole link, so never lower a known value because of it
+				BandwidthSample::Traffic(bandwidth) => measurement.bandwidth.max(bandwidth),
+			},
+			None => log::debug!("Bandwidth to unmeasured peer {:?} ignored: {:?}", peer, sample),
+		}
+	}
+	/// Drop measurements that were not refreshed within `MEASUREMENT_TTL`
+	pub fn expire(&mut self, now: Instant) {
+		self.map.retain(|_, measurement| !measurement.is_stale(now));
+		let map = &self.map;
+		self.current_routes.retain(|route| map.contains_key(route));
+	}
+	/// Every measured route, from -> to
+	pub fn routes(&self) -> impl Iterator<Item = (&(PeerId, PeerId), &RouteMeasurement)> {
+		self.map.iter()
 	}
 }
 
+/// How much each property of a link adds to its cost
+#[derive(Debug, Clone, Copy)]
+pub struct CostWeights {
+	/// Cost per millisecond of latency
+	pub latency: f32,
+	/// Cost of a link at `REFERENCE_BANDWIDTH`, slower links cost proportionally more
+	pub bandwidth: f32,
+	/// Cost per second since the link was measured
+	pub staleness: f32,
+}
+impl Default for CostWeights {
+	fn default() -> CostWeights {
+		CostWeights { latency: 1.0, bandwidth: 50.0, staleness: 0.5 }
+	}
+}
+
+/// Bandwidth (kilobits / second) a link is assumed to have until it is measured
+pub const REFERENCE_BANDWIDTH: u32 = 1000;
+
+/// Peers from source to destination (both included) and the summed cost of their links
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+	pub hops: Vec<PeerId>,
+	pub cost: f32,
+}
+
+/// Entry of the Dijkstra queue, ordered so the cheapest is popped first
+struct Candidate {
+	cost: f32,
+	peer: PeerId,
+}
+impl PartialEq for Candidate {
+	fn eq(&self, other: &Candidate) -> bool { self.cost == other.cost }
+}
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+	fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Candidate {
+	fn cmp(&self, other: &Candidate) -> Ordering {
+		other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+	}
+}
+
+/// Finds paths through the network using the pairwise measurements of its `RoutingTable`
+/// Only peers known to relay are intermediate hops of a path, any other peer is only ever its source or destination
+#[derive(Debug)]
 pub struct Router {
-	
-}
\ No newline at end of file
+	table: RoutingTable,
+	weights: CostWeights,
+	/// Peers that told us they relay, the only ones used as intermediate hops
+	relays: HashSet<PeerId>,
+}
+
+impl Router {
+	pub fn new(table: RoutingTable) -> Router {
+		Router::with_weights(table, CostWeights::default())
+	}
+	pub fn with_weights(table: RoutingTable, weights: CostWeights) -> Router {
+		Router { table, weights, relays: HashSet::new() }
+	}
+	/// Record whether `peer` relays for anyone, as it advertised through identify
+	/// Peers are not relays until they said so, a node that was not identified yet may well refuse to extend circuits
+	pub fn set_relays(&mut self, peer: PeerId, relays: bool) {
+		if relays {
+			self.relays.insert(peer);
+		} else {
+			self.relays.remove(&peer);
+		}
+	}
+	pub fn relays(&self, peer: &PeerId) -> bool { self.relays.contains(peer) }
+	/// Peers with links that may not be intermediate hops of a path between `from` and `to`
+	fn excluded(&self, links: &HashMap<PeerId, Vec<(PeerId, f32)>>, from: &PeerId, to: &PeerId) -> HashSet<PeerId> {
+		links.keys().filter(|peer| *peer != from && *peer != to && !self.relays(peer)).cloned().collect()
+	}
+	pub fn table(&self) -> &RoutingTable { &self.table }
+	pub fn table_mut(&mut self) -> &mut RoutingTable { &mut self.table }
+	/// Cost of using a measured link, `None` if the measurement is stale
+	pub fn link_cost(&self, measurement: &RouteMeasurement, now: Instant) -> Option<f32> {
+		if measurement.is_stale(now) { return None }
+		let bandwidth = match measurement.bandwidth { 0 => REFERENCE_BANDWIDTH, bandwidth => bandwidth };
+		let age = now.duration_since(measurement.last_measured).as_secs_f32();
+		Some(measurement.latency * self.weights.latency
+			+ self.weights.bandwidth * REFERENCE_BANDWIDTH as f32 / bandwidth as f32
+			+ age * self.weights.staleness)
+	}
+	/// Outgoing links of every peer, a link measured in one direction only is assumed to be symmetric
+	fn links(&self, now: Instant) -> HashMap<PeerId, Vec<(PeerId, f32)>> {
+		let mut links: HashMap<PeerId, Vec<(PeerId, f32)>> = HashMap::new();
+		for ((from, to), measurement) in self.table.routes() {
+			let cost = match self.link_cost(measurement, now) { Some(cost) => cost, None => continue };
+			links.entry(from.clone()).or_default().push((to.clone(), cost));
+			if self.table.get(to, from).is_none() {
+				links.entry(to.clone()).or_default().push((from.clone(), cost));
+			}
+		}
+		links
+	}
+	/// Cheapest path avoiding `excluded` peers and links
+	fn shortest_path(links: &HashMap<PeerId, Vec<(PeerId, f32)>>, from: &PeerId, to: &PeerId, excluded_peers: &HashSet<PeerId>, excluded_links: &HashSet<(PeerId, PeerId)>) -> Option<Path> {
+		let mut costs: HashMap<PeerId, f32> = HashMap::new();
+		let mut previous: HashMap<PeerId, PeerId> = HashMap::new();
+		let mut queue = BinaryHeap::new();
+		costs.insert(from.clone(), 0.0);
+		queue.push(Candidate { cost: 0.0, peer: from.clone() });
+		
+		while let Some(Candidate { cost, peer }) = queue.pop() {
+			if &peer == to {
+				let mut hops = vec![peer];
+				while let Some(prev) = previous.get(hops.last().expect("Path has a hop")) {
+					hops.push(prev.clone());
+				}
+				hops.reverse();
+				return Some(Path { hops, cost });
+			}
+			if costs.get(&peer).map_or(false, |best| cost > *best) { continue }
+			for (next, link_cost) in links.get(&peer).into_iter().flatten() {
+				if excluded_peers.contains(next) || excluded_links.contains(&(peer.clone(), next.clone())) { continue }
+				let next_cost = cost + link_cost;
+				if costs.get(next).map_or(true, |best| next_cost < *best) {
+					costs.insert(next.clone(), next_cost);
+					previous.insert(next.clone(), peer.clone());
+					queue.push(Candidate { cost: next_cost, peer: next.clone() });
+				}
+			}
+		}
+		None
+	}
+	/// Cheapest path from `from` to `to` over measured links
+	pub fn best_path(&self, from: &PeerId, to: &PeerId) -> Option<Path> {
+		let links = self.links(Instant::now());
+		Self::shortest_path(&links, from, to, &self.excluded(&links, from, to), &HashSet::new())
+	}
+	/// Up to `k` paths from `from` to `to` that share no intermediate peers, cheapest first
+	/// Paths are picked greedily, each one is the cheapest path avoiding the peers of those picked before
+	pub fn disjoint_paths(&self, from: &PeerId, to: &PeerId, k: usize) -> Vec<Path> {
+		let links = self.links(Instant::now());
+		let mut excluded_peers = self.excluded(&links, from, to);
+		let mut excluded_links = HashSet::new();
+		let mut paths = Vec::new();
+		while paths.len() < k {
+			let path = match Self::shortest_path(&links, from, to, &excluded_peers, &excluded_links) {
+				Some(path) => path,
+				None => break,
+			};
+			// Source and destination are the same peer, there is nothing to route
+			if path.hops.len() < 2 {
+				paths.push(path);
+				break;
+			}
+			let intermediate = &path.hops[1..path.hops.len() - 1];
+			if intermediate.is_empty() {
+				// Direct link, can only be used once
+				excluded_links.insert((from.clone(), to.clone()));
+			}
+			excluded_peers.extend(intermediate.iter().cloned());
+			paths.push(path);
+		}
+		paths
+	}
+	/// Up to `count` relays for an onion circuit from this node to `to`, in the order the circuit passes them
+	/// Intermediate peers of the best measured path come first, the rest is filled with the cheapest of `candidates`
+	pub fn circuit_relays(&self, to: &PeerId, candidates: &[PeerId], count: usize) -> Vec<PeerId> {
+		let me = self.table.my_id();
+		let mut relays: Vec<PeerId> = match self.best_path(me, to) {
+			Some(path) if path.hops.len() > 2 => path.hops[1..path.hops.len() - 1].iter().take(count).cloned().collect(),
+			_ => Vec::new(),
+		};
+		let candidates: Vec<PeerId> = candidates.iter().filter(|peer| *peer != to && !relays.contains(peer)).cloned().collect();
+		relays.extend(self.cheapest_peers(&candidates, count.saturating_sub(relays.len())));
+		relays
+	}
+	/// Up to `count` of `candidates` that relay with the cheapest links from this node, unmeasured peers last
+	pub fn cheapest_peers(&self, candidates: &[PeerId], count: usize) -> Vec<PeerId> {
+		let me = self.table.my_id();
+		let now = Instant::now();
+		let mut candidates: Vec<(f32, &PeerId)> = candidates.iter()
+			.filter(|peer| *peer != me && self.relays(peer))
+			.map(|peer| {
+				let cost = self.table.get(me, peer).and_then(|measurement| self.link_cost(measurement, now));
+				(cost.unwrap_or(f32::INFINITY), peer)
+			})
+			.collect();
+		candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+		candidates.into_iter().map(|(_, peer)| peer.clone()).take(count).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn link(latency: f32) -> RouteMeasurement {
+		RouteMeasurement::new(latency, REFERENCE_BANDWIDTH, None)
+	}
+	fn peers(count: usize) -> Vec<PeerId> {
+		(0..count).map(|_| PeerId::random()).collect()
+	}
+	fn router(table: RoutingTable, relays: &[&PeerId]) -> Router {
+		let mut router = Router::new(table);
+		for relay in relays {
+			router.set_relays((*relay).clone(), true);
+		}
+		router
+	}
+
+	#[test]
+	fn best_path_goes_through_cheaper_links() {
+		let me = PeerId::random();
+		let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), a.clone(), link(10.0));
+		table.add_route(me.clone(), c.clone(), link(500.0));
+		// Advertised by `a` and `b`, neither link is one of ours
+		table.add_route(a.clone(), b.clone(), link(10.0));
+		table.add_route(b.clone(), c.clone(), link(10.0));
+		let router = router(table, &[&a, &b]);
+		let path = router.best_path(&me, &c).expect("Path exists");
+		assert_eq!(path.hops, vec![me.clone(), a.clone(), b.clone(), c.clone()]);
+		assert!(path.cost < router.link_cost(router.table().get(&me, &c).expect("Measured"), Instant::now()).expect("Fresh"));
+		// Links measured in one direction are used both ways
+		assert_eq!(router.best_path(&c, &me).expect("Path exists").hops, vec![c, b, a, me]);
+	}
+
+	#[test]
+	fn best_path_only_goes_through_relays() {
+		let me = PeerId::random();
+		let (a, b) = (PeerId::random(), PeerId::random());
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), a.clone(), link(10.0));
+		table.add_route(a.clone(), b.clone(), link(10.0));
+		table.add_route(me.clone(), b.clone(), link(500.0));
+		let mut router = Router::new(table);
+		// Peers that were not identified as relays yet are not used
+		assert_eq!(router.best_path(&me, &b).expect("Path exists").hops, vec![me.clone(), b.clone()]);
+		assert!(router.cheapest_peers(&[a.clone(), b.clone()], 2).is_empty());
+		router.set_relays(a.clone(), true);
+		assert_eq!(router.best_path(&me, &b).expect("Path exists").hops, vec![me.clone(), a.clone(), b.clone()]);
+		router.set_relays(a.clone(), false);
+		assert_eq!(router.best_path(&me, &b).expect("Path exists").hops, vec![me.clone(), b]);
+		// Non relays are still reachable themselves
+		assert_eq!(router.best_path(&me, &a).expect("Path exists").hops, vec![me, a]);
+	}
+
+	#[test]
+	fn unconnected_peers_have_no_path() {
+		let me = PeerId::random();
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), PeerId::random(), link(10.0));
+		assert!(Router::new(table).best_path(&me, &PeerId::random()).is_none());
+	}
+
+	#[test]
+	fn disjoint_paths_share_no_peers() {
+		let me = PeerId::random();
+		let to = PeerId::random();
+		let relays = peers(3);
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), to.clone(), link(100.0));
+		for (i, relay) in relays.iter().enumerate() {
+			table.add_route(me.clone(), relay.clone(), link(10.0 * (i + 1) as f32));
+			table.add_route(relay.clone(), to.clone(), link(10.0));
+		}
+		// Cross links that would let later paths reuse earlier relays
+		table.add_route(relays[0].clone(), relays[1].clone(), link(1.0));
+		table.add_route(relays[1].clone(), relays[2].clone(), link(1.0));
+		let router = router(table, &relays.iter().collect::<Vec<_>>());
+		let paths = router.disjoint_paths(&me, &to, 10);
+		assert_eq!(paths.len(), 4);
+		assert_eq!(paths[0].hops, vec![me.clone(), relays[0].clone(), to.clone()]);
+		assert_eq!(paths[3].hops, vec![me.clone(), to.clone()]);
+		let mut seen = HashSet::new();
+		for path in &paths {
+			assert_eq!(path.hops.first(), Some(&me));
+			assert_eq!(path.hops.last(), Some(&to));
+			for hop in &path.hops[1..path.hops.len() - 1] {
+				assert!(seen.insert(hop.clone()), "{:?} is on two paths", hop);
+			}
+		}
+		assert!(paths.windows(2).all(|pair| pair[0].cost <= pair[1].cost));
+		assert_eq!(router.disjoint_paths(&me, &to, 2).len(), 2);
+	}
+
+	#[test]
+	fn circuit_relays_follow_the_best_path_then_cheapest_peers() {
+		let me = PeerId::random();
+		let (a, b, to) = (PeerId::random(), PeerId::random(), PeerId::random());
+		let (close, far) = (PeerId::random(), PeerId::random());
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), a.clone(), link(10.0));
+		table.add_route(a.clone(), b.clone(), link(10.0));
+		table.add_route(b.clone(), to.clone(), link(10.0));
+		table.add_route(me.clone(), close.clone(), link(50.0));
+		table.add_route(me.clone(), far.clone(), link(400.0));
+		let router = router(table, &[&a, &b, &close, &far, &to]);
+		let candidates = vec![far.clone(), to.clone(), a.clone(), close.clone()];
+		assert_eq!(router.circuit_relays(&to, &candidates, 2), vec![a.clone(), b.clone()]);
+		assert_eq!(router.circuit_relays(&to, &candidates, 1), vec![a.clone()]);
+		// The destination is never its own relay, relays on the path are not picked twice
+		assert_eq!(router.circuit_relays(&to, &candidates, 4), vec![a, b, close, far]);
+		// Without a measured path only the candidates are left
+		assert_eq!(router.circuit_relays(&PeerId::random(), &candidates, 2).len(), 2);
+	}
+
+	#[test]
+	fn expire_drops_stale_measurements() {
+		let me = PeerId::random();
+		let peer = PeerId::random();
+		let mut table = RoutingTable::new(me.clone());
+		table.add_route(me.clone(), peer.clone(), link(10.0));
+		let now = Instant::now();
+		table.expire(now + MEASUREMENT_TTL / 2);
+		assert!(table.get(&me, &peer).is_some());
+		table.expire(now + MEASUREMENT_TTL + Duration::from_secs(1));
+		assert!(table.get(&me, &peer).is_none());
+		assert_eq!(table.routes().count(), 0);
+	}
+
+	#[test]
+	fn latency_is_smoothed() {
+		let me = PeerId::random();
+		let peer = PeerId::random();
+		let mut table = RoutingTable::new(me.clone());
+		table.record_latency(peer.clone(), Duration::from_millis(100));
+		table.record_latency(peer.clone(), Duration::from_millis(200));
+		let latency = table.get(&me, &peer).expect("Measured").latency();
+		assert!((latency - 130.0).abs() < 0.01, "{}", latency);
+	}
+}
@@ -0,0 +1,287 @@
+// TCP transport that listens and dials on the same port, `TokioTcpConfig` of libp2p 0.28 can not reuse ports
+//
+// A NAT maps the port a dial leaves from, peers behind it only get through to that mapping. Dialing from the
+// port this node listens on makes the port sent in `CircuitMessage::Upgrade` the one the NAT opened for the
+// hole punch, and keeps it the same for every peer on NATs that map by source port only
+//
+// Only used with `TransportConfig::port_reuse`. Unlike `TokioTcpConfig` it does not watch interfaces: a listener
+// on an unspecified address reports the interface addresses it found when it was opened, and never expires them
+
+use std::{
+	io,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	pin::Pin,
+	sync::{Arc, Mutex},
+	task::{Context, Poll},
+};
+use futures::{
+	FutureExt,
+	future::{self, BoxFuture, Ready},
+	io::{AsyncRead, AsyncWrite},
+	stream::{self, BoxStream, StreamExt},
+};
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use libp2p::{
+	Multiaddr,
+	Transport,
+	core::transport::{ListenerEvent, TransportError},
+	multiaddr::Protocol,
+};
+
+/// Pending connections a listener keeps before they are accepted
+const BACKLOG: i32 = 1024;
+
+/// Socket addresses of open listeners, shared by every clone of a `ReuseTcpConfig`
+type Listening = Arc<Mutex<Vec<SocketAddr>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReuseTcpConfig {
+	nodelay: bool,
+	port_reuse: bool,
+	listening: Listening,
+}
+
+impl ReuseTcpConfig {
+	pub fn new() -> ReuseTcpConfig {
+		ReuseTcpConfig::default()
+	}
+	/// Disable Nagle's algorithm on every connection
+	pub fn nodelay(mut self, nodelay: bool) -> ReuseTcpConfig {
+		self.nodelay = nodelay;
+		self
+	}
+	/// Set `SO_REUSEPORT` on listeners and dial from the port of a listener of the same IP version
+	pub fn port_reuse(mut self, port_reuse: bool) -> ReuseTcpConfig {
+		self.port_reuse = port_reuse;
+		self
+	}
+	/// Address dials to `remote` leave from, `None` to let the system pick one
+	fn local_addr(&self, remote: &SocketAddr) -> Option<SocketAddr> {
+		if !self.port_reuse { return None }
+		let listening = self.listening.lock().expect("Listener lock is never poisoned");
+		listening.iter().find(|addr| addr.is_ipv4() == remote.is_ipv4()).map(|addr| {
+			// Dials to loopback can not leave from another interface and the other way around
+			match addr.ip().is_loopback() == remote.ip().is_loopback() {
+				true => *addr,
+				false => SocketAddr::new(unspecified(addr.ip()), addr.port()),
+			}
+		})
+	}
+}
+
+fn unspecified(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+		IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+	}
+}
+
+/// Socket of the IP version of `addr`, with `SO_REUSEADDR` and `SO_REUSEPORT` set if `port_reuse`
+fn builder(addr: &SocketAddr, port_reuse: bool) -> io::Result<TcpBuilder> {
+	let builder = match addr {
+		SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+		SocketAddr::V6(_) => {
+			let builder = TcpBuilder::new_v6()?;
+			builder.only_v6(true)?;
+			builder
+		},
+	};
+	if port_reuse {
+		builder.reuse_address(true)?;
+		#[cfg(unix)]
+		builder.reuse_port(true)?;
+	}
+	Ok(builder)
+}
+
+/// `/ip4/<ip>/tcp/<port>` or `/ip6/<ip>/tcp/<port>`, `None` for any other address
+pub fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Option<SocketAddr> {
+	let mut protocols = addr.iter();
+	let ip = match protocols.next()? {
+		Protocol::Ip4(ip) => IpAddr::V4(ip),
+		Protocol::Ip6(ip) => IpAddr::V6(ip),
+		_ => return None,
+	};
+	match (protocols.next()?, protocols.next()) {
+		(Protocol::Tcp(port), None) => Some(SocketAddr::new(ip, port)),
+		_ => None,
+	}
+}
+
+pub fn socketaddr_to_multiaddr(addr: &SocketAddr) -> Multiaddr {
+	Multiaddr::empty().with(addr.ip().into()).with(Protocol::Tcp(addr.port()))
+}
+
+/// Addresses a listener on `addr` can be reached on, every interface of its IP version if it is unspecified
+/// Looked up once, addresses of interfaces that come up later are not reported
+fn listen_addresses(addr: &SocketAddr) -> io::Result<Vec<Multiaddr>> {
+	if !addr.ip().is_unspecified() {
+		return Ok(vec![socketaddr_to_multiaddr(addr)]);
+	}
+	Ok(get_if_addrs::get_if_addrs()?.into_iter()
+		.map(|interface| interface.ip())
+		.filter(|ip| ip.is_ipv4() == addr.is_ipv4())
+		.map(|ip| socketaddr_to_multiaddr(&SocketAddr::new(ip, addr.port())))
+		.collect())
+}
+
+/// Takes a listener out of `Listening` once it is closed
+struct ListenGuard {
+	addr: SocketAddr,
+	listening: Listening,
+}
+impl Drop for ListenGuard {
+	fn drop(&mut self) {
+		let mut listening = self.listening.lock().expect("Listener lock is never poisoned");
+		if let Some(index) = listening.iter().position(|addr| *addr == self.addr) {
+			listening.remove(index);
+		}
+	}
+}
+
+/// Connection of a `ReuseTcpConfig`, tokio's `TcpStream` with the `futures` IO traits libp2p upgrades need
+#[derive(Debug)]
+pub struct TcpStream(tokio::net::TcpStream);
+
+impl AsyncRead for TcpStream {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		tokio::io::AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+	}
+}
+impl AsyncWrite for TcpStream {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+	}
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+	}
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+	}
+}
+
+impl Transport for ReuseTcpConfig {
+	type Output = TcpStream;
+	type Error = io::Error;
+	type Listener = BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, io::Error>, io::Error>>;
+	type ListenerUpgrade = Ready<Result<TcpStream, io::Error>>;
+	type Dial = BoxFuture<'static, Result<TcpStream, io::Error>>;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<io::Error>> {
+		let socket_addr = match multiaddr_to_socketaddr(&addr) {
+			Some(socket_addr) => socket_addr,
+			None => return Err(TransportError::MultiaddrNotSupported(addr)),
+		};
+		let listen = || -> io::Result<(tokio::net::TcpListener, SocketAddr)> {
+			let listener = builder(&socket_addr, self.port_reuse)?.bind(socket_addr)?.listen(BACKLOG)?;
+			listener.set_nonblocking(true)?;
+			let local = listener.local_addr()?;
+			Ok((tokio::net::TcpListener::from_std(listener)?, local))
+		};
+		let (listener, local) = listen().map_err(TransportError::Other)?;
+		let addresses = listen_addresses(&local).map_err(TransportError::Other)?;
+		log::debug!("Listening on {:?}, reachable on {:?}", local, addresses);
+		self.listening.lock().expect("Listener lock is never poisoned").push(local);
+		let guard = ListenGuard { addr: local, listening: self.listening.clone() };
+		let nodelay = self.nodelay;
+
+		// The guard lives as long as the stream, which is dropped when the listener is removed
+		let accepted = stream::unfold((listener, guard), move |(mut listener, guard)| async move {
+			let accepted = listener.accept().await.and_then(|(stream, remote)| {
+				stream.set_nodelay(nodelay)?;
+				Ok((stream.local_addr()?, remote, stream))
+			});
+			let event = match accepted {
+				Ok((local, remote, stream)) => ListenerEvent::Upgrade {
+					upgrade: future::ok(TcpStream(stream)),
+					local_addr: socketaddr_to_multiaddr(&local),
+					remote_addr: socketaddr_to_multiaddr(&remote),
+				},
+				Err(err) => ListenerEvent::Error(err),
+			};
+			Some((Ok(event), (listener, guard)))
+		});
+		Ok(stream::iter(addresses.into_iter().map(|addr| Ok(ListenerEvent::NewAddress(addr))))
+			.chain(accepted)
+			.boxed())
+	}
+
+	fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<io::Error>> {
+		let remote = match multiaddr_to_socketaddr(&addr) {
+			Some(remote) if remote.port() != 0 && !remote.ip().is_unspecified() => remote,
+			_ => return Err(TransportError::MultiaddrNotSupported(addr)),
+		};
+		let local = self.local_addr(&remote);
+		let nodelay = self.nodelay;
+		Ok(async move {
+			let builder = builder(&remote, local.is_some())?;
+			if let Some(local) = local {
+				builder.bind(local)?;
+			}
+			let stream = tokio::net::TcpStream::connect_std(builder.to_tcp_stream()?, &remote).await?;
+			stream.set_nodelay(nodelay)?;
+			Ok(TcpStream(stream))
+		}.boxed())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::TryStreamExt;
+
+	/// Listen on `addr`, returns the listener and the address it got
+	async fn listen(transport: &ReuseTcpConfig, addr: &str) -> (<ReuseTcpConfig as Transport>::Listener, SocketAddr) {
+		let mut listener = transport.clone().listen_on(addr.parse().expect("Valid address")).expect("Listening works");
+		match listener.try_next().await.expect("Listener is open") {
+			Some(ListenerEvent::NewAddress(addr)) => (listener, multiaddr_to_socketaddr(&addr).expect("TCP address")),
+			_ => panic!("Listener did not report its address first"),
+		}
+	}
+
+	#[tokio::test]
+	async fn dials_leave_from_the_listen_port() {
+		let transport = ReuseTcpConfig::new().port_reuse(true);
+		let (_listener, listening) = listen(&transport, "/ip4/127.0.0.1/tcp/0").await;
+		let mut remote = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Remote listens");
+		let remote_addr = remote.local_addr().expect("Remote has an address");
+		let (dialed, accepted) = future::join(transport.dial(socketaddr_to_multiaddr(&remote_addr)).expect("Address is dialable"), remote.accept()).await;
+		dialed.expect("Dial succeeds");
+		let (_, from) = accepted.expect("Remote accepts");
+		assert_eq!(from, listening);
+	}
+
+	#[tokio::test]
+	async fn dials_pick_a_port_without_port_reuse() {
+		let transport = ReuseTcpConfig::new();
+		let (_listener, listening) = listen(&transport, "/ip4/127.0.0.1/tcp/0").await;
+		let mut remote = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Remote listens");
+		let remote_addr = remote.local_addr().expect("Remote has an address");
+		let (dialed, accepted) = future::join(transport.dial(socketaddr_to_multiaddr(&remote_addr)).expect("Address is dialable"), remote.accept()).await;
+		dialed.expect("Dial succeeds");
+		let (_, from) = accepted.expect("Remote accepts");
+		assert_ne!(from.port(), listening.port());
+	}
+
+	#[tokio::test]
+	async fn closed_listeners_are_not_dialed_from() {
+		let transport = ReuseTcpConfig::new().port_reuse(true);
+		let (listener, _) = listen(&transport, "/ip4/127.0.0.1/tcp/0").await;
+		assert!(transport.local_addr(&"127.0.0.1:1".parse().expect("Valid address")).is_some());
+		drop(listener);
+		assert!(transport.local_addr(&"127.0.0.1:1".parse().expect("Valid address")).is_none());
+	}
+
+	#[test]
+	fn only_tcp_addresses_are_supported() {
+		let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().expect("Valid address");
+		assert_eq!(multiaddr_to_socketaddr(&addr), Some("10.0.0.1:4001".parse().expect("Valid address")));
+		assert_eq!(socketaddr_to_multiaddr(&"10.0.0.1:4001".parse().expect("Valid address")), addr);
+		assert_eq!(multiaddr_to_socketaddr(&"/ip6/::1/tcp/1".parse().expect("Valid address")), Some("[::1]:1".parse().expect("Valid address")));
+		for other in &["/ip4/10.0.0.1/udp/4001", "/ip4/10.0.0.1/tcp/4001/ws", "/dns4/example.com/tcp/4001"] {
+			assert_eq!(multiaddr_to_socketaddr(&other.parse().expect("Valid address")), None);
+		}
+	}
+}
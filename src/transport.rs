@@ -0,0 +1,89 @@
+// Transport stack the swarm runs on, built from `DitherConfig`
+
+use std::io;
+use libp2p::{
+	PeerId,
+	Transport,
+	core::{
+		either::EitherOutput,
+		upgrade::{self, SelectUpgrade},
+		muxing::StreamMuxerBox,
+		transport::boxed::Boxed,
+	},
+	dns::DnsConfig,
+	identity::Keypair,
+	mplex::MplexConfig,
+	noise,
+	tcp::TokioTcpConfig,
+	websocket::WsConfig,
+	yamux,
+};
+
+use crate::{DitherConfig, DitherError, config::{Multiplexer, TransportConfig}, tcp::{ReuseTcpConfig, TcpStream}};
+
+pub type DitherTransport = Boxed<(PeerId, StreamMuxerBox), io::Error>;
+/// Either TCP transport, boxed so the rest of the stack is the same for both
+type TcpTransport = Boxed<EitherOutput<<TokioTcpConfig as Transport>::Output, TcpStream>, io::Error>;
+
+fn other<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, err)
+}
+fn yamux_config(config: &TransportConfig) -> yamux::Config {
+	let mut yamux = yamux::Config::default();
+	if let Some(max) = config.max_substreams {
+		yamux.set_max_num_streams(max);
+	}
+	yamux
+}
+fn mplex_config(config: &TransportConfig) -> MplexConfig {
+	let mut mplex = MplexConfig::new();
+	if let Some(max) = config.max_substreams {
+		mplex.max_substreams(max);
+	}
+	mplex
+}
+/// libp2p's TCP transport, or `tcp::ReuseTcpConfig` if `TransportConfig::port_reuse` is set
+fn tcp_config(config: &TransportConfig) -> TcpTransport {
+	if config.port_reuse {
+		ReuseTcpConfig::new().nodelay(config.tcp_nodelay).port_reuse(true)
+			.map(|stream, _| EitherOutput::Second(stream))
+			.boxed()
+	} else {
+		TokioTcpConfig::new().nodelay(config.tcp_nodelay)
+			.map(|stream, _| EitherOutput::First(stream))
+			.boxed()
+	}
+}
+
+/// TCP over IPv4 and IPv6 with DNS resolution, and WebSockets on top of that for `/ws` addresses
+/// Dials leave from the listen port if `TransportConfig::port_reuse` is set, see `tcp::ReuseTcpConfig`
+/// Every connection is authenticated with noise using the node's `key` and multiplexed as configured in `DitherConfig::transport`
+pub fn build(key: &Keypair, config: &DitherConfig) -> Result<DitherTransport, DitherError> {
+	let config = &config.transport;
+	let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+		.into_authentic(key)?;
+	
+	let tcp = DnsConfig::new(tcp_config(config))?;
+	let ws = WsConfig::new(tcp.clone());
+	let authenticated = tcp.or_transport(ws)
+		.upgrade(upgrade::Version::V1)
+		.authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated());
+	
+	Ok(match config.multiplexer {
+		Multiplexer::Yamux => authenticated
+			.multiplex(yamux_config(config))
+			.map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+			.map_err(other)
+			.boxed(),
+		Multiplexer::Mplex => authenticated
+			.multiplex(mplex_config(config))
+			.map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+			.map_err(other)
+			.boxed(),
+		Multiplexer::Negotiate => authenticated
+			.multiplex(SelectUpgrade::new(yamux_config(config), mplex_config(config)))
+			.map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+			.map_err(other)
+			.boxed(),
+	})
+}
@@ -1,11 +1,13 @@
 
 
 use libp2p::identity::{Keypair, PublicKey};
+use crate::UserId;
 
 /// Contains all information pertaining to a specific user of the Dither Network
+#[derive(Clone)]
 pub struct NetworkKey {
 	key: Keypair,
-	id: PeerId,
+	id: UserId,
 }
 
 use std::fmt;
@@ -26,14 +28,28 @@ impl NetworkKey {
 			id: peer_id,
 		}
 	}
+	/// Id of the user this key belongs to
+	pub fn id(&self) -> &UserId { &self.id }
+	/// Public half of the key, used in `PublicUserDefinition`
+	pub fn public(&self) -> PublicKey { self.key.public() }
+	/// Sign some data as this user
+	pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+		self.key.sign(msg).expect("Signing with ed25519 keys does not fail")
+	}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Application {
 	tag: String,
 }
+impl Application {
+	pub fn new(tag: &str) -> Application {
+		Application { tag: tag.to_owned() }
+	}
+	pub fn tag(&self) -> &str { &self.tag }
+}
 /*pub enum Application {
 	DitherChat = 0,
 	DitherSCP = 1,
 	DitherDB = 2,
-}*/
\ No newline at end of file
+}*/
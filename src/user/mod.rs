@@ -1,7 +1,11 @@
 
+use std::{
+	collections::HashMap,
+	time::{SystemTime, UNIX_EPOCH},
+};
 use libp2p::{
-	PeerId, 
-	Multiaddr, 
+	PeerId,
+	Multiaddr,
 	identity::PublicKey
 };
 use libp2p::multihash::Multihash;
@@ -9,8 +13,11 @@ use libp2p::multihash::Multihash;
 use crate::types::{NetworkKey, Application};
 pub type UserId = PeerId;
 
+mod record;
+
 /// Defines a user, e.g. a collection of relevant peers and information
 /// Updated User Definitions will be accepted by nodes updated if a new one is produced with enough agreeing parties to compute a ring signature with a high enough threshold
+#[derive(Debug, Clone)]
 pub struct PublicUserDefinition {
 	/// Hash of previous UserDefinition (for easy tracking between versions)
 	previous_definition: Option<Multihash>,
@@ -25,16 +32,32 @@ pub struct PublicUserDefinition {
 	/// Must have at least this amount of signing keys to link a new user definition
 	/// TODO: This will be replaced in the future by some kind of advanced, permissioned key update where different keys have more power over others
 	update_threshold: u32,
+	/// Nodes that pass introductions on to a hidden user, published instead of the nodes hosting it
+	introduction_points: Vec<PeerId>,
 	/// Threshold Ring Signature computed on this object with enough update_keys to be >/= update_threshold
 	///ring_signature: nazgul::
 }
 
+impl PublicUserDefinition {
+	/// Create the first definition of a user, only `key` may update it
+	pub fn new(key: PublicKey) -> PublicUserDefinition {
+		PublicUserDefinition {
+			previous_definition: None,
+			data: HashMap::new(),
+			applications: Vec::new(),
+			keys: vec![key],
+			update_threshold: 1,
+			introduction_points: Vec::new(),
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
-	public: UserDefinition,
-	requested: UserDefinition,
-	config: UserConfig,
-	
+	public: PublicUserDefinition,
+	//requested: UserDefinition,
+	//config: UserConfig,
+
 	/// ID of the User (Multihash of the Public Key)
 	id: UserId,
 	/// Public Key of this user
@@ -42,5 +65,65 @@ pub struct User {
 	/// Other users this user knows about
 	users: Vec<User>, // Data on other users
 	/// Nodes this user
-	user_nodes: Vec<UserNode>, // Nodes connected to
-}
\ No newline at end of file
+	user_nodes: Vec<PeerId>, // Nodes connected to
+	/// `/p2p-circuit` addresses of nodes in `user_nodes` that are behind NAT
+	relay_addresses: Vec<Multiaddr>,
+	/// Version of this definition, signed with it so newer records on the DHT win over older ones
+	seq: u64,
+}
+
+/// Milliseconds since the Unix epoch, so versions keep increasing across restarts
+fn unix_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+impl User {
+	/// Create a new user hosted on `node` from a freshly generated key
+	pub fn new(key: &NetworkKey, node: PeerId) -> User {
+		User {
+			public: PublicUserDefinition::new(key.public()),
+			id: key.id().clone(),
+			public_key: key.public(),
+			users: Vec::new(),
+			user_nodes: vec![node],
+			relay_addresses: Vec::new(),
+			seq: unix_millis(),
+		}
+	}
+	/// Create a new user that publishes `introduction_points` instead of the node hosting it
+	pub fn new_hidden(key: &NetworkKey, introduction_points: Vec<PeerId>) -> User {
+		let mut public = PublicUserDefinition::new(key.public());
+		public.introduction_points = introduction_points;
+		User {
+			public,
+			id: key.id().clone(),
+			public_key: key.public(),
+			users: Vec::new(),
+			user_nodes: Vec::new(),
+			relay_addresses: Vec::new(),
+			seq: unix_millis(),
+		}
+	}
+	pub fn id(&self) -> &UserId { &self.id }
+	pub fn public(&self) -> &PublicUserDefinition { &self.public }
+	pub fn public_key(&self) -> &PublicKey { &self.public_key }
+	pub fn user_nodes(&self) -> &[PeerId] { &self.user_nodes }
+	pub fn introduction_points(&self) -> &[PeerId] { &self.public.introduction_points }
+	pub fn relay_addresses(&self) -> &[Multiaddr] { &self.relay_addresses }
+	/// Version of the definition, higher ones replace lower ones
+	pub fn seq(&self) -> u64 { self.seq }
+	/// Replace the relay addresses published with this user, the definition has to be published again
+	pub fn set_relay_addresses(&mut self, addresses: Vec<Multiaddr>) {
+		self.relay_addresses = addresses;
+		self.changed();
+	}
+	/// Replace the introduction points of a hidden user, the definition has to be published again
+	pub fn set_introduction_points(&mut self, points: Vec<PeerId>) {
+		self.public.introduction_points = points;
+		self.changed();
+	}
+	/// New version for a changed definition, later than the previous one even if the clock went back
+	fn changed(&mut self) { self.seq = unix_millis().max(self.seq + 1) }
+	/// Hidden users are only reachable through a rendezvous, see `DitherAction::CreateHiddenUser`
+	pub fn is_hidden(&self) -> bool { self.user_nodes.is_empty() && !self.public.introduction_points.is_empty() }
+}
@@ -0,0 +1,108 @@
+// Wire format for publishing `PublicUserDefinition`s on the DHT
+
+use std::{
+	collections::HashMap,
+	convert::TryFrom,
+};
+use serde_derive::{Serialize, Deserialize};
+use libp2p::{
+	PeerId,
+	Multiaddr,
+	identity::PublicKey,
+	multihash::Multihash,
+};
+
+use super::{User, UserId, PublicUserDefinition};
+use crate::{types::NetworkKey, DitherError};
+
+/// `PublicUserDefinition` and hosting nodes of a user, encoded for the network
+#[derive(Debug, Serialize, Deserialize)]
+struct DefinitionRecord {
+	previous_definition: Option<Vec<u8>>,
+	data: HashMap<String, Vec<u8>>,
+	applications: Vec<String>,
+	/// Protobuf encoded public keys
+	keys: Vec<Vec<u8>>,
+	update_threshold: u32,
+	user_nodes: Vec<Vec<u8>>,
+	#[serde(default)]
+	introduction_points: Vec<Vec<u8>>,
+	#[serde(default)]
+	relay_addresses: Vec<Vec<u8>>,
+	/// `User::seq`, of all valid records of a user the highest one is used
+	#[serde(default)]
+	seq: u64,
+}
+
+/// `DefinitionRecord` signed by the key the `UserId` was derived from
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedRecord {
+	record: Vec<u8>,
+	signature: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> DitherError {
+	DitherError::Encoding(msg.to_owned())
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, DitherError> {
+	serde_json::to_vec(value).map_err(|err| DitherError::Encoding(err.to_string()))
+}
+fn decode<'a, T: serde::Deserialize<'a>>(data: &'a [u8]) -> Result<T, DitherError> {
+	serde_json::from_slice(data).map_err(|err| DitherError::Encoding(err.to_string()))
+}
+
+impl User {
+	/// Encode and sign this user's public definition so it can be stored on the DHT
+	pub fn to_signed_record(&self, key: &NetworkKey) -> Result<Vec<u8>, DitherError> {
+		let PublicUserDefinition { previous_definition, data, applications, keys, update_threshold, introduction_points } = &self.public;
+		let record = encode(&DefinitionRecord {
+			previous_definition: previous_definition.as_ref().map(|hash| hash.as_bytes().to_vec()),
+			data: data.iter().map(|(name, hash)| (name.clone(), hash.as_bytes().to_vec())).collect(),
+			applications: applications.clone(),
+			keys: keys.iter().map(|key| key.clone().into_protobuf_encoding()).collect(),
+			update_threshold: *update_threshold,
+			user_nodes: self.user_nodes.iter().map(|node| node.clone().into_bytes()).collect(),
+			introduction_points: introduction_points.iter().map(|node| node.clone().into_bytes()).collect(),
+			relay_addresses: self.relay_addresses.iter().map(|addr| addr.to_vec()).collect(),
+			seq: self.seq,
+		})?;
+		let signature = key.sign(&record);
+		encode(&SignedRecord { record, signature })
+	}
+	/// Decode a record fetched from the DHT, checking that it was signed by `id`
+	pub fn from_signed_record(id: &UserId, data: &[u8]) -> Result<User, DitherError> {
+		let SignedRecord { record, signature } = decode(data)?;
+		let DefinitionRecord { previous_definition, data, applications, keys, update_threshold, user_nodes, introduction_points, relay_addresses, seq } = decode(&record)?;
+
+		let keys = keys.into_iter()
+			.map(|key| PublicKey::from_protobuf_encoding(&key).map_err(|_| invalid("Invalid public key in user record")))
+			.collect::<Result<Vec<PublicKey>, _>>()?;
+		let public_key = keys.iter().find(|key| &PeerId::from((*key).clone()) == id)
+			.ok_or_else(|| invalid("User record does not contain the key of its user"))?.clone();
+		if !public_key.verify(&record, &signature) {
+			return Err(invalid("User record signature is invalid"));
+		}
+
+		let node = |bytes: Vec<u8>| PeerId::from_bytes(bytes).map_err(|_| invalid("Invalid node id in user record"));
+		let hash = |bytes: Vec<u8>| Multihash::from_bytes(bytes).map_err(|_| invalid("Invalid hash in user record"));
+		Ok(User {
+			public: PublicUserDefinition {
+				previous_definition: previous_definition.map(hash).transpose()?,
+				data: data.into_iter().map(|(name, bytes)| Ok((name, hash(bytes)?))).collect::<Result<_, DitherError>>()?,
+				applications,
+				keys,
+				update_threshold,
+				introduction_points: introduction_points.into_iter().map(node).collect::<Result<_, _>>()?,
+			},
+			id: id.clone(),
+			public_key,
+			users: Vec::new(),
+			user_nodes: user_nodes.into_iter().map(node).collect::<Result<_, _>>()?,
+			relay_addresses: relay_addresses.into_iter()
+				.map(|addr| Multiaddr::try_from(addr).map_err(|_| invalid("Invalid relay address in user record")))
+				.collect::<Result<_, _>>()?,
+			seq,
+		})
+	}
+}